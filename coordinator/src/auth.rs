@@ -0,0 +1,269 @@
+use std::{collections::HashMap, fs, path::Path, sync::Arc};
+
+use anyhow::{anyhow, bail};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use tonic::{metadata::MetadataMap, service::Interceptor, Request, Status};
+
+/// Metadata key of the bearer token that clients authenticate with.
+const AUTHORIZATION_KEY: &str = "authorization";
+
+/// Role of an authenticated client, which determines the services it can call.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Workers subscribe to tasks and publish their results.
+    Worker,
+    /// Operators start training runs and read their metrics.
+    Operator,
+}
+
+/// An authenticated client.
+///
+/// Added to the extensions of authorized requests. Workers are identified by
+/// the subject of their token.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Principal {
+    pub subject: String,
+    pub role: Role,
+}
+
+/// Claims of a JSON Web Token.
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    role: Role,
+}
+
+/// Verifies the bearer tokens of clients.
+///
+/// Tokens are either listed in a static token file or are JSON Web Tokens
+/// signed with HMAC-SHA256, which are verified with a shared secret.
+#[derive(Default)]
+pub struct Authenticator {
+    tokens: HashMap<String, Principal>,
+    jwt_key: Option<DecodingKey>,
+}
+
+impl Authenticator {
+    /// Accept the tokens of a file with a "<role> <subject> <token>" line per
+    /// token, e.g. "worker hospital-a 3f2b...". Empty lines and lines starting
+    /// with '#' are ignored.
+    pub fn with_token_file(mut self, path: &Path) -> Result<Self, anyhow::Error> {
+        let content = fs::read_to_string(path)?;
+
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (role, subject, token) = parse_token_line(line)
+                .map_err(|e| anyhow!("{}:{}: {e}", path.display(), index + 1))?;
+
+            self.tokens.insert(token, Principal { subject, role });
+        }
+
+        Ok(self)
+    }
+
+    /// Accept JSON Web Tokens signed with HMAC-SHA256 using the secret in `path`.
+    ///
+    /// Tokens need a "sub", "role" and "exp" claim.
+    pub fn with_jwt_secret_file(mut self, path: &Path) -> Result<Self, anyhow::Error> {
+        let secret = fs::read(path)?;
+        let secret = secret.trim_ascii();
+        if secret.is_empty() {
+            bail!("{}: empty JWT secret", path.display());
+        }
+
+        self.jwt_key = Some(DecodingKey::from_secret(secret));
+
+        Ok(self)
+    }
+
+    /// Authenticate the bearer token of a request.
+    pub fn authenticate(&self, metadata: &MetadataMap) -> Result<Principal, Status> {
+        let token = metadata
+            .get(AUTHORIZATION_KEY)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| Status::unauthenticated("missing bearer token"))?;
+
+        if let Some(principal) = self.tokens.get(token) {
+            return Ok(principal.clone());
+        }
+
+        if let Some(key) = &self.jwt_key {
+            let data =
+                jsonwebtoken::decode::<Claims>(token, key, &Validation::new(Algorithm::HS256))
+                    .map_err(|e| Status::unauthenticated(format!("invalid token: {e}")))?;
+
+            return Ok(Principal {
+                subject: data.claims.sub,
+                role: data.claims.role,
+            });
+        }
+
+        Err(Status::unauthenticated("invalid token"))
+    }
+}
+
+fn parse_token_line(line: &str) -> Result<(Role, String, String), String> {
+    let fields = line.split_whitespace().collect::<Vec<_>>();
+    let [role, subject, token] = fields[..] else {
+        return Err("expected \"<role> <subject> <token>\"".to_string());
+    };
+
+    let role = match role {
+        "worker" => Role::Worker,
+        "operator" => Role::Operator,
+        _ => return Err(format!("unknown role {role}")),
+    };
+
+    Ok((role, subject.to_string(), token.to_string()))
+}
+
+/// Interceptor that only admits clients with a given role.
+///
+/// All requests are admitted if no authenticator is configured.
+#[derive(Clone)]
+pub struct Authorize {
+    authenticator: Option<Arc<Authenticator>>,
+    role: Role,
+}
+
+impl Authorize {
+    pub fn new(authenticator: Option<Arc<Authenticator>>, role: Role) -> Self {
+        Self {
+            authenticator,
+            role,
+        }
+    }
+}
+
+impl Interceptor for Authorize {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let Some(authenticator) = &self.authenticator else {
+            return Ok(request);
+        };
+
+        let principal = authenticator.authenticate(request.metadata())?;
+        if principal.role != self.role {
+            return Err(Status::permission_denied(format!(
+                "{} is not authorized as {:?}",
+                principal.subject, self.role
+            )));
+        }
+
+        request.extensions_mut().insert(principal);
+
+        Ok(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde::Serialize;
+
+    use super::*;
+
+    #[derive(Serialize)]
+    struct TestClaims<'a> {
+        sub: &'a str,
+        role: &'a str,
+        exp: u64,
+    }
+
+    fn request(token: &str) -> Request<()> {
+        let mut request = Request::new(());
+        request.metadata_mut().insert(
+            AUTHORIZATION_KEY,
+            format!("Bearer {token}").parse().unwrap(),
+        );
+        request
+    }
+
+    #[test]
+    fn test_parse_token_line() {
+        assert_eq!(
+            parse_token_line("worker hospital-a secret"),
+            Ok((Role::Worker, "hospital-a".into(), "secret".into()))
+        );
+        assert!(parse_token_line("admin alice secret").is_err());
+        assert!(parse_token_line("operator alice").is_err());
+    }
+
+    #[test]
+    fn test_authorize_static_token() {
+        let authenticator = Authenticator {
+            tokens: HashMap::from([(
+                "secret".to_string(),
+                Principal {
+                    subject: "hospital-a".into(),
+                    role: Role::Worker,
+                },
+            )]),
+            jwt_key: None,
+        };
+        let authenticator = Some(Arc::new(authenticator));
+
+        let mut worker = Authorize::new(authenticator.clone(), Role::Worker);
+        let authorized = worker.call(request("secret")).unwrap();
+        assert_eq!(
+            authorized.extensions().get::<Principal>().unwrap().subject,
+            "hospital-a"
+        );
+
+        let status = worker.call(Request::new(())).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        let status = worker.call(request("wrong")).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        let mut operator = Authorize::new(authenticator, Role::Operator);
+        let status = operator.call(request("secret")).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    }
+
+    #[test]
+    fn test_authorize_jwt() {
+        let secret = b"jwt secret";
+        let authenticator = Authenticator {
+            tokens: HashMap::new(),
+            jwt_key: Some(DecodingKey::from_secret(secret)),
+        };
+        let mut operator = Authorize::new(Some(Arc::new(authenticator)), Role::Operator);
+
+        let token = |secret: &[u8], exp: u64| {
+            let claims = TestClaims {
+                sub: "alice",
+                role: "operator",
+                exp,
+            };
+            jsonwebtoken::encode(
+                &Header::default(),
+                &claims,
+                &EncodingKey::from_secret(secret),
+            )
+            .unwrap()
+        };
+        let valid_until = jsonwebtoken::get_current_timestamp() + 60;
+
+        let authorized = operator.call(request(&token(secret, valid_until))).unwrap();
+        assert_eq!(
+            authorized.extensions().get::<Principal>(),
+            Some(&Principal {
+                subject: "alice".into(),
+                role: Role::Operator,
+            })
+        );
+
+        let forged = operator.call(request(&token(b"other secret", valid_until)));
+        assert!(forged.is_err());
+
+        let expired = operator.call(request(&token(secret, 1)));
+        assert!(expired.is_err());
+    }
+}
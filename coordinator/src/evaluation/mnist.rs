@@ -0,0 +1,70 @@
+use std::{collections::HashMap, path::Path};
+
+use candle_core::{DType, Error, Tensor, D};
+use candle_nn::{loss, Linear, Module};
+use protocol::mnist;
+
+/// Evaluates the MNIST classifier trained by workers on a held-out test split.
+///
+/// The evaluation runs on the coordinator, independent of which workers
+/// participated in a round, and gives a consistent benchmark across rounds.
+pub struct MnistEvaluator {
+    images: Tensor,
+    labels: Tensor,
+}
+
+impl MnistEvaluator {
+    /// Load the MNIST test split from the IDX files in `dir`, which may be
+    /// compressed with gzip like the training split of workers.
+    pub fn load(dir: &Path) -> Result<Self, Error> {
+        let dataset = mnist::load_dir(dir, "t10k")?;
+
+        Ok(Self {
+            images: dataset.images,
+            labels: dataset.labels.to_dtype(DType::U32)?,
+        })
+    }
+
+    /// Whether models of an architecture can be evaluated, which needs to
+    /// match the tensors that [`MnistEvaluator::evaluate`] reads.
+    pub fn supports(architecture: &str) -> bool {
+        architecture == "mlp"
+    }
+
+    /// Compute loss and accuracy of the global model described by `weights`.
+    pub fn evaluate(
+        &self,
+        weights: &HashMap<String, Tensor>,
+    ) -> Result<HashMap<String, f64>, Error> {
+        let ln1 = linear(weights, "ln1")?;
+        let ln2 = linear(weights, "ln2")?;
+
+        let logits = ln2.forward(&ln1.forward(&self.images)?.relu()?)?;
+
+        let loss = loss::cross_entropy(&logits, &self.labels)?.to_scalar::<f32>()?;
+        let correct = logits
+            .argmax(D::Minus1)?
+            .eq(&self.labels)?
+            .to_dtype(DType::F32)?
+            .sum_all()?
+            .to_scalar::<f32>()?;
+        let total = self.labels.dims()[0];
+
+        Ok(HashMap::from([
+            ("loss".to_string(), loss as f64),
+            ("accuracy".to_string(), correct as f64 / total as f64),
+            ("num_examples".to_string(), total as f64),
+        ]))
+    }
+}
+
+fn linear(weights: &HashMap<String, Tensor>, prefix: &str) -> Result<Linear, Error> {
+    let tensor = |name: &str| {
+        weights
+            .get(&format!("{prefix}.{name}"))
+            .cloned()
+            .ok_or_else(|| Error::Msg(format!("missing tensor {prefix}.{name}")))
+    };
+
+    Ok(Linear::new(tensor("weight")?, Some(tensor("bias")?)))
+}
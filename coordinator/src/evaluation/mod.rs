@@ -0,0 +1,3 @@
+pub use mnist::MnistEvaluator;
+
+mod mnist;
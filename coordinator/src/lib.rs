@@ -0,0 +1,197 @@
+//! Coordinator of federated learning jobs.
+//!
+//! Workers subscribe to the coordinator, which sends them training tasks and
+//! aggregates their results. The `coordinator` binary serves it over gRPC.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use candle_core::Tensor;
+use tonic::{
+    service::interceptor::InterceptedService,
+    transport::{server::Router, Server, ServerTlsConfig},
+};
+use tonic_health::server::{health_reporter, HealthReporter};
+use tracing::info;
+
+use crate::{
+    auth::{Authorize, Role},
+    candlefl::{
+        command_server::CommandServer, publisher_server::PublisherServer,
+        subscriber_server::SubscriberServer, transfer_server::TransferServer, ModelSpec,
+    },
+    evaluation::MnistEvaluator,
+    service::{CommandService, PublisherService, SubscriberService, TransferService},
+    state::Checkpoint,
+};
+
+pub use crate::{
+    auth::Authenticator,
+    provenance::Provenance,
+    state::State,
+    strategy::{EvaluateFn, FedAvg},
+};
+pub use protocol::{blob::CHUNK_SIZE, candlefl};
+
+mod auth;
+mod evaluation;
+mod provenance;
+mod service;
+mod state;
+mod strategy;
+
+/// Default limit of the size of gRPC messages, in bytes.
+///
+/// Weights that are larger than [`CHUNK_SIZE`] are transferred in chunks, so
+/// that they don't need to fit into a single message.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Default limit of the size of blobs that workers upload, in bytes.
+pub const DEFAULT_MAX_BLOB_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Build the gRPC server of the coordinator, with all services sharing `state`.
+///
+/// Connections are encrypted if `tls` is provided. If it includes a client CA
+/// certificate, workers are identified by the subject of their certificate.
+/// With an `authenticator`, clients need a bearer token: workers to subscribe
+/// and publish, operators to send commands. Workers are then identified by the
+/// subject of their token and can only publish results of their own tasks.
+/// Messages that are sent or received are limited to `max_message_size` bytes.
+/// The returned health reporter is used to mark services as not serving on
+/// [`shutdown`].
+pub async fn server(
+    state: State,
+    evaluate: Option<EvaluateFn>,
+    tls: Option<ServerTlsConfig>,
+    authenticator: Option<Authenticator>,
+    max_message_size: usize,
+) -> Result<(Router, HealthReporter), tonic::transport::Error> {
+    let authenticator = authenticator.map(Arc::new);
+    let operator = Authorize::new(authenticator.clone(), Role::Operator);
+    let worker = Authorize::new(authenticator, Role::Worker);
+
+    let command_service = CommandService::new(state.clone(), evaluate, max_message_size);
+    let publisher_service = PublisherService::new(state.clone());
+    let subscriber_service = SubscriberService::new(state.clone());
+    let transfer_service = TransferService::new(state.clone());
+
+    let (mut health_reporter, health_service) = health_reporter();
+    health_reporter
+        .set_serving::<PublisherServer<PublisherService>>()
+        .await;
+    health_reporter
+        .set_serving::<SubscriberServer<SubscriberService>>()
+        .await;
+    health_reporter
+        .set_serving::<TransferServer<TransferService>>()
+        .await;
+
+    let mut builder = Server::builder();
+    if let Some(tls) = tls {
+        builder = builder.tls_config(tls)?;
+    }
+
+    let router = builder
+        .add_service(health_service)
+        .add_service(InterceptedService::new(
+            CommandServer::new(command_service)
+                .max_decoding_message_size(max_message_size)
+                .max_encoding_message_size(max_message_size),
+            operator,
+        ))
+        .add_service(InterceptedService::new(
+            PublisherServer::new(publisher_service)
+                .max_decoding_message_size(max_message_size)
+                .max_encoding_message_size(max_message_size),
+            worker.clone(),
+        ))
+        .add_service(InterceptedService::new(
+            SubscriberServer::new(subscriber_service)
+                .max_decoding_message_size(max_message_size)
+                .max_encoding_message_size(max_message_size),
+            worker.clone(),
+        ))
+        .add_service(InterceptedService::new(
+            TransferServer::new(transfer_service)
+                .max_decoding_message_size(max_message_size)
+                .max_encoding_message_size(max_message_size),
+            worker,
+        ));
+
+    Ok((router, health_reporter))
+}
+
+/// Prepare the coordinator for shutting down.
+///
+/// Marks services as not serving, notifies subscribed workers and interrupts
+/// running jobs. The weights of their last completed round are saved to
+/// `checkpoint_dir`, if provided.
+pub async fn shutdown(
+    state: &State,
+    health_reporter: &mut HealthReporter,
+    checkpoint_dir: Option<&Path>,
+) -> Result<(), anyhow::Error> {
+    health_reporter
+        .set_not_serving::<PublisherServer<PublisherService>>()
+        .await;
+    health_reporter
+        .set_not_serving::<SubscriberServer<SubscriberService>>()
+        .await;
+    health_reporter
+        .set_not_serving::<TransferServer<TransferService>>()
+        .await;
+
+    let checkpoints = state.shutdown().await?;
+
+    if let Some(dir) = checkpoint_dir {
+        for checkpoint in &checkpoints {
+            let path = save_checkpoint(dir, checkpoint)?;
+            info!(job_id = %checkpoint.job_id, path = %path.display(), "saved checkpoint");
+        }
+    }
+
+    Ok(())
+}
+
+/// Save the weights of a checkpoint to "<job ID>.safetensors" in `dir`.
+///
+/// The job ID, round and model architecture are stored as metadata.
+fn save_checkpoint(dir: &Path, checkpoint: &Checkpoint) -> Result<PathBuf, anyhow::Error> {
+    fs::create_dir_all(dir)?;
+    let path = dir.join(format!("{}.safetensors", checkpoint.job_id));
+
+    let metadata = HashMap::from([
+        ("job_id".to_string(), checkpoint.job_id.to_string()),
+        ("round".to_string(), checkpoint.round.to_string()),
+        (
+            "architecture".to_string(),
+            checkpoint.model.architecture.clone(),
+        ),
+    ]);
+    safetensors::serialize_to_file(&checkpoint.weights, &Some(metadata), &path)?;
+
+    Ok(path)
+}
+
+/// Evaluate global models on the MNIST test split in `dir`.
+///
+/// Only models of the "mlp" architecture are evaluated, others are skipped.
+pub fn mnist_evaluation(dir: &Path) -> Result<EvaluateFn, candle_core::Error> {
+    let evaluator = MnistEvaluator::load(dir)?;
+
+    info!(dir = %dir.display(), "loaded evaluation data");
+
+    Ok(Arc::new(
+        move |model: &ModelSpec, weights: &HashMap<String, Tensor>| {
+            if !MnistEvaluator::supports(&model.architecture) {
+                return Ok(None);
+            }
+
+            Ok(Some(evaluator.evaluate(weights)?))
+        },
+    ))
+}
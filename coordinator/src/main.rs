@@ -1,31 +1,114 @@
-use std::net::SocketAddr;
+use std::{fs, io, net::SocketAddr, path::PathBuf};
 
 use clap::Parser;
-use tonic::transport::Server;
-use tonic_health::server::health_reporter;
-use tracing::info;
-
-use crate::{
-    candlefl::{
-        command_server::CommandServer, publisher_server::PublisherServer,
-        subscriber_server::SubscriberServer,
-    },
-    service::{CommandService, PublisherService, SubscriberService},
-    state::State,
-};
+use tokio::signal;
+use tonic::transport::{Certificate, Identity, ServerTlsConfig};
+use tracing::{info, warn};
 
-mod candlefl {
-    tonic::include_proto!("candlefl.v1");
-}
-mod service;
-mod state;
-mod strategy;
+use coordinator::{
+    mnist_evaluation, server, shutdown, Authenticator, Provenance, State, DEFAULT_MAX_BLOB_SIZE,
+    DEFAULT_MAX_MESSAGE_SIZE,
+};
 
 #[derive(Parser)]
 #[command(version)]
 struct Args {
     #[arg(long, default_value_t = String::from("[::1]:50051"))]
     addr: String,
+
+    /// Directory with MNIST IDX files to evaluate the global model after each round
+    #[arg(long)]
+    eval_data_dir: Option<PathBuf>,
+
+    /// Directory to save the weights of jobs that are interrupted by a shutdown
+    #[arg(long)]
+    checkpoint_dir: Option<PathBuf>,
+
+    /// PEM certificate to serve with TLS
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of the TLS certificate
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// PEM CA certificate to require and verify client certificates with.
+    /// Workers are then identified by the common name of their certificate
+    #[arg(long, requires = "tls_cert")]
+    tls_client_ca_cert: Option<PathBuf>,
+
+    /// File with a "<role> <subject> <token>" line per bearer token that clients
+    /// need to authenticate with. Roles are "worker" and "operator"
+    #[arg(long)]
+    token_file: Option<PathBuf>,
+
+    /// File with the secret of HMAC-SHA256 signed JSON Web Tokens that clients
+    /// can authenticate with, with "sub", "role" and "exp" claims
+    #[arg(long)]
+    jwt_secret_file: Option<PathBuf>,
+
+    /// PKCS#8 PEM Ed25519 private key to sign training requests and final models with
+    #[arg(long)]
+    signing_key: Option<PathBuf>,
+
+    /// Directory with the SPKI PEM Ed25519 public key of each enrolled worker,
+    /// as "<worker ID>.pem". Results of workers need to be signed
+    #[arg(long)]
+    worker_keys_dir: Option<PathBuf>,
+
+    /// Limit of the size of gRPC messages in bytes. Weights larger than 1 MiB
+    /// are transferred in chunks, but final models are sent in a single message,
+    /// so training fails early if the model exceeds it
+    #[arg(long, default_value_t = DEFAULT_MAX_MESSAGE_SIZE)]
+    max_message_size: usize,
+
+    /// Limit of the size of weights that workers upload in chunks, in bytes
+    #[arg(long, default_value_t = DEFAULT_MAX_BLOB_SIZE)]
+    max_blob_size: u64,
+}
+
+impl Args {
+    fn tls_config(&self) -> Result<Option<ServerTlsConfig>, io::Error> {
+        let (Some(cert), Some(key)) = (&self.tls_cert, &self.tls_key) else {
+            return Ok(None);
+        };
+
+        let mut config =
+            ServerTlsConfig::new().identity(Identity::from_pem(fs::read(cert)?, fs::read(key)?));
+        if let Some(ca_cert) = &self.tls_client_ca_cert {
+            config = config.client_ca_root(Certificate::from_pem(fs::read(ca_cert)?));
+        }
+
+        Ok(Some(config))
+    }
+
+    fn authenticator(&self) -> Result<Option<Authenticator>, anyhow::Error> {
+        if self.token_file.is_none() && self.jwt_secret_file.is_none() {
+            return Ok(None);
+        }
+
+        let mut authenticator = Authenticator::default();
+        if let Some(path) = &self.token_file {
+            authenticator = authenticator.with_token_file(path)?;
+        }
+        if let Some(path) = &self.jwt_secret_file {
+            authenticator = authenticator.with_jwt_secret_file(path)?;
+        }
+
+        Ok(Some(authenticator))
+    }
+
+    fn provenance(&self) -> Result<Provenance, anyhow::Error> {
+        let mut provenance = Provenance::default();
+        if let Some(path) = &self.signing_key {
+            provenance = provenance.with_signing_key_file(path)?;
+        }
+        if let Some(dir) = &self.worker_keys_dir {
+            provenance = provenance.with_worker_keys_dir(dir)?;
+        }
+
+        Ok(provenance)
+    }
 }
 
 #[tokio::main]
@@ -36,29 +119,68 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let addr: SocketAddr = args.addr.parse()?;
 
-    let state = State::new();
+    let evaluate = args
+        .eval_data_dir
+        .as_deref()
+        .map(mnist_evaluation)
+        .transpose()?;
+
+    let state = State::with_provenance(args.provenance()?, args.max_blob_size);
 
-    let command_service = CommandService::new(state.clone());
-    let publisher_service = PublisherService::new(state.clone());
-    let subscriber_service = SubscriberService::new(state.clone());
+    let authenticator = args.authenticator()?;
 
-    let (mut health_reporter, health_service) = health_reporter();
-    health_reporter
-        .set_serving::<PublisherServer<PublisherService>>()
-        .await;
-    health_reporter
-        .set_serving::<SubscriberServer<SubscriberService>>()
-        .await;
+    info!(
+        addr = %addr,
+        tls = args.tls_cert.is_some(),
+        auth = authenticator.is_some(),
+        "coordinator started"
+    );
 
-    info!(addr = %addr, "coordinator started");
+    let (server, mut health_reporter) = server(
+        state.clone(),
+        evaluate,
+        args.tls_config()?,
+        authenticator,
+        args.max_message_size,
+    )
+    .await?;
 
-    Server::builder()
-        .add_service(health_service)
-        .add_service(CommandServer::new(command_service))
-        .add_service(PublisherServer::new(publisher_service))
-        .add_service(SubscriberServer::new(subscriber_service))
-        .serve(addr)
+    server
+        .serve_with_shutdown(addr, async {
+            shutdown_signal().await;
+
+            info!("shutting down");
+
+            if let Err(e) =
+                shutdown(&state, &mut health_reporter, args.checkpoint_dir.as_deref()).await
+            {
+                warn!(error = %e, "failed to shut down gracefully");
+            }
+        })
         .await?;
 
     Ok(())
 }
+
+/// Completes on SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    let interrupt = async {
+        signal::ctrl_c().await.expect("failed to listen for SIGINT");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
+}
@@ -0,0 +1,149 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use anyhow::anyhow;
+use ed25519_dalek::{
+    pkcs8::{DecodePrivateKey, DecodePublicKey},
+    Signature, Signer, SigningKey, Verifier, VerifyingKey,
+};
+
+pub use protocol::signing::{
+    digest, fit_request_digest, Signed, FIT_REQUEST, FIT_RESPONSE, TRAIN_RESPONSE, WEIGHTS_RESPONSE,
+};
+
+/// Signs the weights sent by the coordinator and verifies the weights
+/// published by workers with Ed25519.
+///
+/// Workers are enrolled by registering their public key. Once any worker is
+/// enrolled, results of workers without a valid signature are rejected.
+#[derive(Default)]
+pub struct Provenance {
+    signing_key: Option<SigningKey>,
+    worker_keys: HashMap<String, VerifyingKey>,
+}
+
+impl Provenance {
+    /// Sign requests and models with the PKCS#8 PEM private key in `path`.
+    pub fn with_signing_key_file(mut self, path: &Path) -> Result<Self, anyhow::Error> {
+        let pem = fs::read_to_string(path)?;
+        let key = SigningKey::from_pkcs8_pem(&pem)
+            .map_err(|e| anyhow!("{}: invalid signing key: {e}", path.display()))?;
+
+        self.signing_key = Some(key);
+
+        Ok(self)
+    }
+
+    /// Enroll the workers whose public keys are in `dir`, as a
+    /// "<worker ID>.pem" SPKI PEM file per worker.
+    pub fn with_worker_keys_dir(mut self, dir: &Path) -> Result<Self, anyhow::Error> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|extension| extension.to_str()) != Some("pem") {
+                continue;
+            }
+            let Some(worker_id) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+
+            let pem = fs::read_to_string(&path)?;
+            let key = VerifyingKey::from_public_key_pem(&pem)
+                .map_err(|e| anyhow!("{}: invalid public key: {e}", path.display()))?;
+
+            self.worker_keys.insert(worker_id.to_string(), key);
+        }
+
+        Ok(self)
+    }
+
+    /// Sign weights sent by the coordinator, or return an empty signature if
+    /// no signing key is configured.
+    pub fn sign(&self, signed: &Signed) -> Vec<u8> {
+        match &self.signing_key {
+            Some(key) => key.sign(&signed.payload()).to_bytes().to_vec(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Verify the signature of weights published by a worker.
+    ///
+    /// All signatures are accepted if no workers are enrolled.
+    pub fn verify(&self, worker_id: &str, signed: &Signed, signature: &[u8]) -> Result<(), String> {
+        if self.worker_keys.is_empty() {
+            return Ok(());
+        }
+
+        let key = self
+            .worker_keys
+            .get(worker_id)
+            .ok_or_else(|| format!("worker {worker_id} isn't enrolled"))?;
+        let signature = Signature::from_slice(signature)
+            .map_err(|_| "missing or malformed signature".to_string())?;
+
+        key.verify(&signed.payload(), &signature)
+            .map_err(|_| "invalid signature".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::candlefl::WeightEncoding;
+
+    use super::*;
+
+    fn signed<'a>(context: &'a str, round: u64, weights: &'a [u8]) -> Signed<'a> {
+        Signed {
+            context,
+            job_id: "job",
+            round,
+            encoding: WeightEncoding::None,
+            weights,
+            fields_digest: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_sign_verify() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let worker = Provenance {
+            signing_key: Some(key.clone()),
+            worker_keys: HashMap::new(),
+        };
+        let coordinator = Provenance {
+            signing_key: None,
+            worker_keys: HashMap::from([("a".to_string(), key.verifying_key())]),
+        };
+
+        let signature = worker.sign(&signed(FIT_RESPONSE, 1, b"weights"));
+        assert!(coordinator
+            .verify("a", &signed(FIT_RESPONSE, 1, b"weights"), &signature)
+            .is_ok());
+
+        // Tampered weights or encodings, replayed rounds or messages and
+        // unknown workers are rejected
+        assert!(coordinator
+            .verify("a", &signed(FIT_RESPONSE, 1, b"tampered"), &signature)
+            .is_err());
+        let fp16 = Signed {
+            encoding: WeightEncoding::Fp16,
+            ..signed(FIT_RESPONSE, 1, b"weights")
+        };
+        assert!(coordinator.verify("a", &fp16, &signature).is_err());
+        assert!(coordinator
+            .verify("a", &signed(FIT_RESPONSE, 2, b"weights"), &signature)
+            .is_err());
+        assert!(coordinator
+            .verify("a", &signed(WEIGHTS_RESPONSE, 1, b"weights"), &signature)
+            .is_err());
+        assert!(coordinator
+            .verify("b", &signed(FIT_RESPONSE, 1, b"weights"), &signature)
+            .is_err());
+        assert!(coordinator
+            .verify("a", &signed(FIT_RESPONSE, 1, b"weights"), &[])
+            .is_err());
+
+        // Without enrolled workers, unsigned results are accepted
+        assert!(worker
+            .verify("a", &signed(FIT_RESPONSE, 1, b"weights"), &[])
+            .is_ok());
+    }
+}
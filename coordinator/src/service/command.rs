@@ -1,41 +1,182 @@
+use std::pin::Pin;
+
+use futures_util::Stream;
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Request, Response, Status};
+use tracing::warn;
+use uuid::Uuid;
 
 use crate::{
-    candlefl::{command_server::Command, TrainRequest, TrainResponse},
-    state::State,
-    strategy::FedAvg,
+    candlefl::{
+        command_server::Command, config_value, ConfigValue, GetMetricsRequest, GetMetricsResponse,
+        ModelSpec, RoundMetrics, TrainRequest, TrainResponse, WatchMetricsRequest, WeightEncoding,
+    },
+    provenance::{self, Signed},
+    state::{State, StateError},
+    strategy::{fit_config, EvaluateFn, FedAvg},
 };
 
+/// Size of the fields of a `TrainResponse` besides the weights, i.e. the job
+/// ID and signature, with their encoding overhead.
+const TRAIN_RESPONSE_OVERHEAD: usize = 128;
+
 pub struct CommandService {
     state: State,
+    evaluate: Option<EvaluateFn>,
+    // Limit of the size of responses, which final models need to fit into.
+    max_message_size: usize,
 }
 
 impl CommandService {
-    pub fn new(state: State) -> Self {
-        Self { state }
+    pub fn new(state: State, evaluate: Option<EvaluateFn>, max_message_size: usize) -> Self {
+        Self {
+            state,
+            evaluate,
+            max_message_size,
+        }
     }
 }
 
 #[tonic::async_trait]
 impl Command for CommandService {
+    type WatchMetricsStream = Pin<Box<dyn Stream<Item = Result<RoundMetrics, Status>> + Send>>;
+
     async fn train(
         &self,
         request: Request<TrainRequest>,
     ) -> Result<Response<TrainResponse>, Status> {
         let request = request.into_inner();
 
-        let strategy = FedAvg::new(self.state.clone());
+        // The final model is sent inline, so it needs to fit into the response
+        let strategy = FedAvg::new(self.state.clone(), self.evaluate.clone()).with_max_model_size(
+            self.max_message_size
+                .saturating_sub(TRAIN_RESPONSE_OVERHEAD),
+        );
 
-        let weights = strategy
-            .fit(request.rounds as usize)
+        // Fail early on invalid configurations instead of after the first round
+        fit_config(
+            &request.fit_config,
+            request.learning_rate_schedule.as_ref(),
+            0,
+            request.rounds as usize,
+        )
+        .map_err(|e| Status::invalid_argument(format!("invalid configuration: {e}")))?;
+
+        // Requests without a model train the MLP on MNIST
+        let model = request.model.unwrap_or_else(|| ModelSpec {
+            architecture: "mlp".to_string(),
+            config: [("input_dim", 784), ("num_classes", 10)]
+                .into_iter()
+                .map(|(name, value)| {
+                    let value = ConfigValue {
+                        value: Some(config_value::Value::Int(value)),
+                    };
+                    (name.to_string(), value)
+                })
+                .collect(),
+        });
+
+        let (job_id, weights) = strategy
+            .fit(
+                request.rounds as usize,
+                model,
+                &request.fit_config,
+                request.learning_rate_schedule.as_ref(),
+                request.update_filter.as_ref(),
+                request.weight_encoding(),
+            )
             .await
-            .map_err(|e| Status::internal(format!("failed to train model: {e}")))?;
+            .map_err(|e| match e.downcast::<StateError>() {
+                Ok(e) => Status::from(e),
+                Err(e) => Status::internal(format!("failed to train model: {e}")),
+            })?;
 
         let serialized_weights = safetensors::serialize(weights, &None)
             .map_err(|e| Status::internal(format!("invalid weights: {e}")))?;
 
+        let job_id = job_id.to_string();
+        let signature = self.state.provenance().sign(&Signed {
+            context: provenance::TRAIN_RESPONSE,
+            job_id: &job_id,
+            round: request.rounds,
+            encoding: WeightEncoding::None,
+            weights: &serialized_weights,
+            fields_digest: Vec::new(),
+        });
+
         Ok(Response::new(TrainResponse {
             weights: serialized_weights,
+            job_id,
+            signature,
+        }))
+    }
+
+    async fn get_metrics(
+        &self,
+        request: Request<GetMetricsRequest>,
+    ) -> Result<Response<GetMetricsResponse>, Status> {
+        let request = request.into_inner();
+
+        let job_id = Uuid::parse_str(request.job_id.as_str()).map_err(|_| {
+            Status::invalid_argument(format!("invalid job ID {}", request.job_id.as_str()))
+        })?;
+
+        let rounds = self.state.get_metrics(job_id).await?;
+        let status = self.state.job_status(job_id).await?;
+
+        Ok(Response::new(GetMetricsResponse {
+            rounds,
+            status: status.into(),
         }))
     }
+
+    async fn watch_metrics(
+        &self,
+        request: Request<WatchMetricsRequest>,
+    ) -> Result<Response<Self::WatchMetricsStream>, Status> {
+        let request = request.into_inner();
+
+        // An empty job ID watches all jobs
+        let job_id = if request.job_id.is_empty() {
+            None
+        } else {
+            let job_id = Uuid::parse_str(request.job_id.as_str()).map_err(|_| {
+                Status::invalid_argument(format!("invalid job ID {}", request.job_id.as_str()))
+            })?;
+            Some(job_id.to_string())
+        };
+
+        let mut metrics = self.state.watch_metrics().await?;
+
+        let (sender, receiver) = mpsc::channel(32);
+
+        tokio::spawn(async move {
+            loop {
+                match metrics.recv().await {
+                    Ok(round_metrics) => {
+                        if job_id
+                            .as_ref()
+                            .is_some_and(|id| *id != round_metrics.job_id)
+                        {
+                            continue;
+                        }
+
+                        // The client disconnected
+                        if sender.send(Ok(round_metrics)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!(skipped, "metrics watcher lagged behind");
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+
+        Ok(Response::new(
+            Box::pin(ReceiverStream::new(receiver)) as Self::WatchMetricsStream
+        ))
+    }
 }
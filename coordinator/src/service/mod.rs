@@ -1,7 +1,55 @@
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::auth::Principal;
+
 mod command;
 mod publisher;
 mod subscriber;
+mod transfer;
 
 pub use command::CommandService;
 pub use publisher::PublisherService;
 pub use subscriber::SubscriberService;
+pub use transfer::TransferService;
+
+/// Metadata key of the ID that workers subscribe and publish with.
+const WORKER_ID_KEY: &str = "x-worker-id";
+
+/// ID of the worker sending a request, if it is valid.
+///
+/// Workers authenticated with a client certificate are identified by the
+/// common name of its subject, and workers authenticated with a token by its
+/// subject, regardless of the ID they send. If both are present, they need to
+/// match. Workers that don't send an ID are identified by their address, so
+/// they can't resubscribe after reconnecting.
+fn worker_id<T>(request: &tonic::Request<T>) -> Option<String> {
+    let subject = request
+        .extensions()
+        .get::<Principal>()
+        .map(|principal| principal.subject.clone());
+
+    if let Some(certs) = request.peer_certs() {
+        let common_name = certs.first().and_then(|cert| common_name(cert.get_ref()));
+
+        return match subject {
+            Some(subject) if common_name.as_ref() != Some(&subject) => None,
+            _ => common_name,
+        };
+    }
+    if subject.is_some() {
+        return subject;
+    }
+
+    match request.metadata().get(WORKER_ID_KEY) {
+        Some(id) => id.to_str().ok().map(str::to_string),
+        None => request.remote_addr().map(|addr| addr.to_string()),
+    }
+}
+
+/// Common name of the subject of a DER-encoded certificate.
+fn common_name(der: &[u8]) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(der).ok()?;
+    let common_name = cert.subject().iter_common_name().next()?;
+
+    common_name.as_str().ok().map(str::to_string)
+}
@@ -1,13 +1,16 @@
 use std::collections::HashMap;
 
-use candle_core::{safetensors::load_buffer, Device, Tensor};
+use candle_core::Tensor;
+use protocol::encoding;
 use tonic::{Request, Response, Status};
-use tracing::debug;
+use tracing::{debug, info, warn};
 use uuid::Uuid;
 
 use crate::{
     candlefl::{publisher_server::Publisher, worker_message, WorkerMessage},
-    state::State,
+    provenance::{self, digest, Signed},
+    service::worker_id,
+    state::{FitResult, State},
 };
 
 pub struct PublisherService {
@@ -18,18 +21,83 @@ impl PublisherService {
     pub fn new(state: State) -> Self {
         Self { state }
     }
+
+    /// Serialized weights of a result, sent inline or uploaded as a blob before.
+    ///
+    /// A missing blob fails the task, so that the round doesn't wait for it.
+    async fn data(
+        &self,
+        job_id: Uuid,
+        worker_id: &str,
+        round: u64,
+        weights: Vec<u8>,
+        blob_id: &str,
+    ) -> Result<Vec<u8>, Status> {
+        if blob_id.is_empty() {
+            return Ok(weights);
+        }
+
+        match self.state.blobs().take_upload(job_id, worker_id, blob_id) {
+            Ok(data) => Ok(data),
+            Err(e) => {
+                self.state
+                    .fail_task(job_id, worker_id.to_string(), round, e.to_string())
+                    .await?;
+
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Verify the signature of a result and decode its weights.
+    ///
+    /// Invalid signatures or weights reject the result, so that the round
+    /// doesn't wait for it and records the rejection.
+    async fn weights(
+        &self,
+        job_id: Uuid,
+        worker_id: &str,
+        signed: Signed<'_>,
+        signature: &[u8],
+    ) -> Result<HashMap<String, Tensor>, Status> {
+        if let Err(e) = self
+            .state
+            .provenance()
+            .verify(worker_id, &signed, signature)
+        {
+            let message = format!("rejected signature: {e}");
+            self.state
+                .reject_result(job_id, worker_id.to_string(), message.clone())
+                .await?;
+
+            return Err(Status::unauthenticated(message));
+        }
+
+        match encoding::decode(signed.weights, signed.encoding) {
+            Ok(weights) => Ok(weights),
+            Err(e) => {
+                let message = format!("invalid weights: {e}");
+                self.state
+                    .reject_result(job_id, worker_id.to_string(), message.clone())
+                    .await?;
+
+                Err(Status::invalid_argument(message))
+            }
+        }
+    }
 }
 
 #[tonic::async_trait]
 impl Publisher for PublisherService {
     async fn publish(&self, request: Request<WorkerMessage>) -> Result<Response<()>, Status> {
-        let addr = request.remote_addr().unwrap();
+        let worker_id = worker_id(&request)
+            .ok_or_else(|| Status::invalid_argument("missing or invalid worker ID"))?;
 
         if let Some(message) = request.into_inner().message {
             match message {
                 worker_message::Message::WeightsResponse(weights_response) => {
                     debug!(
-                        addr = addr.to_string(),
+                        worker_id,
                         job_id = weights_response.job_id,
                         "received WeightsResponse"
                     );
@@ -41,17 +109,44 @@ impl Publisher for PublisherService {
                             ))
                         })?;
 
-                    let weights = deserialize(&weights_response.weights)
-                        .map_err(|e| Status::invalid_argument(format!("invalid weights: {e}")))?;
+                    let encoding = weights_response.weight_encoding();
+                    let data = self
+                        .data(
+                            job_id,
+                            &worker_id,
+                            0,
+                            weights_response.weights,
+                            &weights_response.weights_blob_id,
+                        )
+                        .await?;
 
-                    self.state
-                        .set_fit_result(job_id, addr, weights)
-                        .await
-                        .unwrap();
+                    let signed = Signed {
+                        context: provenance::WEIGHTS_RESPONSE,
+                        job_id: &weights_response.job_id,
+                        round: 0,
+                        encoding,
+                        weights: &data,
+                        fields_digest: Vec::new(),
+                    };
+                    let weights = self
+                        .weights(job_id, &worker_id, signed, &weights_response.signature)
+                        .await?;
+
+                    let result = FitResult {
+                        worker_id: worker_id.clone(),
+                        round: 0,
+                        weights,
+                        metrics: HashMap::new(),
+                        weights_digest: digest(&data),
+                        signature: weights_response.signature,
+                    };
+
+                    // Results can be delivered again after a worker reconnects
+                    self.state.set_fit_result(job_id, worker_id, result).await?;
                 }
                 worker_message::Message::FitResponse(fit_response) => {
                     debug!(
-                        addr = addr.to_string(),
+                        worker_id,
                         job_id = fit_response.job_id,
                         "received FitResponse"
                     );
@@ -62,13 +157,62 @@ impl Publisher for PublisherService {
                         ))
                     })?;
 
-                    let weights = deserialize(&fit_response.weights)
-                        .map_err(|e| Status::invalid_argument(format!("invalid weights: {e}")))?;
+                    let encoding = fit_response.weight_encoding();
+                    let data = self
+                        .data(
+                            job_id,
+                            &worker_id,
+                            fit_response.round,
+                            fit_response.weights,
+                            &fit_response.weights_blob_id,
+                        )
+                        .await?;
+
+                    let signed = Signed {
+                        context: provenance::FIT_RESPONSE,
+                        job_id: &fit_response.job_id,
+                        round: fit_response.round,
+                        encoding,
+                        weights: &data,
+                        fields_digest: Vec::new(),
+                    };
+                    let weights = self
+                        .weights(job_id, &worker_id, signed, &fit_response.signature)
+                        .await?;
+
+                    let result = FitResult {
+                        worker_id: worker_id.clone(),
+                        round: fit_response.round,
+                        weights,
+                        metrics: fit_response.metrics,
+                        weights_digest: digest(&data),
+                        signature: fit_response.signature,
+                    };
+
+                    self.state.set_fit_result(job_id, worker_id, result).await?;
+                }
+                worker_message::Message::TaskError(task_error) => {
+                    warn!(
+                        worker_id,
+                        job_id = task_error.job_id,
+                        error = task_error.message,
+                        "worker failed task"
+                    );
+                    let job_id = Uuid::parse_str(task_error.job_id.as_str()).map_err(|_| {
+                        Status::invalid_argument(format!(
+                            "invalid job ID {}",
+                            task_error.job_id.as_str()
+                        ))
+                    })?;
 
                     self.state
-                        .set_fit_result(job_id, addr, weights)
-                        .await
-                        .unwrap();
+                        .fail_task(job_id, worker_id, task_error.round, task_error.message)
+                        .await?;
+                }
+                worker_message::Message::Unregister(_) => {
+                    info!(worker_id, "worker unregistering");
+
+                    self.state.remove_worker(worker_id).await?;
                 }
             }
         }
@@ -76,7 +220,3 @@ impl Publisher for PublisherService {
         Ok(Response::new(()))
     }
 }
-
-fn deserialize(data: &[u8]) -> Result<HashMap<String, Tensor>, candle_core::Error> {
-    load_buffer(data, &Device::Cpu)
-}
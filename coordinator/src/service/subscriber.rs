@@ -8,6 +8,7 @@ use tracing::info;
 
 use crate::{
     candlefl::{subscriber_server::Subscriber, CoordinatorMessage},
+    service::worker_id,
     state::State,
 };
 
@@ -29,16 +30,14 @@ impl Subscriber for SubscriberService {
         &self,
         request: Request<()>,
     ) -> Result<Response<Self::SubscribeStream>, Status> {
-        let addr = request.remote_addr().unwrap();
+        let worker_id = worker_id(&request)
+            .ok_or_else(|| Status::invalid_argument("missing or invalid worker ID"))?;
 
-        info!(addr = addr.to_string(), "worker subscribing");
+        info!(worker_id, "worker subscribing");
 
         let (sender, receiver) = mpsc::channel(32);
 
-        self.state
-            .add_worker(addr, sender)
-            .await
-            .map_err(|e| Status::internal(format!("failed to add worker: {e}")))?;
+        self.state.add_worker(worker_id, sender).await?;
 
         Ok(Response::new(
             Box::pin(ReceiverStream::new(receiver)) as Self::SubscribeStream
@@ -0,0 +1,107 @@
+use std::pin::Pin;
+
+use futures_util::{stream, Stream};
+use protocol::blob::chunks;
+use tonic::{Request, Response, Status, Streaming};
+use tracing::debug;
+use uuid::Uuid;
+
+use crate::{
+    candlefl::{
+        transfer_server::Transfer, Chunk, DownloadRequest, UploadStatus, UploadStatusRequest,
+    },
+    service::worker_id,
+    state::State,
+};
+
+pub struct TransferService {
+    state: State,
+}
+
+impl TransferService {
+    pub fn new(state: State) -> Self {
+        Self { state }
+    }
+}
+
+#[tonic::async_trait]
+impl Transfer for TransferService {
+    type DownloadStream = Pin<Box<dyn Stream<Item = Result<Chunk, Status>> + Send>>;
+
+    async fn download(
+        &self,
+        request: Request<DownloadRequest>,
+    ) -> Result<Response<Self::DownloadStream>, Status> {
+        let request = request.into_inner();
+
+        let job_id = parse_job_id(&request.job_id)?;
+        let blob = self.state.blobs().get(job_id, &request.blob_id)?;
+        let size = blob.len();
+        let offset = usize::try_from(request.offset)
+            .ok()
+            .filter(|offset| *offset <= size)
+            .ok_or_else(|| {
+                Status::out_of_range(format!("offset {} exceeds the blob", request.offset))
+            })?;
+
+        debug!(%job_id, blob_id = request.blob_id, offset, size, "sending blob");
+
+        let chunks = chunks(request.job_id, request.blob_id, blob, offset).map(Ok);
+
+        Ok(Response::new(
+            Box::pin(stream::iter(chunks)) as Self::DownloadStream
+        ))
+    }
+
+    async fn upload(
+        &self,
+        request: Request<Streaming<Chunk>>,
+    ) -> Result<Response<UploadStatus>, Status> {
+        let worker_id = worker_id(&request)
+            .ok_or_else(|| Status::invalid_argument("missing or invalid worker ID"))?;
+
+        let mut chunks = request.into_inner();
+        let mut status = None;
+
+        // Chunks received before the stream breaks are kept, so that the
+        // worker can resume the upload
+        while let Some(chunk) = chunks.message().await? {
+            let job_id = parse_job_id(&chunk.job_id)?;
+            status = Some(self.state.blobs().append(job_id, &worker_id, chunk)?);
+        }
+
+        let status = status.ok_or_else(|| Status::invalid_argument("no chunks uploaded"))?;
+
+        debug!(
+            worker_id,
+            blob_id = status.blob_id,
+            offset = status.offset,
+            complete = status.complete,
+            "received chunks"
+        );
+
+        Ok(Response::new(status))
+    }
+
+    async fn get_upload_status(
+        &self,
+        request: Request<UploadStatusRequest>,
+    ) -> Result<Response<UploadStatus>, Status> {
+        let worker_id = worker_id(&request)
+            .ok_or_else(|| Status::invalid_argument("missing or invalid worker ID"))?;
+
+        let request = request.into_inner();
+        let job_id = parse_job_id(&request.job_id)?;
+
+        Ok(Response::new(self.state.blobs().upload_status(
+            job_id,
+            &worker_id,
+            &request.blob_id,
+        )))
+    }
+}
+
+fn parse_job_id(job_id: &str) -> Result<Uuid, Status> {
+    Uuid::parse_str(job_id)
+        .map_err(|_| Status::invalid_argument(format!("invalid job ID {job_id}")))
+}
@@ -0,0 +1,348 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use protocol::blob::{blob_id, CHUNK_SIZE};
+use uuid::Uuid;
+
+use crate::{
+    candlefl::{Chunk, UploadStatus},
+    state::StateError,
+};
+
+/// Blobs of serialized weights that are too large for a single message.
+///
+/// Blobs are identified by the hex-encoded SHA-256 digest of their content and
+/// belong to a job, so that jobs with identical weights don't remove each
+/// other's blobs. Only workers with an open task in a job upload to it, a
+/// single blob with the result of their task. Uploads are kept until the
+/// result is published, so that workers can resume interrupted uploads, and
+/// are evicted once the task is complete or the job ends.
+pub struct Blobs {
+    // Workers with an open task, by job, which may upload its result.
+    tasks: Mutex<HashSet<(Uuid, String)>>,
+    // Blobs that workers download, e.g. the global weights of a round, by job
+    // and blob ID.
+    downloads: Mutex<HashMap<(Uuid, String), Arc<[u8]>>>,
+    // Blobs that workers upload, by job, worker and blob ID.
+    uploads: Mutex<HashMap<(Uuid, String, String), Upload>>,
+    // Limit of the size of uploaded blobs, in bytes.
+    max_size: u64,
+}
+
+struct Upload {
+    data: Vec<u8>,
+    size: u64,
+    complete: bool,
+}
+
+impl Blobs {
+    /// Create blobs whose uploads are limited to `max_size` bytes.
+    pub fn new(max_size: u64) -> Self {
+        Self {
+            tasks: Mutex::default(),
+            downloads: Mutex::default(),
+            uploads: Mutex::default(),
+            max_size,
+        }
+    }
+
+    /// Accept the upload of the result of a task of a worker.
+    pub fn open_task(&self, job_id: Uuid, worker_id: &str) {
+        self.tasks
+            .lock()
+            .unwrap()
+            .insert((job_id, worker_id.to_string()));
+    }
+
+    /// Stop accepting uploads of a worker whose task is complete or who left
+    /// the job, and remove its upload if it wasn't taken.
+    pub fn close_task(&self, job_id: Uuid, worker_id: &str) {
+        self.tasks
+            .lock()
+            .unwrap()
+            .remove(&(job_id, worker_id.to_string()));
+        self.uploads
+            .lock()
+            .unwrap()
+            .retain(|(id, worker, _), _| *id != job_id || worker != worker_id);
+    }
+
+    /// Remove all blobs of a job that ended and stop accepting its uploads.
+    pub fn remove_job(&self, job_id: Uuid) {
+        self.tasks.lock().unwrap().retain(|(id, _)| *id != job_id);
+        self.downloads
+            .lock()
+            .unwrap()
+            .retain(|(id, _), _| *id != job_id);
+        self.uploads
+            .lock()
+            .unwrap()
+            .retain(|(id, _, _), _| *id != job_id);
+    }
+
+    /// Make a blob of a job available for download and return its ID.
+    pub fn publish(&self, job_id: Uuid, data: Vec<u8>) -> String {
+        let blob_id = blob_id(&data);
+        self.downloads
+            .lock()
+            .unwrap()
+            .insert((job_id, blob_id.clone()), data.into());
+
+        blob_id
+    }
+
+    /// Remove a blob of a job that is no longer downloaded.
+    pub fn unpublish(&self, job_id: Uuid, blob_id: &str) {
+        self.downloads
+            .lock()
+            .unwrap()
+            .remove(&(job_id, blob_id.to_string()));
+    }
+
+    pub fn get(&self, job_id: Uuid, blob_id: &str) -> Result<Arc<[u8]>, StateError> {
+        self.downloads
+            .lock()
+            .unwrap()
+            .get(&(job_id, blob_id.to_string()))
+            .cloned()
+            .ok_or_else(|| StateError::BlobNotFound(blob_id.to_string()))
+    }
+
+    /// Status of an upload of a worker, which starts at offset 0.
+    pub fn upload_status(&self, job_id: Uuid, worker_id: &str, blob_id: &str) -> UploadStatus {
+        let uploads = self.uploads.lock().unwrap();
+        let upload = uploads.get(&(job_id, worker_id.to_string(), blob_id.to_string()));
+
+        UploadStatus {
+            blob_id: blob_id.to_string(),
+            offset: upload.map_or(0, |upload| upload.data.len() as u64),
+            complete: upload.is_some_and(|upload| upload.complete),
+        }
+    }
+
+    /// Append a chunk to an upload of a worker.
+    ///
+    /// The worker needs an open task in the job. Starting the upload of
+    /// another blob replaces its previous upload, e.g. of a result it
+    /// abandoned. Chunks need to continue at the offset received so far. Once
+    /// the blob is complete, its content is checked against its ID. Blobs that
+    /// don't match are discarded.
+    pub fn append(
+        &self,
+        job_id: Uuid,
+        worker_id: &str,
+        chunk: Chunk,
+    ) -> Result<UploadStatus, StateError> {
+        let invalid = |reason: String| StateError::InvalidChunk {
+            blob_id: chunk.blob_id.clone(),
+            reason,
+        };
+
+        if !self
+            .tasks
+            .lock()
+            .unwrap()
+            .contains(&(job_id, worker_id.to_string()))
+        {
+            return Err(StateError::UnexpectedResult {
+                job_id,
+                worker_id: worker_id.to_string(),
+            });
+        }
+        if chunk.data.len() > CHUNK_SIZE {
+            return Err(invalid(format!(
+                "chunk of {} bytes exceeds {CHUNK_SIZE} bytes",
+                chunk.data.len()
+            )));
+        }
+        if chunk.size > self.max_size {
+            return Err(invalid(format!(
+                "blob of {} bytes exceeds {} bytes",
+                chunk.size, self.max_size
+            )));
+        }
+
+        let key = (job_id, worker_id.to_string(), chunk.blob_id.clone());
+        let mut uploads = self.uploads.lock().unwrap();
+        if !uploads.contains_key(&key) {
+            uploads.retain(|(id, worker, _), _| *id != job_id || worker != worker_id);
+        }
+        let upload = uploads.entry(key.clone()).or_insert_with(|| Upload {
+            data: Vec::new(),
+            size: chunk.size,
+            complete: false,
+        });
+
+        if upload.complete {
+            return Err(invalid("blob is already complete".to_string()));
+        }
+        if chunk.size != upload.size {
+            return Err(invalid(format!(
+                "blob size {}, expected {}",
+                chunk.size, upload.size
+            )));
+        }
+        if chunk.offset != upload.data.len() as u64 {
+            return Err(invalid(format!(
+                "chunk at offset {}, expected {}",
+                chunk.offset,
+                upload.data.len()
+            )));
+        }
+        if upload.data.len() as u64 + chunk.data.len() as u64 > upload.size {
+            return Err(invalid("chunk exceeds the size of the blob".to_string()));
+        }
+
+        upload.data.extend_from_slice(&chunk.data);
+
+        if upload.data.len() as u64 == upload.size {
+            if blob_id(&upload.data) != chunk.blob_id {
+                uploads.remove(&key);
+                return Err(StateError::ChecksumMismatch(chunk.blob_id));
+            }
+            upload.complete = true;
+        }
+
+        Ok(UploadStatus {
+            blob_id: chunk.blob_id.clone(),
+            offset: upload.data.len() as u64,
+            complete: upload.complete,
+        })
+    }
+
+    /// Take a complete upload of a worker.
+    pub fn take_upload(
+        &self,
+        job_id: Uuid,
+        worker_id: &str,
+        blob_id: &str,
+    ) -> Result<Vec<u8>, StateError> {
+        let key = (job_id, worker_id.to_string(), blob_id.to_string());
+        let mut uploads = self.uploads.lock().unwrap();
+
+        match uploads.remove(&key) {
+            Some(upload) if upload.complete => Ok(upload.data),
+            Some(upload) => {
+                uploads.insert(key, upload);
+                Err(StateError::BlobNotFound(blob_id.to_string()))
+            }
+            None => Err(StateError::BlobNotFound(blob_id.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(blob_id: &str, offset: usize, data: &[u8], size: usize) -> Chunk {
+        Chunk {
+            blob_id: blob_id.to_string(),
+            offset: offset as u64,
+            size: size as u64,
+            data: data.to_vec(),
+            job_id: String::new(),
+        }
+    }
+
+    fn blobs(job_id: Uuid) -> Blobs {
+        let blobs = Blobs::new(1024);
+        blobs.open_task(job_id, "a");
+        blobs.open_task(job_id, "b");
+        blobs
+    }
+
+    #[test]
+    fn test_resume_upload() {
+        let job = Uuid::new_v4();
+        let blobs = blobs(job);
+        let data = b"serialized weights";
+        let id = blob_id(data);
+
+        let status = blobs
+            .append(job, "a", chunk(&id, 0, &data[..5], data.len()))
+            .unwrap();
+        assert_eq!((status.offset, status.complete), (5, false));
+
+        // An interrupted upload resumes at the offset received so far
+        assert!(blobs
+            .append(job, "a", chunk(&id, 0, data, data.len()))
+            .is_err());
+        assert_eq!(blobs.upload_status(job, "a", &id).offset, 5);
+        assert_eq!(blobs.upload_status(job, "b", &id).offset, 0);
+        assert!(blobs.take_upload(job, "a", &id).is_err());
+
+        let status = blobs
+            .append(job, "a", chunk(&id, 5, &data[5..], data.len()))
+            .unwrap();
+        assert_eq!((status.offset, status.complete), (data.len() as u64, true));
+
+        assert_eq!(blobs.take_upload(job, "a", &id).unwrap(), data);
+        assert!(blobs.take_upload(job, "a", &id).is_err());
+    }
+
+    #[test]
+    fn test_checksum_mismatch() {
+        let job = Uuid::new_v4();
+        let blobs = blobs(job);
+        let id = blob_id(b"serialized weights");
+
+        let result = blobs.append(job, "a", chunk(&id, 0, b"tampered weights!!", 18));
+        assert!(matches!(result, Err(StateError::ChecksumMismatch(_))));
+        assert_eq!(blobs.upload_status(job, "a", &id).offset, 0);
+    }
+
+    #[test]
+    fn test_evict() {
+        let (job, other) = (Uuid::new_v4(), Uuid::new_v4());
+        let blobs = blobs(job);
+        blobs.open_task(other, "a");
+        let data = b"serialized weights";
+        let id = blob_id(data);
+
+        // Blobs larger than the limit and uploads without a task are rejected
+        assert!(blobs.append(job, "a", chunk(&id, 0, data, 2048)).is_err());
+        assert!(matches!(
+            blobs.append(other, "b", chunk(&id, 0, data, data.len())),
+            Err(StateError::UnexpectedResult { .. })
+        ));
+
+        for (job, worker) in [(job, "a"), (job, "b"), (other, "a")] {
+            blobs
+                .append(job, worker, chunk(&id, 0, &data[..5], data.len()))
+                .unwrap();
+        }
+
+        // Workers have a single upload per job
+        let tampered = blob_id(b"tampered weights");
+        blobs
+            .append(job, "b", chunk(&tampered, 0, &data[..5], data.len()))
+            .unwrap();
+        assert_eq!(blobs.upload_status(job, "b", &id).offset, 0);
+
+        blobs.close_task(job, "a");
+        assert_eq!(blobs.upload_status(job, "a", &id).offset, 0);
+        assert_eq!(blobs.upload_status(job, "b", &tampered).offset, 5);
+        assert_eq!(blobs.upload_status(other, "a", &id).offset, 5);
+        assert!(blobs
+            .append(job, "a", chunk(&id, 0, data, data.len()))
+            .is_err());
+
+        // Jobs with the same weights keep their own downloads
+        assert_eq!(blobs.publish(job, data.to_vec()), id);
+        assert_eq!(blobs.publish(other, data.to_vec()), id);
+        blobs.unpublish(job, &id);
+        assert!(blobs.get(job, &id).is_err());
+        assert_eq!(&*blobs.get(other, &id).unwrap(), data);
+
+        blobs.remove_job(other);
+        assert!(blobs.get(other, &id).is_err());
+        assert_eq!(blobs.upload_status(other, "a", &id).offset, 0);
+        assert_eq!(blobs.upload_status(job, "b", &tampered).offset, 5);
+        assert!(blobs
+            .append(other, "a", chunk(&id, 0, data, data.len()))
+            .is_err());
+    }
+}
@@ -0,0 +1,81 @@
+use thiserror::Error;
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinError,
+};
+use tonic::Status;
+use uuid::Uuid;
+
+/// Errors of operations on the coordinator state.
+#[derive(Debug, Error)]
+pub enum StateError {
+    #[error("job {0} not found")]
+    JobNotFound(Uuid),
+    #[error("worker {0} not found")]
+    WorkerNotFound(String),
+    #[error("no pending task of worker {worker_id} in job {job_id}")]
+    UnexpectedResult { job_id: Uuid, worker_id: String },
+    #[error("invalid update from worker {worker_id}: {reason}")]
+    InvalidUpdate { worker_id: String, reason: String },
+    #[error("worker {worker_id} failed its task: {message}")]
+    TaskFailed {
+        worker_id: String,
+        round: u64,
+        message: String,
+    },
+    #[error("worker {0} left before completing its task")]
+    WorkerLeft(String),
+    #[error("no worker completed round {0}")]
+    NoResults(u64),
+    #[error("no workers are connected")]
+    NoWorkers,
+    #[error("blob {0} not found")]
+    BlobNotFound(String),
+    #[error("invalid chunk of blob {blob_id}: {reason}")]
+    InvalidChunk { blob_id: String, reason: String },
+    #[error("content of blob {0} doesn't match its digest")]
+    ChecksumMismatch(String),
+    #[error("failed to serialize weights: {0}")]
+    Serialization(#[from] candle_core::Error),
+    #[error("task failed: {0}")]
+    Task(#[from] JoinError),
+    // The state handler stopped, which only happens if it panicked
+    #[error("state is unavailable")]
+    Unavailable,
+}
+
+impl<T> From<mpsc::error::SendError<T>> for StateError {
+    fn from(_: mpsc::error::SendError<T>) -> Self {
+        StateError::Unavailable
+    }
+}
+
+impl From<oneshot::error::RecvError> for StateError {
+    fn from(_: oneshot::error::RecvError) -> Self {
+        StateError::Unavailable
+    }
+}
+
+impl From<StateError> for Status {
+    fn from(error: StateError) -> Self {
+        let message = error.to_string();
+
+        match error {
+            StateError::JobNotFound(_)
+            | StateError::WorkerNotFound(_)
+            | StateError::BlobNotFound(_) => Status::not_found(message),
+            StateError::UnexpectedResult { .. } | StateError::NoWorkers => {
+                Status::failed_precondition(message)
+            }
+            StateError::InvalidUpdate { .. } | StateError::InvalidChunk { .. } => {
+                Status::invalid_argument(message)
+            }
+            StateError::ChecksumMismatch(_) => Status::data_loss(message),
+            StateError::TaskFailed { .. } => Status::aborted(message),
+            StateError::WorkerLeft(_) | StateError::NoResults(_) | StateError::Unavailable => {
+                Status::unavailable(message)
+            }
+            StateError::Serialization(_) | StateError::Task(_) => Status::internal(message),
+        }
+    }
+}
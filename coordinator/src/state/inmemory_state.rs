@@ -1,14 +1,18 @@
-use std::{collections::HashMap, net::SocketAddr};
+use std::{collections::HashMap, sync::Arc};
 
 use candle_core::Tensor;
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{broadcast, mpsc, oneshot};
 use tonic::Status;
-use tracing::warn;
+use tracing::{info, warn};
 use uuid::Uuid;
 
 use crate::{
-    candlefl::CoordinatorMessage,
-    state::{job::Job, worker::Worker},
+    candlefl::{
+        coordinator_message, ConfigValue, CoordinatorMessage, JobStatus, ModelSpec, RoundMetrics,
+        Shutdown, WeightEncoding,
+    },
+    provenance::Provenance,
+    state::{job::Job, worker::Worker, Blobs, Checkpoint, FitResult, RoundResults, StateError},
 };
 
 /// In-memory state for the coordinator.
@@ -20,31 +24,81 @@ use crate::{
 pub struct InMemoryState {
     workers: Vec<Worker>,
     jobs: HashMap<Uuid, Job>,
+    // Round metrics of all jobs are broadcast to watching clients.
+    metrics: broadcast::Sender<RoundMetrics>,
+    provenance: Arc<Provenance>,
+    blobs: Arc<Blobs>,
 }
 
 impl InMemoryState {
-    pub fn new() -> Self {
+    pub fn new(provenance: Arc<Provenance>, blobs: Arc<Blobs>) -> Self {
+        let (metrics, _) = broadcast::channel(64);
+
         InMemoryState {
             workers: Vec::new(),
             jobs: HashMap::new(),
+            metrics,
+            provenance,
+            blobs,
+        }
+    }
+
+    pub fn num_workers(&self, response: oneshot::Sender<Result<usize, StateError>>) {
+        if response.send(Ok(self.workers.len())).is_err() {
+            warn!("failed to set response");
         }
     }
 
     pub fn add_worker(
         &mut self,
-        addr: SocketAddr,
+        id: String,
         sender: mpsc::Sender<Result<CoordinatorMessage, Status>>,
-        response: oneshot::Sender<Result<(), anyhow::Error>>,
+        response: oneshot::Sender<Result<(), StateError>>,
     ) {
-        self.workers.push(Worker::new(addr, sender));
+        // A worker that reconnects keeps its tasks in running jobs
+        if let Some(worker) = self.workers.iter().find(|worker| worker.id() == id) {
+            info!(worker_id = id, "worker resubscribed");
+            worker.resubscribe(sender);
+        } else {
+            self.workers.push(Worker::new(id, sender));
+        }
 
         if response.send(Ok(())).is_err() {
             warn!("failed to set response");
         }
     }
 
-    pub fn add_job(&mut self, response: oneshot::Sender<Result<Uuid, anyhow::Error>>) {
-        let job = Job::new(self.workers.clone());
+    pub fn remove_worker(&mut self, id: &str, response: oneshot::Sender<Result<(), StateError>>) {
+        let result = if let Some(index) = self.workers.iter().position(|worker| worker.id() == id) {
+            self.workers.remove(index);
+
+            for job in self.jobs.values_mut() {
+                job.remove_worker(id);
+            }
+
+            Ok(())
+        } else {
+            Err(StateError::WorkerNotFound(id.to_string()))
+        };
+
+        if response.send(result).is_err() {
+            warn!("failed to set response");
+        }
+    }
+
+    pub fn add_job(
+        &mut self,
+        model: ModelSpec,
+        encoding: WeightEncoding,
+        response: oneshot::Sender<Result<Uuid, StateError>>,
+    ) {
+        let job = Job::new(
+            self.workers.clone(),
+            model,
+            encoding,
+            self.provenance.clone(),
+            self.blobs.clone(),
+        );
         let job_id = job.id();
         self.jobs.insert(job_id, job);
 
@@ -56,14 +110,11 @@ impl InMemoryState {
     pub fn get_weights(
         &mut self,
         job_id: Uuid,
-        response: oneshot::Sender<Result<HashMap<String, Tensor>, anyhow::Error>>,
+        response: oneshot::Sender<Result<HashMap<String, Tensor>, StateError>>,
     ) {
         if let Some(job) = self.jobs.get_mut(&job_id) {
             job.get_weights(response);
-        } else if response
-            .send(Err(anyhow::anyhow!("job {job_id} not found")))
-            .is_err()
-        {
+        } else if response.send(Err(StateError::JobNotFound(job_id))).is_err() {
             warn!("failed to set response");
         }
     }
@@ -71,15 +122,14 @@ impl InMemoryState {
     pub fn fit_round(
         &mut self,
         job_id: Uuid,
+        round: u64,
         weights: &HashMap<String, Tensor>,
-        response: oneshot::Sender<Result<Vec<HashMap<String, Tensor>>, anyhow::Error>>,
+        config: HashMap<String, ConfigValue>,
+        response: oneshot::Sender<Result<RoundResults, StateError>>,
     ) {
         if let Some(job) = self.jobs.get_mut(&job_id) {
-            job.fit_round(weights, response);
-        } else if response
-            .send(Err(anyhow::anyhow!("job {job_id} not found")))
-            .is_err()
-        {
+            job.fit_round(round, weights, config, response);
+        } else if response.send(Err(StateError::JobNotFound(job_id))).is_err() {
             warn!("failed to set response");
         }
     }
@@ -87,16 +137,125 @@ impl InMemoryState {
     pub fn set_fit_result(
         &mut self,
         job_id: Uuid,
-        addr: SocketAddr,
-        weight: HashMap<String, Tensor>,
-        response: oneshot::Sender<Result<(), anyhow::Error>>,
+        worker_id: String,
+        result: Result<FitResult, StateError>,
+        response: oneshot::Sender<Result<(), StateError>>,
     ) {
         if let Some(job) = self.jobs.get_mut(&job_id) {
-            job.set_result(addr, weight, response);
-        } else if response
-            .send(Err(anyhow::anyhow!("job {job_id} not found")))
-            .is_err()
-        {
+            job.set_result(&worker_id, result, response);
+        } else if response.send(Err(StateError::JobNotFound(job_id))).is_err() {
+            warn!("failed to set response");
+        }
+    }
+
+    pub fn complete_round(
+        &mut self,
+        job_id: Uuid,
+        metrics: RoundMetrics,
+        weights: HashMap<String, Tensor>,
+        response: oneshot::Sender<Result<(), StateError>>,
+    ) {
+        let result = if let Some(job) = self.jobs.get_mut(&job_id) {
+            job.complete_round(metrics.clone(), weights);
+
+            // Sending only fails if nobody is watching, which is fine
+            let _ = self.metrics.send(metrics);
+
+            Ok(())
+        } else {
+            Err(StateError::JobNotFound(job_id))
+        };
+
+        if response.send(result).is_err() {
+            warn!("failed to set response");
+        }
+    }
+
+    pub fn finish_job(
+        &mut self,
+        job_id: Uuid,
+        status: JobStatus,
+        response: oneshot::Sender<Result<(), StateError>>,
+    ) {
+        let result = self
+            .jobs
+            .get_mut(&job_id)
+            .map(|job| job.finish(status))
+            .ok_or_else(|| StateError::JobNotFound(job_id));
+
+        if response.send(result).is_err() {
+            warn!("failed to set response");
+        }
+    }
+
+    pub fn job_status(
+        &self,
+        job_id: Uuid,
+        response: oneshot::Sender<Result<JobStatus, StateError>>,
+    ) {
+        let result = self
+            .jobs
+            .get(&job_id)
+            .map(|job| job.status())
+            .ok_or_else(|| StateError::JobNotFound(job_id));
+
+        if response.send(result).is_err() {
+            warn!("failed to set response");
+        }
+    }
+
+    pub fn get_metrics(
+        &self,
+        job_id: Uuid,
+        response: oneshot::Sender<Result<Vec<RoundMetrics>, StateError>>,
+    ) {
+        let result = self
+            .jobs
+            .get(&job_id)
+            .map(|job| job.metrics().to_vec())
+            .ok_or_else(|| StateError::JobNotFound(job_id));
+
+        if response.send(result).is_err() {
+            warn!("failed to set response");
+        }
+    }
+
+    pub fn watch_metrics(
+        &self,
+        response: oneshot::Sender<Result<broadcast::Receiver<RoundMetrics>, StateError>>,
+    ) {
+        if response.send(Ok(self.metrics.subscribe())).is_err() {
+            warn!("failed to set response");
+        }
+    }
+
+    /// Notify workers of the shutdown, interrupt running jobs and close all
+    /// streams, so that the server can shut down gracefully.
+    pub fn shutdown(&mut self, response: oneshot::Sender<Result<Vec<Checkpoint>, StateError>>) {
+        let message = CoordinatorMessage {
+            message: Some(coordinator_message::Message::Shutdown(Shutdown {})),
+        };
+
+        // Dropping workers ends their subscriptions after the notice
+        for worker in self.workers.drain(..) {
+            worker.notify(message.clone());
+        }
+
+        let checkpoints = self
+            .jobs
+            .values_mut()
+            .filter_map(|job| {
+                if job.status() == JobStatus::Running {
+                    info!(job_id = %job.id(), "interrupting job");
+                }
+                job.shut_down()
+            })
+            .collect();
+
+        // Replacing the sender ends the streams of metrics watchers
+        self.metrics = broadcast::channel(1).0;
+
+        if response.send(Ok(checkpoints)).is_err() {
             warn!("failed to set response");
         }
     }
@@ -1,31 +1,73 @@
-use std::{collections::HashMap, net::SocketAddr};
+use std::{collections::HashMap, sync::Arc};
 
-use candle_core::Tensor;
+use candle_core::{DType, Shape, Tensor};
 use futures_util::future::join_all;
+use protocol::{blob::CHUNK_SIZE, encoding};
 use tokio::sync::oneshot;
-use tonic::Status;
 use tracing::{debug, warn};
 use uuid::Uuid;
 
 use crate::{
-    candlefl::{coordinator_message, CoordinatorMessage, FitRequest, WeightsRequest},
-    state::worker::Worker,
+    candlefl::{
+        coordinator_message, ConfigValue, CoordinatorMessage, FitRequest, JobStatus, ModelSpec,
+        RejectedUpdate, RoundMetrics, WeightEncoding, WeightsRequest,
+    },
+    provenance::{self, fit_request_digest, Provenance, Signed},
+    state::{worker::Worker, Blobs, Checkpoint, FitResult, RoundResults, StateError},
 };
 
 pub struct Job {
     id: Uuid,
     workers: Vec<Worker>,
+    // Model architecture that workers train, announced with each request.
+    model: ModelSpec,
+    // Encoding of the weights exchanged with workers.
+    encoding: WeightEncoding,
     // Tasks wait for responses from workers.
     // They are removed once the response is received in 'set_result'.
-    tasks: HashMap<SocketAddr, Box<oneshot::Sender<HashMap<String, Tensor>>>>,
+    tasks: HashMap<String, Box<oneshot::Sender<Result<FitResult, StateError>>>>,
+    // Metrics of completed rounds, in order.
+    history: Vec<RoundMetrics>,
+    // Global weights after the last completed round, kept for checkpoints.
+    weights: Option<HashMap<String, Tensor>>,
+    // Names, shapes and dtypes of the global weights of the current round,
+    // which results of workers need to match.
+    schema: Option<Schema>,
+    // Current round, whose results are expected, 0 for the initial weights.
+    round: u64,
+    // Signs the weights sent to workers.
+    provenance: Arc<Provenance>,
+    // Global weights of the current round that are too large for a single
+    // message, which workers download from the blobs.
+    blob_id: Option<String>,
+    blobs: Arc<Blobs>,
+    status: JobStatus,
 }
 
+type Schema = HashMap<String, (Shape, DType)>;
+
 impl Job {
-    pub fn new(workers: Vec<Worker>) -> Self {
+    pub fn new(
+        workers: Vec<Worker>,
+        model: ModelSpec,
+        encoding: WeightEncoding,
+        provenance: Arc<Provenance>,
+        blobs: Arc<Blobs>,
+    ) -> Self {
         Job {
             id: Uuid::new_v4(),
             workers,
+            model,
+            encoding,
             tasks: HashMap::new(),
+            history: Vec::new(),
+            weights: None,
+            schema: None,
+            round: 0,
+            provenance,
+            blob_id: None,
+            blobs,
+            status: JobStatus::Running,
         }
     }
 
@@ -33,70 +75,162 @@ impl Job {
         self.id
     }
 
+    pub fn metrics(&self) -> &[RoundMetrics] {
+        &self.history
+    }
+
+    pub fn status(&self) -> JobStatus {
+        self.status
+    }
+
+    pub fn complete_round(&mut self, metrics: RoundMetrics, weights: HashMap<String, Tensor>) {
+        self.history.push(metrics);
+        self.weights = Some(weights);
+    }
+
+    /// Set the final status of a running job.
+    pub fn finish(&mut self, status: JobStatus) {
+        self.remove_blobs();
+
+        if self.status == JobStatus::Running {
+            self.status = status;
+        }
+    }
+
+    /// Stop the job because the coordinator shuts down.
+    ///
+    /// Pending tasks are dropped, so that the job doesn't wait for workers.
+    /// Returns a checkpoint of a running job with the weights of its last
+    /// completed round.
+    pub fn shut_down(&mut self) -> Option<Checkpoint> {
+        self.workers.clear();
+        self.tasks.clear();
+        self.remove_blobs();
+
+        if self.status != JobStatus::Running {
+            return None;
+        }
+        self.status = JobStatus::Interrupted;
+
+        self.weights.take().map(|weights| Checkpoint {
+            job_id: self.id,
+            round: self.history.len() as u64,
+            model: self.model.clone(),
+            weights,
+        })
+    }
+
+    /// Remove a worker from the job.
+    ///
+    /// Dropping its pending task completes it without a result.
+    pub fn remove_worker(&mut self, worker_id: &str) {
+        self.workers.retain(|worker| worker.id() != worker_id);
+        self.tasks.remove(worker_id);
+        self.blobs.close_task(self.id, worker_id);
+    }
+
     pub fn get_weights(
         &mut self,
-        response: oneshot::Sender<Result<HashMap<String, Tensor>, anyhow::Error>>,
+        response: oneshot::Sender<Result<HashMap<String, Tensor>, StateError>>,
     ) {
         let job_id = self.id;
 
+        let Some(worker) = self.workers.first().cloned() else {
+            if response.send(Err(StateError::NoWorkers)).is_err() {
+                warn!("failed to set response");
+            }
+            return;
+        };
+
         let message = CoordinatorMessage {
             message: Some(coordinator_message::Message::WeightsRequest(
                 WeightsRequest {
                     job_id: job_id.into(),
+                    model: Some(self.model.clone()),
+                    weight_encoding: self.encoding.into(),
                 },
             )),
         };
 
-        let _task = self
-            .workers
-            .first()
-            .map(|worker| {
-                let worker = worker.clone();
-                let message = message.clone();
+        let (sender, receiver) = oneshot::channel();
+        self.tasks.insert(worker.id().to_string(), Box::new(sender));
+        self.blobs.open_task(job_id, worker.id());
 
-                let (sender, receiver) = oneshot::channel();
-                self.tasks.insert(worker.addr(), Box::new(sender));
-
-                tokio::spawn(async move {
-                    debug!(
-                        job_id = %job_id,
-                        addr = %worker.addr(),
-                        "sending WeightsRequest"
-                    );
+        tokio::spawn(async move {
+            debug!(
+                job_id = %job_id,
+                worker_id = worker.id(),
+                "sending WeightsRequest"
+            );
 
-                    if let Err(e) = worker
-                        .sender()
-                        .send(Result::<_, Status>::Ok(message.clone()))
-                        .await
-                    {
-                        warn!(
-                            job_id = %job_id,
-                            addr = %worker.addr(),
-                            error = %e,
-                            "failed to send WeightsRequest"
-                        );
-                    }
+            worker.send(message).await;
 
-                    let weights = receiver.await.map_err(|e| anyhow::anyhow!(e));
-                    if response.send(weights).is_err() {
-                        warn!("failed to set response");
-                    }
-                })
-            })
-            .unwrap();
+            let weights = match receiver.await {
+                Ok(result) => result.map(|result| result.weights),
+                Err(_) => Err(StateError::WorkerLeft(worker.id().to_string())),
+            };
+            if response.send(weights).is_err() {
+                warn!("failed to set response");
+            }
+        });
     }
 
     pub fn fit_round(
         &mut self,
+        round: u64,
         weights: &HashMap<String, Tensor>,
-        response: oneshot::Sender<Result<Vec<HashMap<String, Tensor>>, anyhow::Error>>,
+        config: HashMap<String, ConfigValue>,
+        response: oneshot::Sender<Result<RoundResults, StateError>>,
     ) {
         let job_id = self.id;
 
+        self.round = round;
+        self.schema = Some(
+            weights
+                .iter()
+                .map(|(name, tensor)| (name.clone(), (tensor.shape().clone(), tensor.dtype())))
+                .collect(),
+        );
+
+        let weights = match encoding::encode(weights, self.encoding) {
+            Ok(weights) => weights,
+            Err(e) => {
+                if response.send(Err(e.into())).is_err() {
+                    warn!("failed to set response");
+                }
+                return;
+            }
+        };
+
+        let signature = self.provenance.sign(&Signed {
+            context: provenance::FIT_REQUEST,
+            job_id: &job_id.to_string(),
+            round,
+            encoding: self.encoding,
+            weights: &weights,
+            fields_digest: fit_request_digest(&config, Some(&self.model)),
+        });
+
+        // Weights that are too large for a single message are downloaded separately
+        self.unpublish_weights();
+        let (weights, weights_blob_id) = if weights.len() > CHUNK_SIZE {
+            let blob_id = self.blobs.publish(job_id, weights);
+            self.blob_id = Some(blob_id.clone());
+            (Vec::new(), blob_id)
+        } else {
+            (weights, String::new())
+        };
+
         let message = CoordinatorMessage {
             message: Some(coordinator_message::Message::FitRequest(FitRequest {
                 job_id: job_id.into(),
-                weights: serialize(weights).unwrap(),
+                weights,
+                config,
+                model: Some(self.model.clone()),
+                round,
+                signature,
+                weights_blob_id,
+                weight_encoding: self.encoding.into(),
             })),
         };
 
@@ -108,49 +242,54 @@ impl Job {
                 let message = message.clone();
 
                 let (sender, receiver) = oneshot::channel();
-                self.tasks.insert(worker.addr(), Box::new(sender));
+                self.tasks.insert(worker.id().to_string(), Box::new(sender));
+                self.blobs.open_task(job_id, worker.id());
+                self.blobs.open_task(job_id, worker.id());
 
                 tokio::spawn(async move {
                     debug!(
                         job_id = %job_id,
-                        addr = %worker.addr(),
+                        worker_id = worker.id(),
                         "sending FitRequest"
                     );
 
-                    if let Err(e) = worker
-                        .sender()
-                        .send(Result::<_, Status>::Ok(message.clone()))
-                        .await
-                    {
-                        warn!(
-                            job_id = %job_id,
-                            addr = %worker.addr(),
-                            error = %e,
-                            "failed to send FitRequest"
-                        );
-                    }
+                    worker.send(message).await;
 
-                    receiver.await
+                    match receiver.await {
+                        Ok(result) => result,
+                        Err(_) => Err(StateError::WorkerLeft(worker.id().to_string())),
+                    }
                 })
             })
             .collect::<Vec<_>>();
 
         tokio::spawn(async move {
-            let results = join_all(
-                tasks
-                    .into_iter()
-                    .map(|task| async move {
-                        match task.await {
-                            Ok(Ok(weights)) => Ok(weights),
-                            Ok(Err(e)) => Err(anyhow::anyhow!(e)),
-                            Err(e) => Err(anyhow::anyhow!(e)),
-                        }
-                    })
-                    .collect::<Vec<_>>(),
-            )
-            .await
-            .into_iter()
-            .collect::<Result<Vec<_>, anyhow::Error>>();
+            let mut results = Vec::new();
+            let mut rejected = Vec::new();
+            let mut error = None;
+
+            for task in join_all(tasks).await {
+                match task {
+                    Ok(Ok(result)) => results.push(result),
+                    // Rejected results are recorded in the metrics of the round
+                    Ok(Err(StateError::InvalidUpdate { worker_id, reason })) => {
+                        rejected.push(RejectedUpdate { worker_id, reason });
+                    }
+                    // Workers that failed or left are excluded from the round
+                    Ok(Err(e)) => {
+                        warn!(job_id = %job_id, error = %e, "excluding worker from round");
+                    }
+                    Err(e) => error = Some(StateError::from(e)),
+                }
+            }
+
+            let results = match error {
+                Some(e) => Err(e),
+                None if results.is_empty() && rejected.is_empty() => {
+                    Err(StateError::NoResults(round))
+                }
+                None => Ok(RoundResults { results, rejected }),
+            };
 
             if response.send(results).is_err() {
                 warn!("failed to set response");
@@ -158,32 +297,196 @@ impl Job {
         });
     }
 
+    /// Complete the pending task of a worker with its result or failure.
+    ///
+    /// Results of another round or whose weights don't match the global weights
+    /// of the round are rejected, failing the task.
     pub fn set_result(
         &mut self,
-        addr: SocketAddr,
-        weights: HashMap<String, Tensor>,
-        response: oneshot::Sender<Result<(), anyhow::Error>>,
+        worker_id: &str,
+        result: Result<FitResult, StateError>,
+        response: oneshot::Sender<Result<(), StateError>>,
     ) {
-        if let Some(sender) = self.tasks.remove(&addr) {
-            if response
-                .send(
-                    sender
-                        .send(weights)
-                        .map_err(|_| anyhow::anyhow!("failed to set result for {addr}")),
-                )
-                .is_err()
-            {
-                warn!("failed to set response");
+        let job_id = self.id;
+        let unexpected = || StateError::UnexpectedResult {
+            job_id,
+            worker_id: worker_id.to_string(),
+        };
+        let invalid = |reason: &str| StateError::InvalidUpdate {
+            worker_id: worker_id.to_string(),
+            reason: reason.to_string(),
+        };
+
+        let (result, rejection) = match result {
+            Ok(result) => match self.check(&result) {
+                Ok(()) => (Ok(result), None),
+                Err(reason) => (Err(invalid(&reason)), Some(invalid(&reason))),
+            },
+            // Late errors of a task of a previous round don't fail the current task
+            Err(StateError::TaskFailed { round, .. }) if round != self.round => {
+                if response.send(Err(unexpected())).is_err() {
+                    warn!("failed to set response");
+                }
+                return;
             }
-        } else if response
-            .send(Err(anyhow::anyhow!("completer not found for {addr}")))
-            .is_err()
-        {
+            result => (result, None),
+        };
+
+        self.blobs.close_task(job_id, worker_id);
+        let result = match self.tasks.remove(worker_id) {
+            Some(sender) => sender.send(result).map_err(|_| unexpected()),
+            None => Err(unexpected()),
+        };
+
+        // Report a rejected result to the worker, even though its task is complete
+        let result = match rejection {
+            Some(rejection) if result.is_ok() => Err(rejection),
+            _ => result,
+        };
+
+        if response.send(result).is_err() {
             warn!("failed to set response");
         }
     }
+
+    /// Remove the global weights of the previous round from the blobs.
+    fn unpublish_weights(&mut self) {
+        if let Some(blob_id) = self.blob_id.take() {
+            self.blobs.unpublish(self.id, &blob_id);
+        }
+    }
+
+    /// Remove all blobs of the job once it ended, so that workers can't
+    /// transfer its weights anymore.
+    fn remove_blobs(&mut self) {
+        self.blob_id = None;
+        self.blobs.remove_job(self.id);
+    }
+
+    /// Check that a result belongs to the current round and matches its schema.
+    fn check(&self, result: &FitResult) -> Result<(), String> {
+        if result.round != self.round {
+            return Err(format!(
+                "result of round {}, expected {}",
+                result.round, self.round
+            ));
+        }
+
+        match &self.schema {
+            Some(schema) => validate(schema, &result.weights),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Check that weights have the names, shapes and dtypes of a schema.
+fn validate(schema: &Schema, weights: &HashMap<String, Tensor>) -> Result<(), String> {
+    if let Some(name) = weights.keys().find(|name| !schema.contains_key(*name)) {
+        return Err(format!("unexpected tensor {name}"));
+    }
+
+    for (name, (shape, dtype)) in schema {
+        let tensor = weights
+            .get(name)
+            .ok_or_else(|| format!("missing tensor {name}"))?;
+
+        if tensor.shape() != shape {
+            return Err(format!(
+                "tensor {name} has shape {:?}, expected {:?}",
+                tensor.shape(),
+                shape
+            ));
+        }
+        if tensor.dtype() != *dtype {
+            return Err(format!(
+                "tensor {name} has dtype {:?}, expected {:?}",
+                tensor.dtype(),
+                dtype
+            ));
+        }
+    }
+
+    Ok(())
 }
 
-fn serialize(weights: &HashMap<String, Tensor>) -> Result<Vec<u8>, safetensors::SafeTensorError> {
-    safetensors::serialize(weights, &None)
+#[cfg(test)]
+mod tests {
+    use candle_core::Device;
+
+    use super::*;
+
+    fn weights(tensors: &[(&str, &[usize], DType)]) -> HashMap<String, Tensor> {
+        tensors
+            .iter()
+            .map(|(name, shape, dtype)| {
+                let tensor = Tensor::zeros(*shape, *dtype, &Device::Cpu).unwrap();
+                (name.to_string(), tensor)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_validate() {
+        let schema = weights(&[("w", &[2, 3], DType::F32), ("b", &[3], DType::F32)])
+            .into_iter()
+            .map(|(name, tensor)| (name, (tensor.shape().clone(), tensor.dtype())))
+            .collect::<Schema>();
+
+        let valid = weights(&[("w", &[2, 3], DType::F32), ("b", &[3], DType::F32)]);
+        assert!(validate(&schema, &valid).is_ok());
+
+        let missing = weights(&[("w", &[2, 3], DType::F32)]);
+        assert_eq!(validate(&schema, &missing), Err("missing tensor b".into()));
+
+        let extra = weights(&[
+            ("w", &[2, 3], DType::F32),
+            ("b", &[3], DType::F32),
+            ("c", &[1], DType::F32),
+        ]);
+        assert_eq!(validate(&schema, &extra), Err("unexpected tensor c".into()));
+
+        let shape = weights(&[("w", &[3, 2], DType::F32), ("b", &[3], DType::F32)]);
+        assert!(validate(&schema, &shape).is_err());
+
+        let dtype = weights(&[("w", &[2, 3], DType::F32), ("b", &[3], DType::F64)]);
+        assert!(validate(&schema, &dtype).is_err());
+    }
+
+    #[test]
+    fn test_late_task_error() {
+        let mut job = Job::new(
+            Vec::new(),
+            ModelSpec::default(),
+            WeightEncoding::None,
+            Arc::default(),
+            Arc::new(Blobs::new(1024)),
+        );
+        job.round = 2;
+        let (sender, mut task) = oneshot::channel();
+        job.tasks.insert("a".to_string(), Box::new(sender));
+
+        let set_error = |job: &mut Job, round| {
+            let (response, mut receiver) = oneshot::channel();
+            let error = StateError::TaskFailed {
+                worker_id: "a".to_string(),
+                round,
+                message: "failed".to_string(),
+            };
+            job.set_result("a", Err(error), response);
+            receiver.try_recv().unwrap()
+        };
+
+        // An error of the task of a previous round doesn't fail the current task
+        assert!(matches!(
+            set_error(&mut job, 1),
+            Err(StateError::UnexpectedResult { .. })
+        ));
+        assert!(task.try_recv().is_err());
+
+        assert!(set_error(&mut job, 2).is_ok());
+        assert!(matches!(
+            task.try_recv().unwrap(),
+            Err(StateError::TaskFailed { round: 2, .. })
+        ));
+    }
 }
@@ -1,16 +1,59 @@
-use std::{collections::HashMap, net::SocketAddr};
+use std::{collections::HashMap, sync::Arc};
 
 use candle_core::Tensor;
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{broadcast, mpsc, oneshot};
 use tonic::Status;
 use uuid::Uuid;
 
-use crate::{candlefl::CoordinatorMessage, state::inmemory_state::InMemoryState};
+use crate::{
+    candlefl::{
+        ConfigValue, CoordinatorMessage, JobStatus, ModelSpec, RejectedUpdate, RoundMetrics,
+        WeightEncoding,
+    },
+    provenance::Provenance,
+    state::inmemory_state::InMemoryState,
+    DEFAULT_MAX_BLOB_SIZE,
+};
+
+pub use blobs::Blobs;
+pub use error::StateError;
 
+mod blobs;
+mod error;
 mod inmemory_state;
 mod job;
 mod worker;
 
+/// Result of a training task on a single worker.
+#[derive(Debug)]
+pub struct FitResult {
+    pub worker_id: String,
+    // Round of the task, 0 for initial weights.
+    pub round: u64,
+    pub weights: HashMap<String, Tensor>,
+    pub metrics: HashMap<String, f64>,
+    // SHA-256 digest of the weights as published, and the worker's signature.
+    pub weights_digest: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+/// Results of a round, together with the updates that were rejected before
+/// they reached the strategy, e.g. because of an invalid signature.
+#[derive(Debug)]
+pub struct RoundResults {
+    pub results: Vec<FitResult>,
+    pub rejected: Vec<RejectedUpdate>,
+}
+
+/// Global weights of an interrupted job after its last completed round.
+#[derive(Debug)]
+pub struct Checkpoint {
+    pub job_id: Uuid,
+    pub round: u64,
+    pub model: ModelSpec,
+    pub weights: HashMap<String, Tensor>,
+}
+
 #[derive(Clone)]
 pub struct Job<'a> {
     job_id: Uuid,
@@ -26,7 +69,7 @@ impl<'a> Job<'a> {
     ///
     /// The initial weights can be used to ensure that each worker
     /// starts training with the same weights.
-    pub async fn get_weights(&self) -> Result<HashMap<String, Tensor>, anyhow::Error> {
+    pub async fn get_weights(&self) -> Result<HashMap<String, Tensor>, StateError> {
         let (response, receiver) = oneshot::channel();
         self.state
             .sender
@@ -40,47 +83,125 @@ impl<'a> Job<'a> {
 
     /// Perform a single round of training on all workers associated with this job.
     ///
-    /// Each worker will use the provided weights to train a model and return
-    /// the updated weights. The list of updated weights is then returned.
+    /// Each worker will use the provided weights and training configuration to
+    /// train a model and return the updated weights and training metrics.
+    /// The list of results is then returned, without workers that unregistered
+    /// during the round. Results that were rejected are returned separately.
     pub async fn fit_round(
         &self,
+        round: u64,
         weights: HashMap<String, Tensor>,
-    ) -> Result<Vec<HashMap<String, Tensor>>, anyhow::Error> {
+        config: HashMap<String, ConfigValue>,
+    ) -> Result<RoundResults, StateError> {
         let (response, receiver) = oneshot::channel();
         self.state
             .sender
             .send(Command::FitRound {
                 job_id: self.job_id,
+                round,
+                weights,
+                config,
+                response,
+            })
+            .await?;
+        receiver.await?
+    }
+
+    /// Record the metrics and global weights of a completed round and publish
+    /// the metrics to watchers.
+    ///
+    /// The weights of the last completed round are checkpointed if the job is
+    /// interrupted.
+    pub async fn complete_round(
+        &self,
+        metrics: RoundMetrics,
+        weights: HashMap<String, Tensor>,
+    ) -> Result<(), StateError> {
+        let (response, receiver) = oneshot::channel();
+        self.state
+            .sender
+            .send(Command::CompleteRound {
+                job_id: self.job_id,
+                metrics,
                 weights,
                 response,
             })
             .await?;
         receiver.await?
     }
+
+    /// Set the final status of the job, unless it was interrupted.
+    pub async fn finish(&self, status: JobStatus) -> Result<(), StateError> {
+        let (response, receiver) = oneshot::channel();
+        self.state
+            .sender
+            .send(Command::FinishJob {
+                job_id: self.job_id,
+                status,
+                response,
+            })
+            .await?;
+        receiver.await?
+    }
 }
 
 #[derive(Clone)]
 pub struct State {
     sender: mpsc::Sender<Command>,
+    provenance: Arc<Provenance>,
+    blobs: Arc<Blobs>,
 }
 
 impl State {
     pub fn new() -> Self {
+        Self::with_provenance(Provenance::default(), DEFAULT_MAX_BLOB_SIZE)
+    }
+
+    /// Create a state whose jobs sign their requests with `provenance`.
+    ///
+    /// Workers can upload blobs of up to `max_blob_size` bytes.
+    pub fn with_provenance(provenance: Provenance, max_blob_size: u64) -> Self {
+        let provenance = Arc::new(provenance);
+        let blobs = Arc::new(Blobs::new(max_blob_size));
+
         let (sender, receiver) = mpsc::channel(32);
-        tokio::spawn(handler(receiver));
+        tokio::spawn(handler(receiver, provenance.clone(), blobs.clone()));
+
+        State {
+            sender,
+            provenance,
+            blobs,
+        }
+    }
+
+    /// Signs the weights sent by the coordinator and verifies the results of workers.
+    pub fn provenance(&self) -> &Provenance {
+        &self.provenance
+    }
+
+    /// Weights that are transferred in chunks, as they are too large for a
+    /// single message.
+    pub fn blobs(&self) -> &Blobs {
+        &self.blobs
+    }
 
-        State { sender }
+    /// Number of connected workers.
+    pub async fn num_workers(&self) -> Result<usize, StateError> {
+        let (response, receiver) = oneshot::channel();
+        self.sender.send(Command::NumWorkers { response }).await?;
+        receiver.await?
     }
 
+    /// Add a worker, or replace the subscription of a worker with the same ID.
     pub async fn add_worker(
         &self,
-        addr: SocketAddr,
+        id: String,
         sender: mpsc::Sender<Result<CoordinatorMessage, Status>>,
-    ) -> Result<(), anyhow::Error> {
+    ) -> Result<(), StateError> {
         let (response, receiver) = oneshot::channel();
         self.sender
             .send(Command::AddWorker {
-                addr,
+                id,
                 sender,
                 response,
             })
@@ -88,9 +209,32 @@ impl State {
         receiver.await?
     }
 
-    pub async fn add_job(&self) -> Result<Job, anyhow::Error> {
+    /// Remove a worker that unregistered, together with its pending tasks.
+    ///
+    /// Running jobs no longer wait for results of the worker.
+    pub async fn remove_worker(&self, id: String) -> Result<(), StateError> {
+        let (response, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::RemoveWorker { id, response })
+            .await?;
+        receiver.await?
+    }
+
+    /// Add a job that trains a model of the given architecture, exchanging
+    /// weights with workers in the given encoding.
+    pub async fn add_job(
+        &self,
+        model: ModelSpec,
+        encoding: WeightEncoding,
+    ) -> Result<Job, StateError> {
         let (response, receiver) = oneshot::channel();
-        self.sender.send(Command::AddJob { response }).await?;
+        self.sender
+            .send(Command::AddJob {
+                model,
+                encoding,
+                response,
+            })
+            .await?;
 
         let job_id = receiver.await??;
 
@@ -103,30 +247,126 @@ impl State {
     pub async fn set_fit_result(
         &self,
         job_id: Uuid,
-        addr: SocketAddr,
-        weights: HashMap<String, Tensor>,
-    ) -> Result<(), anyhow::Error> {
+        worker_id: String,
+        result: FitResult,
+    ) -> Result<(), StateError> {
         let (response, receiver) = oneshot::channel();
         self.sender
             .send(Command::SetFitResult {
                 job_id,
-                addr,
-                weights,
+                worker_id,
+                result: Ok(result),
                 response,
             })
             .await?;
         receiver.await?
     }
+
+    /// Reject the result of a worker, e.g. because of an invalid signature.
+    ///
+    /// The worker is excluded from the round of the task, which records the
+    /// rejection in its metrics.
+    pub async fn reject_result(
+        &self,
+        job_id: Uuid,
+        worker_id: String,
+        reason: String,
+    ) -> Result<(), StateError> {
+        let (response, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::SetFitResult {
+                job_id,
+                worker_id: worker_id.clone(),
+                result: Err(StateError::InvalidUpdate { worker_id, reason }),
+                response,
+            })
+            .await?;
+        receiver.await?
+    }
+
+    /// Fail the pending task of a worker that reported an error in a round.
+    ///
+    /// The worker is excluded from the round of the task.
+    pub async fn fail_task(
+        &self,
+        job_id: Uuid,
+        worker_id: String,
+        round: u64,
+        message: String,
+    ) -> Result<(), StateError> {
+        let (response, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::SetFitResult {
+                job_id,
+                worker_id: worker_id.clone(),
+                result: Err(StateError::TaskFailed {
+                    worker_id,
+                    round,
+                    message,
+                }),
+                response,
+            })
+            .await?;
+        receiver.await?
+    }
+
+    pub async fn job_status(&self, job_id: Uuid) -> Result<JobStatus, StateError> {
+        let (response, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::GetJobStatus { job_id, response })
+            .await?;
+        receiver.await?
+    }
+
+    /// Get the metrics of all completed rounds of a job.
+    pub async fn get_metrics(&self, job_id: Uuid) -> Result<Vec<RoundMetrics>, StateError> {
+        let (response, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::GetMetrics { job_id, response })
+            .await?;
+        receiver.await?
+    }
+
+    /// Subscribe to the metrics of rounds of all jobs as they complete.
+    pub async fn watch_metrics(&self) -> Result<broadcast::Receiver<RoundMetrics>, StateError> {
+        let (response, receiver) = oneshot::channel();
+        self.sender.send(Command::WatchMetrics { response }).await?;
+        receiver.await?
+    }
+
+    /// Notify workers that the coordinator shuts down and interrupt running jobs.
+    ///
+    /// Returns checkpoints of interrupted jobs that completed a round.
+    pub async fn shutdown(&self) -> Result<Vec<Checkpoint>, StateError> {
+        let (response, receiver) = oneshot::channel();
+        self.sender.send(Command::Shutdown { response }).await?;
+        receiver.await?
+    }
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[derive(Debug)]
 enum Command {
+    NumWorkers {
+        response: CommandResponse<usize>,
+    },
     AddWorker {
-        addr: SocketAddr,
+        id: String,
         sender: mpsc::Sender<Result<CoordinatorMessage, Status>>,
         response: CommandResponse<()>,
     },
+    RemoveWorker {
+        id: String,
+        response: CommandResponse<()>,
+    },
     AddJob {
+        model: ModelSpec,
+        encoding: WeightEncoding,
         response: CommandResponse<Uuid>,
     },
     GetWeights {
@@ -135,53 +375,123 @@ enum Command {
     },
     FitRound {
         job_id: Uuid,
+        round: u64,
         weights: HashMap<String, Tensor>,
-        response: CommandResponse<Vec<HashMap<String, Tensor>>>,
+        config: HashMap<String, ConfigValue>,
+        response: CommandResponse<RoundResults>,
     },
     SetFitResult {
         job_id: Uuid,
-        addr: SocketAddr,
+        worker_id: String,
+        result: Result<FitResult, StateError>,
+        response: CommandResponse<()>,
+    },
+    CompleteRound {
+        job_id: Uuid,
+        metrics: RoundMetrics,
         weights: HashMap<String, Tensor>,
         response: CommandResponse<()>,
     },
+    FinishJob {
+        job_id: Uuid,
+        status: JobStatus,
+        response: CommandResponse<()>,
+    },
+    GetJobStatus {
+        job_id: Uuid,
+        response: CommandResponse<JobStatus>,
+    },
+    GetMetrics {
+        job_id: Uuid,
+        response: CommandResponse<Vec<RoundMetrics>>,
+    },
+    WatchMetrics {
+        response: CommandResponse<broadcast::Receiver<RoundMetrics>>,
+    },
+    Shutdown {
+        response: CommandResponse<Vec<Checkpoint>>,
+    },
 }
 
-type CommandResponse<T> = oneshot::Sender<Result<T, anyhow::Error>>;
+type CommandResponse<T> = oneshot::Sender<Result<T, StateError>>;
 
-async fn handler(mut receiver: mpsc::Receiver<Command>) {
-    let mut state = InMemoryState::new();
+async fn handler(
+    mut receiver: mpsc::Receiver<Command>,
+    provenance: Arc<Provenance>,
+    blobs: Arc<Blobs>,
+) {
+    let mut state = InMemoryState::new(provenance, blobs);
 
     // To unblock the loop, functions return immediately and use
     // response handlers to set the result of the operation.
     while let Some(command) = receiver.recv().await {
         match command {
+            Command::NumWorkers { response } => {
+                state.num_workers(response);
+            }
             Command::AddWorker {
-                addr,
+                id,
                 sender,
                 response,
             } => {
-                state.add_worker(addr, sender, response);
+                state.add_worker(id, sender, response);
+            }
+            Command::RemoveWorker { id, response } => {
+                state.remove_worker(&id, response);
             }
-            Command::AddJob { response } => {
-                state.add_job(response);
+            Command::AddJob {
+                model,
+                encoding,
+                response,
+            } => {
+                state.add_job(model, encoding, response);
             }
             Command::GetWeights { job_id, response } => {
                 state.get_weights(job_id, response);
             }
             Command::FitRound {
                 job_id,
+                round,
                 weights,
+                config,
                 response,
             } => {
-                state.fit_round(job_id, &weights, response);
+                state.fit_round(job_id, round, &weights, config, response);
             }
             Command::SetFitResult {
                 job_id,
-                addr,
+                worker_id,
+                result,
+                response,
+            } => {
+                state.set_fit_result(job_id, worker_id, result, response);
+            }
+            Command::CompleteRound {
+                job_id,
+                metrics,
                 weights,
                 response,
             } => {
-                state.set_fit_result(job_id, addr, weights, response);
+                state.complete_round(job_id, metrics, weights, response);
+            }
+            Command::FinishJob {
+                job_id,
+                status,
+                response,
+            } => {
+                state.finish_job(job_id, status, response);
+            }
+            Command::GetJobStatus { job_id, response } => {
+                state.job_status(job_id, response);
+            }
+            Command::GetMetrics { job_id, response } => {
+                state.get_metrics(job_id, response);
+            }
+            Command::WatchMetrics { response } => {
+                state.watch_metrics(response);
+            }
+            Command::Shutdown { response } => {
+                state.shutdown(response);
             }
         }
     }
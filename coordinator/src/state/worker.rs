@@ -1,26 +1,81 @@
-use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
 
-use tokio::sync::mpsc;
+use tokio::sync::mpsc::{self, error::SendError};
 use tonic::Status;
+use tracing::warn;
 
 use crate::candlefl::CoordinatorMessage;
 
+type MessageSender = mpsc::Sender<Result<CoordinatorMessage, Status>>;
+
+/// A subscribed worker, identified by the ID it subscribes with.
+///
+/// Clones share the connection, so that jobs reach a worker that resubscribed.
 #[derive(Clone)]
 pub struct Worker {
-    addr: SocketAddr,
-    sender: mpsc::Sender<Result<CoordinatorMessage, Status>>,
+    id: String,
+    connection: Arc<Mutex<Connection>>,
+}
+
+struct Connection {
+    sender: MessageSender,
+    // Messages that couldn't be sent while the worker was disconnected.
+    pending: Vec<CoordinatorMessage>,
 }
 
 impl Worker {
-    pub fn new(addr: SocketAddr, sender: mpsc::Sender<Result<CoordinatorMessage, Status>>) -> Self {
-        Worker { addr, sender }
+    pub fn new(id: String, sender: MessageSender) -> Self {
+        Worker {
+            id,
+            connection: Arc::new(Mutex::new(Connection {
+                sender,
+                pending: Vec::new(),
+            })),
+        }
     }
 
-    pub fn addr(&self) -> SocketAddr {
-        self.addr
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Replace the subscription of a worker that resubscribed.
+    ///
+    /// Messages that were sent while the worker was disconnected are delivered
+    /// with the new subscription.
+    pub fn resubscribe(&self, sender: MessageSender) {
+        let mut connection = self.connection.lock().unwrap();
+
+        for message in connection.pending.drain(..) {
+            if let Err(e) = sender.try_send(Ok(message)) {
+                warn!(id = self.id, error = %e, "failed to deliver pending message");
+            }
+        }
+        connection.sender = sender;
     }
 
-    pub fn sender(&self) -> &mpsc::Sender<Result<CoordinatorMessage, Status>> {
-        &self.sender
+    /// Send a message to the worker if it is connected and can receive it
+    /// right away.
+    pub fn notify(&self, message: CoordinatorMessage) {
+        let connection = self.connection.lock().unwrap();
+
+        if let Err(e) = connection.sender.try_send(Ok(message)) {
+            warn!(id = self.id, error = %e, "failed to notify worker");
+        }
+    }
+
+    /// Send a message to the worker, or keep it until the worker resubscribes.
+    pub async fn send(&self, message: CoordinatorMessage) {
+        let sender = self.connection.lock().unwrap().sender.clone();
+
+        if let Err(SendError(Ok(message))) = sender.send(Ok(message)).await {
+            let mut connection = self.connection.lock().unwrap();
+
+            // The worker may have resubscribed in the meantime
+            if connection.sender.same_channel(&sender) {
+                connection.pending.push(message);
+            } else if let Err(e) = connection.sender.try_send(Ok(message)) {
+                warn!(id = self.id, error = %e, "failed to send message");
+            }
+        }
     }
 }
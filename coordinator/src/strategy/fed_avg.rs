@@ -1,40 +1,202 @@
 use std::collections::HashMap;
 
 use candle_core::Tensor;
-use tracing::info;
+use tokio::task;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
 
-use crate::state::State;
+use crate::{
+    candlefl::{
+        ConfigValue, Contribution, JobStatus, LearningRateSchedule, ModelSpec, RoundMetrics,
+        UpdateFilter, WeightEncoding,
+    },
+    state::{Job, RoundResults, State},
+    strategy::{fit_config, EvaluateFn},
+};
 
 /// [FederatedAveraging](https://arxiv.org/abs/1602.05629)
 pub struct FedAvg {
     state: State,
+    // Optional server-side evaluation of the global model after each round.
+    evaluate: Option<EvaluateFn>,
+    // Optional limit of the size of the serialized final model, in bytes.
+    max_model_size: Option<usize>,
 }
 
 impl FedAvg {
-    pub fn new(state: State) -> Self {
-        FedAvg { state }
+    pub fn new(state: State, evaluate: Option<EvaluateFn>) -> Self {
+        FedAvg {
+            state,
+            evaluate,
+            max_model_size: None,
+        }
+    }
+
+    /// Fail jobs before the first round if the serialized final model would
+    /// exceed `size` bytes, e.g. because it can't be sent in a single message.
+    pub fn with_max_model_size(mut self, size: usize) -> Self {
+        self.max_model_size = Some(size);
+        self
     }
 
     /// Fit model weights using federated averaging by training on data provided
     /// by connected workers.
-    pub async fn fit(&self, num_rounds: usize) -> Result<HashMap<String, Tensor>, anyhow::Error> {
-        let job = self.state.add_job().await?;
+    ///
+    /// Workers train a model of the given architecture with the provided
+    /// configuration, whose learning rate is adjusted per round if a schedule
+    /// is provided.
+    /// Updates that fail the sanity checks of the filter aren't aggregated.
+    /// Weights are exchanged with workers in the given encoding.
+    /// Returns the ID of the job together with the final weights.
+    pub async fn fit(
+        &self,
+        num_rounds: usize,
+        model: ModelSpec,
+        config: &HashMap<String, ConfigValue>,
+        schedule: Option<&LearningRateSchedule>,
+        update_filter: Option<&UpdateFilter>,
+        encoding: WeightEncoding,
+    ) -> Result<(Uuid, HashMap<String, Tensor>), anyhow::Error> {
+        info!(architecture = model.architecture, encoding = ?encoding, "adding job");
+
+        let job = self.state.add_job(model.clone(), encoding).await?;
 
         info!(job_id = %job.id(), "starting job");
 
+        let result = self
+            .fit_job(&job, &model, num_rounds, config, schedule, update_filter)
+            .await;
+
+        job.finish(match result {
+            Ok(_) => JobStatus::Completed,
+            Err(_) => JobStatus::Failed,
+        })
+        .await?;
+
+        let weights = result?;
+
+        info!(job_id = %job.id(), "finished job");
+
+        Ok((job.id(), weights))
+    }
+
+    async fn fit_job(
+        &self,
+        job: &Job<'_>,
+        model: &ModelSpec,
+        num_rounds: usize,
+        config: &HashMap<String, ConfigValue>,
+        schedule: Option<&LearningRateSchedule>,
+        update_filter: Option<&UpdateFilter>,
+    ) -> Result<HashMap<String, Tensor>, anyhow::Error> {
+        // Updates with NaN or Inf values are rejected even without a filter
+        let update_filter = update_filter.cloned().unwrap_or_default();
         let mut weights = job.get_weights().await?;
 
+        // Averaging keeps the names, shapes and dtypes of the initial weights
+        if let Some(limit) = self.max_model_size {
+            let size = safetensors::serialize(&weights, &None)?.len();
+            if size > limit {
+                anyhow::bail!("model of {size} bytes exceeds the limit of {limit} bytes");
+            }
+        }
+
         for round in 0..num_rounds {
             info!(job_id = %job.id(), "starting round {}", round + 1);
-            let local_weights = job.fit_round(weights.clone()).await?;
+            let config = fit_config(config, schedule, round, num_rounds)?;
+            let RoundResults {
+                results,
+                rejected: mut rejected_updates,
+            } = job
+                .fit_round(round as u64 + 1, weights.clone(), config)
+                .await?;
+
+            let (results, rejected) = update_filter.filter(&weights, results)?;
+            rejected_updates.extend(rejected);
+            for rejected in &rejected_updates {
+                warn!(
+                    job_id = %job.id(),
+                    worker_id = rejected.worker_id,
+                    reason = rejected.reason,
+                    "rejected update"
+                );
+            }
+            if results.is_empty() {
+                anyhow::bail!("all updates of round {} were rejected", round + 1);
+            }
+
+            // Record which worker contributed which update to the global model
+            let contributions = results
+                .iter()
+                .map(|result| Contribution {
+                    worker_id: result.worker_id.clone(),
+                    weights_digest: result.weights_digest.clone(),
+                    signature: result.signature.clone(),
+                })
+                .collect();
+
+            let (local_weights, local_metrics): (Vec<_>, Vec<_>) = results
+                .into_iter()
+                .map(|result| (result.weights, result.metrics))
+                .unzip();
 
             weights = average_weights(&local_weights)?;
-        }
 
-        info!(job_id = %job.id(), "finished job");
+            let evaluate_metrics = self.evaluate(job.id(), round + 1, model, &weights).await?;
+
+            job.complete_round(
+                RoundMetrics {
+                    job_id: job.id().into(),
+                    round: round as u64 + 1,
+                    num_workers: local_metrics.len() as u64,
+                    fit_metrics: aggregate_metrics(&local_metrics),
+                    evaluate_metrics: evaluate_metrics.unwrap_or_default(),
+                    rejected_updates,
+                    contributions,
+                },
+                weights.clone(),
+            )
+            .await?;
+        }
 
         Ok(weights)
     }
+
+    /// Evaluate the global model on the coordinator, if an evaluation function
+    /// is configured.
+    ///
+    /// A failing evaluation doesn't abort the job, as it doesn't affect training.
+    /// Models whose architecture the evaluation doesn't support are skipped.
+    async fn evaluate(
+        &self,
+        job_id: Uuid,
+        round: usize,
+        model: &ModelSpec,
+        weights: &HashMap<String, Tensor>,
+    ) -> Result<Option<HashMap<String, f64>>, anyhow::Error> {
+        let Some(evaluate) = self.evaluate.clone() else {
+            return Ok(None);
+        };
+
+        let model = model.clone();
+        let weights = weights.clone();
+
+        // Evaluation is a blocking operation, so we'll offload it
+        match task::spawn_blocking(move || evaluate(&model, &weights)).await? {
+            Ok(Some(metrics)) => {
+                info!(job_id = %job_id, round, metrics = ?metrics, "evaluated global model");
+                Ok(Some(metrics))
+            }
+            Ok(None) => {
+                debug!(job_id = %job_id, round, "evaluation doesn't support the architecture");
+                Ok(None)
+            }
+            Err(e) => {
+                warn!(job_id = %job_id, round, error = %e, "failed to evaluate global model");
+                Ok(None)
+            }
+        }
+    }
 }
 
 fn average_weights(
@@ -42,23 +204,41 @@ fn average_weights(
 ) -> Result<HashMap<String, Tensor>, candle_core::Error> {
     let num_tensors = tensors.len() as f64;
 
-    let result = tensors
-        .iter()
-        .fold(HashMap::new(), |result, tensor| {
-            tensor.iter().fold(result, |mut result, (name, tensor)| {
-                if let Some(existing) = result.get(name) {
-                    result.insert(name.to_string(), (existing + tensor).unwrap());
-                } else {
-                    result.insert(name.to_string(), tensor.clone());
-                }
-                result
-            })
-        })
-        .iter()
-        .map(|(name, tensor)| (name.to_string(), (num_tensors.recip() * tensor).unwrap()))
-        .collect();
+    let mut sums = HashMap::<String, Tensor>::new();
+    for weights in tensors {
+        for (name, tensor) in weights {
+            let sum = match sums.remove(name) {
+                Some(sum) => (sum + tensor)?,
+                None => tensor.clone(),
+            };
+            sums.insert(name.to_string(), sum);
+        }
+    }
 
-    Ok(result)
+    sums.into_iter()
+        .map(|(name, sum)| Ok((name, (num_tensors.recip() * sum)?)))
+        .collect()
+}
+
+/// Aggregate training metrics reported by workers.
+///
+/// Metrics are averaged, weighted by the number of examples each worker trained
+/// on. Counters are summed up and the wall time is the one of the slowest worker.
+fn aggregate_metrics(metrics: &[HashMap<String, f64>]) -> HashMap<String, f64> {
+    let weight = |metrics: &HashMap<String, f64>| *metrics.get("num_examples").unwrap_or(&1.0);
+    let total_weight: f64 = metrics.iter().map(weight).sum();
+
+    metrics.iter().fold(HashMap::new(), |mut result, metrics| {
+        for (name, value) in metrics {
+            let entry = result.entry(name.to_string()).or_insert(0.0);
+            match name.as_str() {
+                "num_examples" | "num_steps" => *entry += value,
+                "wall_time" => *entry = entry.max(*value),
+                _ => *entry += value * weight(metrics) / total_weight,
+            }
+        }
+        result
+    })
 }
 
 #[cfg(test)]
@@ -130,4 +310,27 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_aggregate_metrics() {
+        let metrics = vec![
+            HashMap::from([
+                ("loss".to_string(), 1.0),
+                ("num_examples".to_string(), 10.0),
+                ("wall_time".to_string(), 2.0),
+            ]),
+            HashMap::from([
+                ("loss".to_string(), 2.0),
+                ("num_examples".to_string(), 30.0),
+                ("wall_time".to_string(), 1.0),
+            ]),
+        ];
+
+        let result = aggregate_metrics(&metrics);
+
+        assert_eq!(result.len(), 3);
+        assert_eq!(result["loss"], 1.75);
+        assert_eq!(result["num_examples"], 40.0);
+        assert_eq!(result["wall_time"], 2.0);
+    }
 }
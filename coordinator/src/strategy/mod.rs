@@ -1,3 +1,25 @@
+use std::{collections::HashMap, sync::Arc};
+
+use candle_core::Tensor;
+
+use crate::candlefl::ModelSpec;
+
 pub use fed_avg::FedAvg;
+pub use schedule::fit_config;
 
 mod fed_avg;
+mod schedule;
+mod update_filter;
+
+/// Evaluates global model weights of the given model on data held by the
+/// coordinator and returns the resulting metrics, e.g. loss and accuracy.
+///
+/// Returns `None` if the evaluation doesn't support the architecture of the model.
+pub type EvaluateFn = Arc<
+    dyn Fn(
+            &ModelSpec,
+            &HashMap<String, Tensor>,
+        ) -> Result<Option<HashMap<String, f64>>, anyhow::Error>
+        + Send
+        + Sync,
+>;
@@ -0,0 +1,121 @@
+use std::{collections::HashMap, f64::consts::PI};
+
+use crate::candlefl::{
+    config_value, learning_rate_schedule::Kind, ConfigValue, LearningRateSchedule,
+};
+
+impl LearningRateSchedule {
+    /// Learning rate of a round, decayed from the initial `learning_rate`.
+    ///
+    /// Rounds are counted from 0.
+    pub fn learning_rate(&self, learning_rate: f64, round: usize, num_rounds: usize) -> f64 {
+        match self.kind() {
+            Kind::Constant => learning_rate,
+            Kind::Exponential => learning_rate * self.gamma.powi(round as i32),
+            Kind::Step => {
+                let steps = round / (self.step_size as usize).max(1);
+                learning_rate * self.gamma.powi(steps as i32)
+            }
+            Kind::Cosine => {
+                let progress = round as f64 / num_rounds.max(1) as f64;
+                self.min_learning_rate
+                    + (learning_rate - self.min_learning_rate) * (1.0 + (PI * progress).cos()) / 2.0
+            }
+        }
+    }
+}
+
+/// Training configuration of a round.
+///
+/// The "learning_rate" of the configuration is adjusted according to the
+/// schedule, if one is provided.
+pub fn fit_config(
+    config: &HashMap<String, ConfigValue>,
+    schedule: Option<&LearningRateSchedule>,
+    round: usize,
+    num_rounds: usize,
+) -> Result<HashMap<String, ConfigValue>, anyhow::Error> {
+    let mut config = config.clone();
+
+    if let Some(schedule) = schedule {
+        let learning_rate = match config.get("learning_rate").and_then(|v| v.value.as_ref()) {
+            Some(config_value::Value::Double(learning_rate)) => *learning_rate,
+            // Workers accept integer learning rates as well
+            Some(config_value::Value::Int(learning_rate)) => *learning_rate as f64,
+            _ => anyhow::bail!("learning rate schedule requires a numeric \"learning_rate\""),
+        };
+
+        config.insert(
+            "learning_rate".to_string(),
+            ConfigValue {
+                value: Some(config_value::Value::Double(schedule.learning_rate(
+                    learning_rate,
+                    round,
+                    num_rounds,
+                ))),
+            },
+        );
+    }
+
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_learning_rate_schedule() {
+        let schedule = LearningRateSchedule {
+            kind: Kind::Step.into(),
+            gamma: 0.5,
+            step_size: 2,
+            min_learning_rate: 0.0,
+        };
+
+        let learning_rates = (0..5)
+            .map(|round| schedule.learning_rate(0.1, round, 5))
+            .collect::<Vec<_>>();
+
+        assert_eq!(learning_rates, vec![0.1, 0.1, 0.05, 0.05, 0.025]);
+
+        let schedule = LearningRateSchedule {
+            kind: Kind::Cosine.into(),
+            gamma: 0.0,
+            step_size: 0,
+            min_learning_rate: 0.01,
+        };
+
+        assert_eq!(schedule.learning_rate(0.1, 0, 10), 0.1);
+        assert!((schedule.learning_rate(0.1, 5, 10) - 0.055).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_fit_config() {
+        let schedule = LearningRateSchedule {
+            kind: Kind::Exponential.into(),
+            gamma: 0.5,
+            step_size: 0,
+            min_learning_rate: 0.0,
+        };
+        let learning_rate = |value| {
+            let config = HashMap::from([(
+                "learning_rate".to_string(),
+                ConfigValue { value: Some(value) },
+            )]);
+            fit_config(&config, Some(&schedule), 1, 2)
+                .map(|config| config["learning_rate"].value.clone())
+        };
+
+        // Integer learning rates are scheduled like doubles
+        assert_eq!(
+            learning_rate(config_value::Value::Int(1)).unwrap(),
+            Some(config_value::Value::Double(0.5))
+        );
+        assert_eq!(
+            learning_rate(config_value::Value::Double(1.0)).unwrap(),
+            Some(config_value::Value::Double(0.5))
+        );
+        assert!(learning_rate(config_value::Value::String("fast".to_string())).is_err());
+    }
+}
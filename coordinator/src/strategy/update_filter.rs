@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Tensor};
+
+use crate::{
+    candlefl::{RejectedUpdate, UpdateFilter},
+    state::FitResult,
+};
+
+// The median of fewer updates is too easily dominated by a single worker
+const MIN_UPDATES_FOR_MEDIAN: usize = 3;
+
+impl UpdateFilter {
+    /// Split the results of a round into updates that are aggregated and
+    /// updates that are rejected.
+    ///
+    /// Updates are the differences between the weights of a worker and the
+    /// global weights of the round. They are computed one tensor at a time, so
+    /// that the updates of all workers are never held in memory at once.
+    pub fn filter(
+        &self,
+        global: &HashMap<String, Tensor>,
+        results: Vec<FitResult>,
+    ) -> Result<(Vec<FitResult>, Vec<RejectedUpdate>), candle_core::Error> {
+        let mut names: Vec<_> = global.keys().collect();
+        names.sort();
+
+        let mut accepted = Vec::with_capacity(results.len());
+        let mut norms = Vec::with_capacity(results.len());
+        let mut rejected = Vec::new();
+
+        for result in results {
+            let mut finite = true;
+            let mut squared_norm = 0.0;
+            for name in names.iter() {
+                let update = tensor_update(global, &result.weights, name)?;
+
+                // Subtracting a tensor from itself only leaves NaN or Inf values non-zero
+                finite &= (&update - &update)?.sum_all()?.to_scalar::<f64>()? == 0.0;
+                squared_norm += update.sqr()?.sum_all()?.to_scalar::<f64>()?;
+            }
+
+            match self.check_update(finite, squared_norm.sqrt()) {
+                Ok(norm) => {
+                    accepted.push(result);
+                    norms.push(norm);
+                }
+                Err(reason) => rejected.push(RejectedUpdate {
+                    worker_id: result.worker_id,
+                    reason,
+                }),
+            }
+        }
+
+        let Some(max_distance) = self.max_cosine_distance else {
+            return Ok((accepted, rejected));
+        };
+        if accepted.len() < MIN_UPDATES_FOR_MEDIAN {
+            return Ok((accepted, rejected));
+        }
+
+        // Only the updates of a single tensor are materialized for its median
+        let mut dots = vec![0.0; accepted.len()];
+        let mut median_squared_norm = 0.0;
+        for name in names {
+            let updates = accepted
+                .iter()
+                .map(|result| tensor_update(global, &result.weights, name)?.flatten_all())
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let median = median(&Tensor::stack(&updates, 0)?.to_vec2()?);
+            let median = Tensor::new(median, updates[0].device())?;
+            median_squared_norm += median.sqr()?.sum_all()?.to_scalar::<f64>()?;
+
+            for (dot, update) in dots.iter_mut().zip(&updates) {
+                *dot += (update * &median)?.sum_all()?.to_scalar::<f64>()?;
+            }
+        }
+        let median_norm = median_squared_norm.sqrt();
+
+        let mut results = Vec::with_capacity(accepted.len());
+        for ((result, norm), dot) in accepted.into_iter().zip(norms).zip(dots) {
+            let distance = cosine_distance(dot, norm, median_norm);
+
+            if distance > max_distance {
+                rejected.push(RejectedUpdate {
+                    worker_id: result.worker_id,
+                    reason: format!(
+                        "cosine distance {distance:.4} from the median update exceeds {max_distance}"
+                    ),
+                });
+            } else {
+                results.push(result);
+            }
+        }
+
+        Ok((results, rejected))
+    }
+
+    /// Check an update by whether its values are finite and its norm, which
+    /// is returned if the update is accepted.
+    fn check_update(&self, finite: bool, norm: f64) -> Result<f64, String> {
+        if !finite {
+            return Err("update contains NaN or Inf values".to_string());
+        }
+
+        match self.max_update_norm {
+            Some(max_norm) if norm > max_norm => {
+                Err(format!("update norm {norm:.4} exceeds {max_norm}"))
+            }
+            _ => Ok(norm),
+        }
+    }
+}
+
+/// Difference between the local and global weights of a tensor.
+fn tensor_update(
+    global: &HashMap<String, Tensor>,
+    local: &HashMap<String, Tensor>,
+    name: &str,
+) -> Result<Tensor, candle_core::Error> {
+    let tensor = local
+        .get(name)
+        .ok_or_else(|| candle_core::Error::Msg(format!("missing tensor {name}")))?;
+
+    tensor.to_dtype(DType::F64)? - global[name].to_dtype(DType::F64)?
+}
+
+/// Coordinate-wise median of updates of the same length.
+fn median(updates: &[Vec<f64>]) -> Vec<f64> {
+    let mut values = vec![0.0; updates.len()];
+
+    (0..updates[0].len())
+        .map(|i| {
+            for (value, update) in values.iter_mut().zip(updates) {
+                *value = update[i];
+            }
+            values.sort_by(f64::total_cmp);
+
+            // Both are the middle value with an odd number of updates
+            let (lower, upper) = (values[(values.len() - 1) / 2], values[values.len() / 2]);
+            (lower + upper) / 2.0
+        })
+        .collect()
+}
+
+/// Cosine distance in [0, 2] of updates with a dot product and norms, an
+/// update without direction is only close to another one without direction.
+fn cosine_distance(dot: f64, norm_a: f64, norm_b: f64) -> f64 {
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return if norm_a == norm_b { 0.0 } else { 1.0 };
+    }
+
+    1.0 - dot / (norm_a * norm_b)
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::Device;
+
+    use super::*;
+
+    fn result(worker_id: &str, values: &[f32]) -> FitResult {
+        FitResult {
+            worker_id: worker_id.to_string(),
+            round: 1,
+            weights: HashMap::from([("w".to_string(), Tensor::new(values, &Device::Cpu).unwrap())]),
+            metrics: HashMap::new(),
+            weights_digest: Vec::new(),
+            signature: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_filter() -> Result<(), candle_core::Error> {
+        let global = HashMap::from([("w".to_string(), Tensor::new(&[0f32, 0.0], &Device::Cpu)?)]);
+        let results = vec![
+            result("a", &[1.0, 1.0]),
+            result("b", &[1.0, 0.9]),
+            result("c", &[0.9, 1.0]),
+            result("nan", &[f32::NAN, 1.0]),
+            result("large", &[10.0, 10.0]),
+            result("opposite", &[-1.0, -1.0]),
+        ];
+
+        let filter = UpdateFilter {
+            max_update_norm: Some(5.0),
+            max_cosine_distance: Some(0.5),
+        };
+        let (accepted, rejected) = filter.filter(&global, results)?;
+
+        let accepted: Vec<_> = accepted.iter().map(|r| r.worker_id.as_str()).collect();
+        let rejected: Vec<_> = rejected.iter().map(|r| r.worker_id.as_str()).collect();
+        assert_eq!(accepted, ["a", "b", "c"]);
+        assert_eq!(rejected, ["nan", "large", "opposite"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter_tensors() -> Result<(), candle_core::Error> {
+        let tensors = |a: &[f32], b: &[f32]| -> Result<_, candle_core::Error> {
+            Ok(HashMap::from([
+                ("a".to_string(), Tensor::new(a, &Device::Cpu)?),
+                ("b".to_string(), Tensor::new(b, &Device::Cpu)?),
+            ]))
+        };
+        let global = tensors(&[0.0, 0.0], &[0.0])?;
+        let results = vec![
+            FitResult {
+                weights: tensors(&[3.0, 0.0], &[0.0])?,
+                ..result("small", &[])
+            },
+            FitResult {
+                weights: tensors(&[3.0, 0.0], &[4.0])?,
+                ..result("large", &[])
+            },
+            FitResult {
+                weights: tensors(&[0.0, 0.0], &[f32::INFINITY])?,
+                ..result("inf", &[])
+            },
+        ];
+
+        // The norm of an update spans all of its tensors
+        let filter = UpdateFilter {
+            max_update_norm: Some(4.5),
+            max_cosine_distance: None,
+        };
+        let (accepted, rejected) = filter.filter(&global, results)?;
+
+        let accepted: Vec<_> = accepted.iter().map(|r| r.worker_id.as_str()).collect();
+        let rejected: Vec<_> = rejected.iter().map(|r| r.worker_id.as_str()).collect();
+        assert_eq!(accepted, ["small"]);
+        assert_eq!(rejected, ["large", "inf"]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,69 @@
+//! Transfer of blobs of weights that are too large for a single message.
+//!
+//! Blobs are identified by the hex-encoded SHA-256 digest of their content and
+//! transferred in chunks, which can be resumed at an offset.
+
+use std::sync::Arc;
+
+use sha2::{Digest, Sha256};
+
+use crate::candlefl::Chunk;
+
+/// Size of the chunks that blobs are transferred in. Weights that are larger
+/// are transferred as blobs instead of inline.
+pub const CHUNK_SIZE: usize = 1 << 20;
+
+/// ID of a blob, the hex-encoded SHA-256 digest of its content.
+pub fn blob_id(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Chunks of a blob of a job, starting at `offset`.
+pub fn chunks(
+    job_id: String,
+    blob_id: String,
+    data: Arc<[u8]>,
+    offset: usize,
+) -> impl Iterator<Item = Chunk> {
+    let size = data.len();
+
+    (offset..size).step_by(CHUNK_SIZE).map(move |start| {
+        let end = (start + CHUNK_SIZE).min(size);
+
+        Chunk {
+            blob_id: blob_id.clone(),
+            offset: start as u64,
+            size: size as u64,
+            data: data[start..end].to_vec(),
+            job_id: job_id.clone(),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunks() {
+        let data: Arc<[u8]> = vec![7; 2 * CHUNK_SIZE + 3].into();
+
+        let offsets = |offset| {
+            chunks("job".to_string(), "blob".to_string(), data.clone(), offset)
+                .map(|chunk| (chunk.offset as usize, chunk.data.len()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            offsets(0),
+            [
+                (0, CHUNK_SIZE),
+                (CHUNK_SIZE, CHUNK_SIZE),
+                (2 * CHUNK_SIZE, 3)
+            ]
+        );
+        // A resumed transfer continues at the offset received so far
+        assert_eq!(offsets(2 * CHUNK_SIZE), [(2 * CHUNK_SIZE, 3)]);
+        assert_eq!(offsets(data.len()), []);
+    }
+}
@@ -0,0 +1,224 @@
+//! Encodings of the weights exchanged between the coordinator and workers,
+//! which cast or quantize weights to reduce the size of transfers.
+
+use std::collections::HashMap;
+
+use candle_core::{bail, safetensors::load_buffer, DType, Device, Error, Tensor};
+use safetensors::SafeTensors;
+
+use crate::candlefl::WeightEncoding;
+
+/// Suffix of the tensor with the scales of a quantized tensor.
+const SCALE_SUFFIX: &str = ".scale";
+/// Suffix of the tensor with the zero points of a quantized tensor.
+const ZERO_POINT_SUFFIX: &str = ".zero_point";
+
+/// Serialize weights as safetensors with an encoding.
+///
+/// Floating point tensors are cast or quantized, their original dtypes are
+/// stored as metadata. Quantized tensors are stored as unsigned bytes, along
+/// with a "<name>.scale" and "<name>.zero_point" tensor.
+pub fn encode(
+    weights: &HashMap<String, Tensor>,
+    encoding: WeightEncoding,
+) -> Result<Vec<u8>, Error> {
+    let mut tensors = HashMap::new();
+    let mut dtypes = HashMap::new();
+
+    for (name, tensor) in weights {
+        if encoding == WeightEncoding::None || !tensor.dtype().is_float() {
+            tensors.insert(name.clone(), tensor.clone());
+            continue;
+        }
+
+        let encoded = match encoding {
+            WeightEncoding::None => unreachable!(),
+            WeightEncoding::Fp16 => tensor.to_dtype(DType::F16)?,
+            WeightEncoding::Bf16 => tensor.to_dtype(DType::BF16)?,
+            WeightEncoding::Int8PerTensor | WeightEncoding::Int8PerChannel => {
+                let per_channel = encoding == WeightEncoding::Int8PerChannel;
+                let (values, scale, zero_point) = quantize(name, tensor, per_channel)?;
+
+                tensors.insert(format!("{name}{SCALE_SUFFIX}"), scale);
+                tensors.insert(format!("{name}{ZERO_POINT_SUFFIX}"), zero_point);
+                values
+            }
+        };
+
+        tensors.insert(name.clone(), encoded);
+        dtypes.insert(name.clone(), tensor.dtype().as_str().to_string());
+    }
+
+    let metadata = (!dtypes.is_empty()).then_some(dtypes);
+
+    Ok(safetensors::serialize(&tensors, &metadata)?)
+}
+
+/// Deserialize weights that were serialized with an encoding, restoring
+/// the original dtypes.
+pub fn decode(data: &[u8], encoding: WeightEncoding) -> Result<HashMap<String, Tensor>, Error> {
+    let mut tensors = load_buffer(data, &Device::Cpu)?;
+    if encoding == WeightEncoding::None {
+        return Ok(tensors);
+    }
+
+    let (_, metadata) = SafeTensors::read_metadata(data)?;
+    let dtypes = metadata.metadata().clone().unwrap_or_default();
+
+    for (name, dtype) in dtypes {
+        let dtype = dtype
+            .parse::<DType>()
+            .map_err(|e| Error::Msg(format!("tensor {name}: {e}")))?;
+        let tensor = take(&mut tensors, &name)?;
+
+        let decoded = match encoding {
+            WeightEncoding::None => unreachable!(),
+            WeightEncoding::Fp16 | WeightEncoding::Bf16 => tensor.to_dtype(dtype)?,
+            WeightEncoding::Int8PerTensor | WeightEncoding::Int8PerChannel => {
+                let scale = take(&mut tensors, &format!("{name}{SCALE_SUFFIX}"))?;
+                let zero_point = take(&mut tensors, &format!("{name}{ZERO_POINT_SUFFIX}"))?;
+
+                dequantize(&tensor, &scale, &zero_point)?.to_dtype(dtype)?
+            }
+        };
+
+        tensors.insert(name, decoded);
+    }
+
+    Ok(tensors)
+}
+
+/// Quantize a tensor to unsigned bytes with an affine mapping, per slice along
+/// the first dimension or for the whole tensor.
+///
+/// The range of each slice includes zero, so that zero is represented exactly.
+/// Returns the quantized tensor, the scales and the zero points.
+fn quantize(
+    name: &str,
+    tensor: &Tensor,
+    per_channel: bool,
+) -> Result<(Tensor, Tensor, Tensor), Error> {
+    let channels = match tensor.dims() {
+        [channels, _, ..] if per_channel => *channels,
+        _ => 1,
+    };
+    let rows = tensor
+        .to_dtype(DType::F32)?
+        .reshape((channels, ()))?
+        .to_vec2::<f32>()?;
+
+    let mut values = Vec::with_capacity(tensor.elem_count());
+    let mut scales = Vec::with_capacity(channels);
+    let mut zero_points = Vec::with_capacity(channels);
+
+    for row in rows {
+        // Quantization would hide NaN values from the checks of the receiver
+        if !row.iter().all(|x| x.is_finite()) {
+            bail!("cannot quantize non-finite values of tensor {name}");
+        }
+
+        let min = row.iter().copied().fold(0.0, f32::min);
+        let max = row.iter().copied().fold(0.0, f32::max);
+        let scale = if max > min { (max - min) / 255.0 } else { 1.0 };
+        let zero_point = (-min / scale).round().clamp(0.0, 255.0);
+
+        values.extend(
+            row.iter()
+                .map(|x| (x / scale + zero_point).round().clamp(0.0, 255.0) as u8),
+        );
+        scales.push(scale);
+        zero_points.push(zero_point as u8);
+    }
+
+    Ok((
+        Tensor::from_vec(values, tensor.shape(), tensor.device())?,
+        Tensor::new(scales, tensor.device())?,
+        Tensor::new(zero_points, tensor.device())?,
+    ))
+}
+
+/// Map a quantized tensor back to 32-bit floating point.
+fn dequantize(tensor: &Tensor, scale: &Tensor, zero_point: &Tensor) -> Result<Tensor, Error> {
+    let channels = scale.elem_count();
+
+    tensor
+        .to_dtype(DType::F32)?
+        .reshape((channels, ()))?
+        .broadcast_sub(&zero_point.to_dtype(DType::F32)?.reshape((channels, 1))?)?
+        .broadcast_mul(&scale.to_dtype(DType::F32)?.reshape((channels, 1))?)?
+        .reshape(tensor.shape())
+}
+
+fn take(tensors: &mut HashMap<String, Tensor>, name: &str) -> Result<Tensor, Error> {
+    tensors.remove(name).ok_or_else(|| Error::CannotFindTensor {
+        path: name.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(
+        weights: &HashMap<String, Tensor>,
+        encoding: WeightEncoding,
+    ) -> HashMap<String, Tensor> {
+        decode(&encode(weights, encoding).unwrap(), encoding).unwrap()
+    }
+
+    fn max_error(a: &Tensor, b: &Tensor) -> f32 {
+        (a - b)
+            .unwrap()
+            .abs()
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .max(0)
+            .unwrap()
+            .to_scalar::<f32>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let w = Tensor::new(&[[-1.0f32, 0.0, 0.5], [10.0, 20.0, 30.0]], &Device::Cpu).unwrap();
+        let weights = HashMap::from([("w".to_string(), w.clone())]);
+
+        for (encoding, tolerance) in [
+            (WeightEncoding::None, 0.0),
+            (WeightEncoding::Fp16, 0.01),
+            (WeightEncoding::Bf16, 0.1),
+            (WeightEncoding::Int8PerTensor, 31.0 / 255.0),
+            (WeightEncoding::Int8PerChannel, 30.0 / 255.0),
+        ] {
+            let decoded = roundtrip(&weights, encoding);
+
+            assert_eq!(decoded.len(), 1, "{encoding:?}");
+            assert_eq!(decoded["w"].dtype(), DType::F32, "{encoding:?}");
+            assert_eq!(decoded["w"].shape(), w.shape(), "{encoding:?}");
+            assert!(max_error(&decoded["w"], &w) <= tolerance, "{encoding:?}");
+        }
+
+        // Small values of a slice aren't flattened by large values of another
+        let decoded = roundtrip(&weights, WeightEncoding::Int8PerChannel);
+        assert!(max_error(&decoded["w"].get(0).unwrap(), &w.get(0).unwrap()) <= 1.5 / 255.0);
+    }
+
+    #[test]
+    fn test_non_finite() {
+        let w = Tensor::new(&[1.0f32, f32::NAN], &Device::Cpu).unwrap();
+        let weights = HashMap::from([("w".to_string(), w)]);
+
+        assert!(encode(&weights, WeightEncoding::Int8PerTensor).is_err());
+    }
+
+    #[test]
+    fn test_non_float() {
+        let steps = Tensor::new(&[3i64], &Device::Cpu).unwrap();
+        let weights = HashMap::from([("steps".to_string(), steps)]);
+
+        // Tensors that aren't floating point are sent as they are
+        let decoded = roundtrip(&weights, WeightEncoding::Int8PerChannel);
+        assert_eq!(decoded["steps"].to_vec1::<i64>().unwrap(), [3]);
+    }
+}
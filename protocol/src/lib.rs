@@ -0,0 +1,13 @@
+//! Protocol between the coordinator and workers.
+//!
+//! Both sides share the gRPC messages and services, the encoding of weights,
+//! the payload of signatures, the transfer of blobs in chunks and the reader of
+//! MNIST files, so that they can't diverge.
+
+pub mod blob;
+pub mod candlefl {
+    tonic::include_proto!("candlefl.v1");
+}
+pub mod encoding;
+pub mod mnist;
+pub mod signing;
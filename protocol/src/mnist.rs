@@ -0,0 +1,125 @@
+//! MNIST IDX files, which workers train on and the coordinator evaluates with.
+//!
+//! Both sides read the same directory, so that files that work for one of
+//! them work for the other.
+
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    path::{Path, PathBuf},
+};
+
+use candle_core::{DType, Device, Error, Tensor};
+use flate2::read::GzDecoder;
+
+/// Images and labels of a split of MNIST.
+pub struct Mnist {
+    /// Images scaled to [0, 1], flattened to one row per image.
+    pub images: Tensor,
+    /// Class labels as unsigned bytes.
+    pub labels: Tensor,
+    pub height: usize,
+    pub width: usize,
+}
+
+/// Load a split of MNIST, "train" or "t10k", from the IDX files in `dir`.
+///
+/// Files may be uncompressed, e.g. `train-images-idx3-ubyte`, or compressed
+/// with gzip, e.g. `train-images-idx3-ubyte.gz`.
+pub fn load_dir(dir: &Path, split: &str) -> Result<Mnist, Error> {
+    let (image_shape, images) = read_idx(open(dir, &format!("{split}-images-idx3-ubyte"))?)?;
+    let (label_shape, labels) = read_idx(open(dir, &format!("{split}-labels-idx1-ubyte"))?)?;
+
+    let [num_images, height, width] = image_shape[..] else {
+        return Err(Error::Msg(format!(
+            "expected images of shape [n, height, width], got {image_shape:?}"
+        )));
+    };
+    if label_shape != [num_images] {
+        return Err(Error::Msg(format!(
+            "expected {num_images} labels, got shape {label_shape:?}"
+        )));
+    }
+
+    let images = (Tensor::from_vec(images, (num_images, height * width), &Device::Cpu)?
+        .to_dtype(DType::F32)?
+        / 255.)?;
+    let labels = Tensor::from_vec(labels, num_images, &Device::Cpu)?;
+
+    Ok(Mnist {
+        images,
+        labels,
+        height,
+        width,
+    })
+}
+
+/// Open an IDX file, preferring the uncompressed version if both exist.
+fn open(dir: &Path, name: &str) -> Result<Box<dyn Read>, Error> {
+    let path = dir.join(name);
+    if path.exists() {
+        return Ok(Box::new(BufReader::new(File::open(path)?)));
+    }
+
+    let path = PathBuf::from(format!("{}.gz", path.display()));
+    if path.exists() {
+        return Ok(Box::new(GzDecoder::new(BufReader::new(File::open(path)?))));
+    }
+
+    Err(Error::Msg(format!(
+        "missing {name} or {name}.gz in {}",
+        dir.display()
+    )))
+}
+
+/// Read an IDX file of unsigned bytes, returning its shape and data.
+fn read_idx(mut reader: impl Read) -> Result<(Vec<usize>, Vec<u8>), Error> {
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+
+    // The magic number is two zero bytes, the data type and the number of dimensions
+    let [0, 0, 0x08, num_dims] = magic else {
+        return Err(Error::Msg(format!(
+            "invalid IDX magic number {magic:02x?}, expected unsigned bytes"
+        )));
+    };
+
+    let mut shape = Vec::with_capacity(num_dims as usize);
+    for _ in 0..num_dims {
+        let mut dim = [0u8; 4];
+        reader.read_exact(&mut dim)?;
+        shape.push(u32::from_be_bytes(dim) as usize);
+    }
+
+    let len = shape.iter().product();
+    let mut data = Vec::with_capacity(len);
+    reader.read_to_end(&mut data)?;
+    if data.len() != len {
+        return Err(Error::Msg(format!(
+            "expected {len} bytes of data for shape {shape:?}, got {}",
+            data.len()
+        )));
+    }
+
+    Ok((shape, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_idx() {
+        let data = [0, 0, 8, 2, 0, 0, 0, 2, 0, 0, 0, 3, 1, 2, 3, 4, 5, 6];
+
+        let (shape, values) = read_idx(&data[..]).unwrap();
+
+        assert_eq!(shape, vec![2, 3]);
+        assert_eq!(values, vec![1, 2, 3, 4, 5, 6]);
+
+        // Truncated data
+        assert!(read_idx(&data[..16]).is_err());
+        // Not unsigned bytes
+        assert!(read_idx(&[0, 0, 0x0d, 1, 0, 0, 0, 1, 0, 0, 0, 0][..]).is_err());
+    }
+}
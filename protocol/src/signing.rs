@@ -0,0 +1,160 @@
+//! Payload of the Ed25519 signatures of the weights exchanged between the
+//! coordinator and workers.
+
+use std::collections::HashMap;
+
+use sha2::{Digest, Sha256};
+
+use crate::candlefl::{config_value::Value, ConfigValue, ModelSpec, WeightEncoding};
+
+/// Context of the signature of a `FitRequest` by the coordinator.
+pub const FIT_REQUEST: &str = "candlefl.v1.FitRequest";
+/// Context of the signature of a `TrainResponse` by the coordinator.
+pub const TRAIN_RESPONSE: &str = "candlefl.v1.TrainResponse";
+/// Context of the signature of a `WeightsResponse` by a worker.
+pub const WEIGHTS_RESPONSE: &str = "candlefl.v1.WeightsResponse";
+/// Context of the signature of a `FitResponse` by a worker.
+pub const FIT_RESPONSE: &str = "candlefl.v1.FitResponse";
+
+/// Fields of a message with weights of a job that are signed.
+pub struct Signed<'a> {
+    pub context: &'a str,
+    pub job_id: &'a str,
+    pub round: u64,
+    pub encoding: WeightEncoding,
+    /// Serialized weights, in the encoding of the message.
+    pub weights: &'a [u8],
+    /// Digest of other fields of the message that are signed, empty if there
+    /// are none, see [`fit_request_digest`].
+    pub fields_digest: Vec<u8>,
+}
+
+impl Signed<'_> {
+    /// Message that is signed.
+    ///
+    /// The context separates signatures of different messages, so that a
+    /// signature can't be replayed as another message.
+    pub fn payload(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(self.context.as_bytes());
+        payload.push(0);
+        payload.extend_from_slice(self.job_id.as_bytes());
+        payload.push(0);
+        payload.extend_from_slice(&self.round.to_be_bytes());
+        payload.extend_from_slice(&(self.encoding as i32).to_be_bytes());
+        payload.extend_from_slice(&digest(self.weights));
+        payload.extend_from_slice(&self.fields_digest);
+        payload
+    }
+}
+
+/// SHA-256 digest of serialized weights.
+pub fn digest(weights: &[u8]) -> Vec<u8> {
+    Sha256::digest(weights).to_vec()
+}
+
+/// Canonical SHA-256 digest of the training configuration and model of a
+/// `FitRequest`, independent of the order of their entries.
+pub fn fit_request_digest(
+    config: &HashMap<String, ConfigValue>,
+    model: Option<&ModelSpec>,
+) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    update_config(&mut hasher, config);
+
+    match model {
+        Some(model) => {
+            hasher.update([1]);
+            update_bytes(&mut hasher, model.architecture.as_bytes());
+            update_config(&mut hasher, &model.config);
+        }
+        None => hasher.update([0]),
+    }
+
+    hasher.finalize().to_vec()
+}
+
+fn update_config(hasher: &mut Sha256, config: &HashMap<String, ConfigValue>) {
+    let mut names: Vec<_> = config.keys().collect();
+    names.sort();
+
+    hasher.update((names.len() as u64).to_be_bytes());
+    for name in names {
+        update_bytes(hasher, name.as_bytes());
+
+        // Values are tagged with their type, so that e.g. 1 and 1.0 differ
+        match &config[name].value {
+            None => hasher.update([0]),
+            Some(Value::Bool(value)) => hasher.update([1, *value as u8]),
+            Some(Value::Int(value)) => {
+                hasher.update([2]);
+                hasher.update(value.to_be_bytes());
+            }
+            Some(Value::Double(value)) => {
+                hasher.update([3]);
+                hasher.update(value.to_bits().to_be_bytes());
+            }
+            Some(Value::String(value)) => {
+                hasher.update([4]);
+                update_bytes(hasher, value.as_bytes());
+            }
+        }
+    }
+}
+
+/// Update a digest with length-prefixed bytes, so that adjacent fields can't
+/// be shifted into each other.
+fn update_bytes(hasher: &mut Sha256, bytes: &[u8]) {
+    hasher.update((bytes.len() as u64).to_be_bytes());
+    hasher.update(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(values: &[(&str, Value)]) -> HashMap<String, ConfigValue> {
+        values
+            .iter()
+            .map(|(name, value)| {
+                let value = ConfigValue {
+                    value: Some(value.clone()),
+                };
+                (name.to_string(), value)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_fit_request_digest() {
+        let a = config(&[
+            ("batch_size", Value::Int(32)),
+            ("shuffle", Value::Bool(true)),
+        ]);
+        let model = ModelSpec {
+            architecture: "mlp".to_string(),
+            config: config(&[("num_classes", Value::Int(10))]),
+        };
+        let digest = fit_request_digest(&a, Some(&model));
+
+        // The digest doesn't depend on the order of entries
+        let b = config(&[
+            ("shuffle", Value::Bool(true)),
+            ("batch_size", Value::Int(32)),
+        ]);
+        assert_eq!(fit_request_digest(&b, Some(&model)), digest);
+
+        let c = config(&[
+            ("batch_size", Value::Double(32.0)),
+            ("shuffle", Value::Bool(true)),
+        ]);
+        assert_ne!(fit_request_digest(&c, Some(&model)), digest);
+        assert_ne!(fit_request_digest(&a, None), digest);
+
+        let other = ModelSpec {
+            architecture: "lenet".to_string(),
+            ..model
+        };
+        assert_ne!(fit_request_digest(&a, Some(&other)), digest);
+    }
+}
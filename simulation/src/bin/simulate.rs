@@ -0,0 +1,271 @@
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
+
+use clap::{Parser, ValueEnum};
+use tokio::{net::TcpListener, time};
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::transport::Uri;
+use tracing::{info, warn};
+
+use coordinator::{
+    candlefl::{config_value::Value, ConfigValue, ModelSpec, UpdateFilter, WeightEncoding},
+    mnist_evaluation, server, FedAvg, State, DEFAULT_MAX_MESSAGE_SIZE,
+};
+use worker::{
+    ml::{DataSource, DatasetCache, DatasetConfig, PartitionConfig, Partitioning},
+    Worker,
+};
+
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    /// Number of simulated workers
+    #[arg(long, default_value_t = 4)]
+    workers: usize,
+
+    /// Directory with the MNIST IDX files, instead of downloading them
+    #[arg(long)]
+    data_dir: Option<PathBuf>,
+
+    /// Directory with MNIST IDX files to evaluate the global model after each round
+    #[arg(long)]
+    eval_data_dir: Option<PathBuf>,
+
+    /// Partitioning of the dataset across workers
+    #[arg(long, value_enum, default_value_t = PartitionMode::Iid)]
+    partition: PartitionMode,
+
+    /// Concentration of the "dirichlet" and "quantity" partitionings, smaller is more skewed
+    #[arg(long, default_value_t = 0.5)]
+    partition_alpha: f64,
+
+    /// Number of classes per worker of the "pathological" partitioning
+    #[arg(long, default_value_t = 2)]
+    classes_per_partition: usize,
+
+    /// Seed of the partitioning
+    #[arg(long, default_value_t = 0)]
+    partition_seed: u64,
+
+    /// Model architecture to train on MNIST, "mlp" or "lenet"
+    #[arg(long, default_value_t = String::from("mlp"))]
+    model: String,
+
+    /// Number of local epochs per round
+    #[arg(long)]
+    local_epochs: Option<i64>,
+
+    #[arg(long)]
+    batch_size: Option<i64>,
+
+    #[arg(long)]
+    learning_rate: Option<f64>,
+
+    /// Maximum number of local optimizer steps per round
+    #[arg(long)]
+    max_steps: Option<i64>,
+
+    /// Reject worker updates with a larger L2 norm
+    #[arg(long)]
+    max_update_norm: Option<f64>,
+
+    /// Reject worker updates with a larger cosine distance from the median update
+    #[arg(long)]
+    max_cosine_distance: Option<f64>,
+
+    /// Cast or quantize the weights exchanged with workers, to reduce their size
+    #[arg(long, value_enum, default_value_t = Encoding::None)]
+    weight_encoding: Encoding,
+
+    rounds: usize,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum PartitionMode {
+    Iid,
+    Dirichlet,
+    Pathological,
+    Quantity,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Encoding {
+    None,
+    Fp16,
+    Bf16,
+    /// 8-bit quantization with a scale and zero point per tensor
+    Int8,
+    /// 8-bit quantization with a scale and zero point per output channel
+    Int8PerChannel,
+}
+
+impl Args {
+    /// Dataset of the worker at `index`.
+    fn dataset_config(&self, index: usize) -> DatasetConfig {
+        let partitioning = match self.partition {
+            PartitionMode::Iid => Partitioning::Iid,
+            PartitionMode::Dirichlet => Partitioning::Dirichlet {
+                alpha: self.partition_alpha,
+            },
+            PartitionMode::Pathological => Partitioning::Pathological {
+                classes_per_partition: self.classes_per_partition,
+            },
+            PartitionMode::Quantity => Partitioning::Quantity {
+                alpha: self.partition_alpha,
+            },
+        };
+
+        DatasetConfig {
+            source: DataSource::Mnist {
+                data_dir: self.data_dir.clone(),
+            },
+            partition: Some(PartitionConfig {
+                partitioning,
+                num_partitions: self.workers,
+                index,
+                seed: self.partition_seed,
+            }),
+        }
+    }
+
+    fn fit_config(&self) -> HashMap<String, ConfigValue> {
+        [
+            ("local_epochs", self.local_epochs.map(Value::Int)),
+            ("batch_size", self.batch_size.map(Value::Int)),
+            ("learning_rate", self.learning_rate.map(Value::Double)),
+            ("max_steps", self.max_steps.map(Value::Int)),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| {
+            value.map(|value| (name.to_string(), ConfigValue { value: Some(value) }))
+        })
+        .collect()
+    }
+
+    /// Model with the shapes of MNIST.
+    fn model(&self) -> ModelSpec {
+        let shapes: &[(&str, i64)] = match self.model.as_str() {
+            "lenet" => &[("channels", 1), ("height", 28), ("width", 28)],
+            _ => &[("input_dim", 28 * 28)],
+        };
+
+        ModelSpec {
+            architecture: self.model.clone(),
+            config: shapes
+                .iter()
+                .chain(&[("num_classes", 10)])
+                .map(|&(name, value)| {
+                    let value = ConfigValue {
+                        value: Some(Value::Int(value)),
+                    };
+                    (name.to_string(), value)
+                })
+                .collect(),
+        }
+    }
+
+    fn update_filter(&self) -> UpdateFilter {
+        UpdateFilter {
+            max_update_norm: self.max_update_norm,
+            max_cosine_distance: self.max_cosine_distance,
+        }
+    }
+
+    fn weight_encoding(&self) -> WeightEncoding {
+        match self.weight_encoding {
+            Encoding::None => WeightEncoding::None,
+            Encoding::Fp16 => WeightEncoding::Fp16,
+            Encoding::Bf16 => WeightEncoding::Bf16,
+            Encoding::Int8 => WeightEncoding::Int8PerTensor,
+            Encoding::Int8PerChannel => WeightEncoding::Int8PerChannel,
+        }
+    }
+}
+
+/// Simulate a federated learning job with a coordinator and several workers in
+/// a single process.
+///
+/// Workers connect to the coordinator over loopback gRPC, each training on its
+/// own partition of the dataset.
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    tracing_subscriber::fmt::init();
+
+    let args = Args::parse();
+
+    // Serve the coordinator on a free loopback port
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+
+    let evaluate = args
+        .eval_data_dir
+        .as_deref()
+        .map(mnist_evaluation)
+        .transpose()?;
+
+    let state = State::new();
+
+    let (server, _) = server(
+        state.clone(),
+        evaluate.clone(),
+        None,
+        None,
+        DEFAULT_MAX_MESSAGE_SIZE,
+    )
+    .await?;
+    tokio::spawn(server.serve_with_incoming(TcpListenerStream::new(listener)));
+
+    info!(addr = %addr, "coordinator started");
+
+    // Workers share a dataset cache, so that the dataset is loaded only once
+    let datasets = Arc::new(DatasetCache::default());
+    let uri: Uri = format!("http://{addr}").parse()?;
+
+    for index in 0..args.workers {
+        let worker = Worker::new(
+            format!("worker-{index}"),
+            args.dataset_config(index),
+            datasets.clone(),
+        );
+        worker.preload().await?;
+
+        let uri = uri.clone();
+        tokio::spawn(async move {
+            if let Err(e) = worker.run(uri).await {
+                warn!(index, error = %e, "worker failed");
+            }
+        });
+    }
+
+    // Jobs only include workers that are connected when they start
+    while state.num_workers().await? < args.workers {
+        time::sleep(Duration::from_millis(100)).await;
+    }
+
+    info!(workers = args.workers, "workers connected");
+
+    let strategy = FedAvg::new(state.clone(), evaluate);
+    let (job_id, _) = strategy
+        .fit(
+            args.rounds,
+            args.model(),
+            &args.fit_config(),
+            None,
+            Some(&args.update_filter()),
+            args.weight_encoding(),
+        )
+        .await?;
+
+    for round_metrics in state.get_metrics(job_id).await? {
+        info!(
+            job_id = round_metrics.job_id,
+            round = round_metrics.round,
+            num_workers = round_metrics.num_workers,
+            fit_metrics = ?round_metrics.fit_metrics,
+            evaluate_metrics = ?round_metrics.evaluate_metrics,
+            rejected_updates = ?round_metrics.rejected_updates,
+            "completed round"
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,68 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+/// Jittered exponential backoff between attempts to reach the coordinator.
+///
+/// Delays double with each attempt up to a maximum. Each delay is drawn
+/// between half and all of the current delay, so that workers that lost their
+/// connection at the same time don't retry in lockstep.
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            attempt: 0,
+        }
+    }
+
+    /// Delay before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self
+            .initial
+            .saturating_mul(2u32.saturating_pow(self.attempt))
+            .min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+
+        delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+
+    /// Start over after a successful attempt.
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(Duration::from_millis(100), Duration::from_secs(30))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(4));
+
+        for max in [1, 2, 4, 4] {
+            let delay = backoff.next_delay();
+            let max = Duration::from_secs(max);
+            assert!(
+                delay >= max / 2 && delay <= max,
+                "{delay:?} not within {max:?}"
+            );
+        }
+
+        backoff.reset();
+        assert!(backoff.next_delay() <= Duration::from_secs(1));
+    }
+}
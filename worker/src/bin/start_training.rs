@@ -1,8 +1,23 @@
-use clap::Parser;
-use tonic::transport::{Channel, Uri};
+use std::{collections::HashMap, io, path::PathBuf};
+
+use clap::{Parser, ValueEnum};
+use tonic::{
+    metadata::{Ascii, MetadataValue},
+    transport::{Channel, ClientTlsConfig, Uri},
+    Request, Status,
+};
 use tracing::info;
+use worker::{
+    read_token,
+    signing::{self, read_verifying_key, Signed},
+    tls_config, DEFAULT_MAX_MESSAGE_SIZE,
+};
 
-use crate::candlefl::{command_client::CommandClient, TrainRequest};
+use crate::candlefl::{
+    command_client::CommandClient, config_value::Value, learning_rate_schedule::Kind, ConfigValue,
+    LearningRateSchedule, ModelSpec, TrainRequest, UpdateFilter, WatchMetricsRequest,
+    WeightEncoding,
+};
 
 mod candlefl {
     tonic::include_proto!("candlefl.v1");
@@ -14,9 +29,224 @@ struct Args {
     #[arg(long, default_value_t = String::from("[::1]:50051"))]
     addr: String,
 
+    /// PEM CA certificate to verify the coordinator with, connects with TLS
+    #[arg(long)]
+    tls_ca_cert: Option<PathBuf>,
+
+    /// PEM certificate to authenticate with, if the coordinator requires mutual TLS
+    #[arg(long, requires_all = ["tls_key", "tls_ca_cert"])]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of the client certificate
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Name to verify the coordinator certificate against, defaults to the host of the address
+    #[arg(long, requires = "tls_ca_cert")]
+    tls_domain: Option<String>,
+
+    /// File with a bearer token of an operator to authenticate to the coordinator with
+    #[arg(long)]
+    token_file: Option<PathBuf>,
+
+    /// SPKI PEM Ed25519 public key of the coordinator, to verify the signature of the final model
+    #[arg(long)]
+    coordinator_public_key: Option<PathBuf>,
+
+    /// Limit of the size of gRPC messages in bytes, which needs to fit the final model
+    #[arg(long, default_value_t = DEFAULT_MAX_MESSAGE_SIZE)]
+    max_message_size: usize,
+
+    /// Model architecture to train, the "mlp" architecture for MNIST if not provided
+    #[arg(long)]
+    model: Option<String>,
+
+    /// Configuration of the model architecture, e.g. "num_classes=10"
+    #[arg(long, value_parser = parse_key_value, requires = "model")]
+    model_config: Vec<(String, ConfigValue)>,
+
+    /// Number of local epochs per round
+    #[arg(long)]
+    local_epochs: Option<i64>,
+
+    #[arg(long)]
+    batch_size: Option<i64>,
+
+    #[arg(long)]
+    learning_rate: Option<f64>,
+
+    /// Local optimizer: "sgd", "adamw" or "rmsprop"
+    #[arg(long)]
+    optimizer: Option<String>,
+
+    #[arg(long)]
+    weight_decay: Option<f64>,
+
+    /// Momentum of the "sgd" and "rmsprop" optimizers
+    #[arg(long)]
+    momentum: Option<f64>,
+
+    /// Use Nesterov momentum with the "sgd" optimizer
+    #[arg(long)]
+    nesterov: bool,
+
+    /// Keep the local optimizer state across rounds instead of resetting it
+    #[arg(long)]
+    persist_optimizer_state: bool,
+
+    /// Maximum number of local optimizer steps per round
+    #[arg(long)]
+    max_steps: Option<i64>,
+
+    /// Batch examples in order instead of shuffling them each epoch
+    #[arg(long)]
+    no_shuffle: bool,
+
+    /// Skip the last batch of an epoch if it is incomplete
+    #[arg(long)]
+    drop_last: bool,
+
+    /// Adjust the learning rate per round, requires '--learning-rate'
+    #[arg(long)]
+    lr_schedule: Option<Schedule>,
+
+    /// Decay factor of the exponential and step schedules
+    #[arg(long, default_value_t = 0.9)]
+    lr_gamma: f64,
+
+    /// Number of rounds between decays of the step schedule
+    #[arg(long, default_value_t = 1)]
+    lr_step_size: u64,
+
+    /// Final learning rate of the cosine schedule
+    #[arg(long, default_value_t = 0.0)]
+    min_learning_rate: f64,
+
+    /// Reject worker updates with a larger L2 norm
+    #[arg(long)]
+    max_update_norm: Option<f64>,
+
+    /// Reject worker updates with a larger cosine distance from the median update
+    #[arg(long)]
+    max_cosine_distance: Option<f64>,
+
+    /// Cast or quantize the weights exchanged with workers, to reduce their size
+    #[arg(long, value_enum, default_value_t = Encoding::None)]
+    weight_encoding: Encoding,
+
     rounds: u64,
 }
 
+#[derive(Clone, Copy, ValueEnum)]
+enum Schedule {
+    Exponential,
+    Step,
+    Cosine,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Encoding {
+    None,
+    Fp16,
+    Bf16,
+    /// 8-bit quantization with a scale and zero point per tensor
+    Int8,
+    /// 8-bit quantization with a scale and zero point per output channel
+    Int8PerChannel,
+}
+
+/// Parse "key=value" pairs, inferring the type of the value.
+fn parse_key_value(s: &str) -> Result<(String, ConfigValue), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected key=value, got {s}"))?;
+
+    let value = if let Ok(value) = value.parse() {
+        Value::Int(value)
+    } else if let Ok(value) = value.parse() {
+        Value::Double(value)
+    } else if let Ok(value) = value.parse() {
+        Value::Bool(value)
+    } else {
+        Value::String(value.to_string())
+    };
+
+    Ok((key.to_string(), ConfigValue { value: Some(value) }))
+}
+
+impl Args {
+    fn tls_config(&self) -> Result<Option<ClientTlsConfig>, io::Error> {
+        let Some(ca_cert) = &self.tls_ca_cert else {
+            return Ok(None);
+        };
+
+        let identity = self.tls_cert.as_deref().zip(self.tls_key.as_deref());
+        tls_config(ca_cert, identity, self.tls_domain.as_deref()).map(Some)
+    }
+
+    fn fit_config(&self) -> HashMap<String, ConfigValue> {
+        [
+            ("local_epochs", self.local_epochs.map(Value::Int)),
+            ("batch_size", self.batch_size.map(Value::Int)),
+            ("learning_rate", self.learning_rate.map(Value::Double)),
+            ("optimizer", self.optimizer.clone().map(Value::String)),
+            ("weight_decay", self.weight_decay.map(Value::Double)),
+            ("momentum", self.momentum.map(Value::Double)),
+            ("nesterov", self.nesterov.then_some(Value::Bool(true))),
+            (
+                "persist_optimizer_state",
+                self.persist_optimizer_state.then_some(Value::Bool(true)),
+            ),
+            ("max_steps", self.max_steps.map(Value::Int)),
+            ("shuffle", self.no_shuffle.then_some(Value::Bool(false))),
+            ("drop_last", self.drop_last.then_some(Value::Bool(true))),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| {
+            value.map(|value| (name.to_string(), ConfigValue { value: Some(value) }))
+        })
+        .collect()
+    }
+
+    fn model(&self) -> Option<ModelSpec> {
+        self.model.as_ref().map(|architecture| ModelSpec {
+            architecture: architecture.clone(),
+            config: self.model_config.iter().cloned().collect(),
+        })
+    }
+
+    fn learning_rate_schedule(&self) -> Option<LearningRateSchedule> {
+        self.lr_schedule.map(|schedule| LearningRateSchedule {
+            kind: match schedule {
+                Schedule::Exponential => Kind::Exponential,
+                Schedule::Step => Kind::Step,
+                Schedule::Cosine => Kind::Cosine,
+            }
+            .into(),
+            gamma: self.lr_gamma,
+            step_size: self.lr_step_size,
+            min_learning_rate: self.min_learning_rate,
+        })
+    }
+
+    fn update_filter(&self) -> UpdateFilter {
+        UpdateFilter {
+            max_update_norm: self.max_update_norm,
+            max_cosine_distance: self.max_cosine_distance,
+        }
+    }
+
+    fn weight_encoding(&self) -> WeightEncoding {
+        match self.weight_encoding {
+            Encoding::None => WeightEncoding::None,
+            Encoding::Fp16 => WeightEncoding::Fp16,
+            Encoding::Bf16 => WeightEncoding::Bf16,
+            Encoding::Int8 => WeightEncoding::Int8PerTensor,
+            Encoding::Int8PerChannel => WeightEncoding::Int8PerChannel,
+        }
+    }
+}
+
 /// Simple command to request the coordinator to start a federated learning training run.
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -24,26 +254,98 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let args = Args::parse();
 
-    let uri: Uri = format!("http://{}", args.addr).parse()?;
+    let tls = args.tls_config()?;
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    let uri: Uri = format!("{scheme}://{}", args.addr).parse()?;
 
-    let channel = Channel::builder(uri.clone())
-        .user_agent("candle-fl-command/0.1.0")?
-        .connect()
-        .await?;
+    let mut endpoint = Channel::builder(uri.clone()).user_agent("candle-fl-command/0.1.0")?;
+    if let Some(tls) = tls {
+        endpoint = endpoint.tls_config(tls)?;
+    }
+    let channel = endpoint.connect().await?;
 
     info!(uri = uri.to_string(), "connected to coordinator");
 
-    let mut command_client = CommandClient::new(channel.clone());
+    let authorization: Option<MetadataValue<Ascii>> = match &args.token_file {
+        Some(path) => Some(format!("Bearer {}", read_token(path)?).parse()?),
+        None => None,
+    };
+
+    // Authenticate each request with the token, if provided
+    let mut command_client = CommandClient::with_interceptor(
+        channel.clone(),
+        move |mut request: Request<()>| -> Result<Request<()>, Status> {
+            if let Some(authorization) = &authorization {
+                request
+                    .metadata_mut()
+                    .insert("authorization", authorization.clone());
+            }
+            Ok(request)
+        },
+    )
+    .max_decoding_message_size(args.max_message_size)
+    .max_encoding_message_size(args.max_message_size);
+
+    // Log round metrics while training is in progress
+    let mut metrics = command_client
+        .watch_metrics(WatchMetricsRequest::default())
+        .await?
+        .into_inner();
+
+    tokio::spawn(async move {
+        while let Ok(Some(round_metrics)) = metrics.message().await {
+            info!(
+                job_id = round_metrics.job_id,
+                round = round_metrics.round,
+                num_workers = round_metrics.num_workers,
+                fit_metrics = ?round_metrics.fit_metrics,
+                evaluate_metrics = ?round_metrics.evaluate_metrics,
+                "completed round"
+            );
+            for rejected in round_metrics.rejected_updates {
+                info!(
+                    round = round_metrics.round,
+                    worker_id = rejected.worker_id,
+                    reason = rejected.reason,
+                    "rejected update"
+                );
+            }
+        }
+    });
 
     info!(uri = uri.to_string(), "sending training request");
 
-    let _response = command_client
+    let response = command_client
         .train(TrainRequest {
             rounds: args.rounds,
+            fit_config: args.fit_config(),
+            learning_rate_schedule: args.learning_rate_schedule(),
+            model: args.model(),
+            update_filter: Some(args.update_filter()),
+            weight_encoding: args.weight_encoding().into(),
         })
-        .await?;
+        .await?
+        .into_inner();
+
+    info!(
+        uri = uri.to_string(),
+        job_id = response.job_id,
+        "training completed"
+    );
+
+    if let Some(path) = &args.coordinator_public_key {
+        let signed = Signed {
+            context: signing::TRAIN_RESPONSE,
+            job_id: &response.job_id,
+            round: args.rounds,
+            encoding: WeightEncoding::None,
+            weights: &response.weights,
+            fields_digest: Vec::new(),
+        };
+        signing::verify(&read_verifying_key(path)?, &signed, &response.signature)?;
 
-    info!(uri = uri.to_string(), "training completed");
+        info!(job_id = response.job_id, "verified signature of the model");
+    }
 
     Ok(())
 }
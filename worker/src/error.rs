@@ -0,0 +1,34 @@
+use ed25519_dalek::SignatureError;
+use thiserror::Error;
+use tokio::{sync::watch, task::JoinError, time::error::Elapsed};
+use tonic::{metadata::errors::InvalidMetadataValue, transport, Status};
+
+/// Errors of the worker.
+#[derive(Debug, Error)]
+pub enum WorkerError {
+    #[error("failed to connect to the coordinator: {0}")]
+    Connection(#[from] transport::Error),
+    // Boxed, as statuses are large
+    #[error("request to the coordinator failed: {0}")]
+    Request(Box<Status>),
+    #[error("invalid worker ID or token: {0}")]
+    InvalidMetadata(#[from] InvalidMetadataValue),
+    #[error("invalid signature of the coordinator: {0}")]
+    Signature(#[from] SignatureError),
+    #[error("checksum mismatch of blob {0}")]
+    ChecksumMismatch(String),
+    #[error(transparent)]
+    Candle(#[from] candle_core::Error),
+    #[error("task failed: {0}")]
+    Task(#[from] JoinError),
+    #[error("timed out")]
+    Timeout(#[from] Elapsed),
+    #[error("worker stopped")]
+    Stopped(#[from] watch::error::RecvError),
+}
+
+impl From<Status> for WorkerError {
+    fn from(status: Status) -> Self {
+        WorkerError::Request(Box::new(status))
+    }
+}
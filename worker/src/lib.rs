@@ -0,0 +1,627 @@
+//! Worker of federated learning jobs.
+//!
+//! Workers subscribe to a coordinator and train models on their local data
+//! when requested. The `worker` binary runs a single worker.
+
+use std::{
+    collections::HashMap,
+    fs,
+    future::Future,
+    io,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use candle_core::{Device, Error};
+use candle_nn::VarMap;
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use protocol::encoding;
+use tokio::{sync::watch, task, time};
+use tonic::{
+    transport::{Certificate, Channel, ClientTlsConfig, Identity, Uri},
+    Code, Request, Status, Streaming,
+};
+use tracing::{debug, info, warn};
+
+use crate::backoff::Backoff;
+use crate::candlefl::{
+    config_value, coordinator_message, publisher_client::PublisherClient,
+    subscriber_client::SubscriberClient, worker_message, ConfigValue, CoordinatorMessage,
+    FitRequest, FitResponse, ModelSpec, TaskError, Unregister, WeightEncoding, WeightsResponse,
+    WorkerMessage,
+};
+use crate::ml::{
+    prepare_model, shuffle_seed, train, DatasetCache, DatasetConfig, ModelRegistry, Session,
+    Sessions, TrainConfig,
+};
+
+pub use error::WorkerError;
+pub use protocol::candlefl;
+
+pub mod ml;
+pub mod signing;
+
+mod backoff;
+mod error;
+mod transfer;
+
+/// Metadata key of the ID that the worker subscribes and publishes with.
+const WORKER_ID_KEY: &str = "x-worker-id";
+
+/// Metadata key of the bearer token that the worker authenticates with.
+const AUTHORIZATION_KEY: &str = "authorization";
+
+/// Default limit of the size of gRPC messages in bytes, as in tonic.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+/// A worker that trains models on its local dataset.
+pub struct Worker {
+    id: String,
+    dataset_config: Arc<DatasetConfig>,
+    datasets: Arc<DatasetCache>,
+    registry: Arc<ModelRegistry>,
+    sessions: Arc<Sessions>,
+    tls: Option<ClientTlsConfig>,
+    token: Option<Arc<str>>,
+    // Signs the results of tasks.
+    signing_key: Option<SigningKey>,
+    // Verifies that training requests are signed by the coordinator.
+    coordinator_key: Option<VerifyingKey>,
+    max_message_size: usize,
+    // Channel of the current connection, replaced when reconnecting.
+    channel: watch::Sender<Option<Channel>>,
+    // Number of tasks whose results are still to be delivered.
+    in_flight: Arc<watch::Sender<usize>>,
+    // Set to abort running training tasks when shutting down.
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Worker {
+    /// Create a worker that subscribes with `id` and loads its dataset with `datasets`.
+    ///
+    /// Workers running in the same process can share datasets with a common cache.
+    pub fn new(id: String, dataset_config: DatasetConfig, datasets: Arc<DatasetCache>) -> Self {
+        Self {
+            id,
+            dataset_config: Arc::new(dataset_config),
+            datasets,
+            registry: Arc::new(ModelRegistry::default()),
+            sessions: Arc::new(Sessions::default()),
+            tls: None,
+            token: None,
+            signing_key: None,
+            coordinator_key: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            channel: watch::channel(None).0,
+            in_flight: Arc::new(watch::channel(0).0),
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Connect to the coordinator with TLS, see [`tls_config`].
+    pub fn with_tls(mut self, config: ClientTlsConfig) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Authenticate to the coordinator with a bearer token.
+    ///
+    /// The coordinator identifies the worker by the subject of the token
+    /// instead of its ID.
+    pub fn with_token(mut self, token: String) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Sign the weights of results with a key that is enrolled at the coordinator.
+    pub fn with_signing_key(mut self, key: SigningKey) -> Self {
+        self.signing_key = Some(key);
+        self
+    }
+
+    /// Only train on weights of requests that are signed by the coordinator.
+    pub fn with_coordinator_key(mut self, key: VerifyingKey) -> Self {
+        self.coordinator_key = Some(key);
+        self
+    }
+
+    /// Limit the size of messages exchanged with the coordinator to `size` bytes.
+    ///
+    /// Weights larger than 1 MiB are transferred in chunks regardless.
+    pub fn with_max_message_size(mut self, size: usize) -> Self {
+        self.max_message_size = size;
+        self
+    }
+
+    /// Load the local dataset, so that the first round isn't delayed.
+    pub async fn preload(&self) -> Result<(), WorkerError> {
+        let datasets = self.datasets.clone();
+        let dataset_config = self.dataset_config.clone();
+
+        task::spawn_blocking(move || datasets.get(&Device::Cpu, &dataset_config)).await??;
+
+        Ok(())
+    }
+
+    /// Connect to the coordinator at `uri` and train models when requested,
+    /// until the coordinator closes the subscription.
+    ///
+    /// Lost connections are retried with jittered exponential backoff and the
+    /// worker resubscribes with the same ID. Results of tasks that complete
+    /// while disconnected are delivered once reconnected.
+    pub async fn run(&self, uri: Uri) -> Result<(), WorkerError> {
+        let mut backoff = Backoff::default();
+
+        loop {
+            match self.subscribe(&uri).await {
+                Ok(stream) => {
+                    backoff.reset();
+
+                    match self.handle_messages(stream).await {
+                        Ok(()) => return Ok(()),
+                        Err(e) => warn!(error = %e, "lost connection to coordinator"),
+                    }
+                }
+                Err(e) => warn!(error = %e, "failed to connect to coordinator"),
+            }
+
+            let delay = backoff.next_delay();
+            info!(delay = ?delay, "reconnecting to coordinator");
+            time::sleep(delay).await;
+        }
+    }
+
+    /// Like [`Worker::run`], but shuts down gracefully once `signal` completes.
+    ///
+    /// The worker stops accepting new tasks and waits up to `timeout` for
+    /// running tasks to deliver their results. Training tasks that take longer
+    /// are cancelled. Finally, the worker unregisters from the coordinator, so
+    /// that jobs don't wait for it.
+    pub async fn run_with_shutdown(
+        &self,
+        uri: Uri,
+        signal: impl Future<Output = ()>,
+        timeout: Duration,
+    ) -> Result<(), WorkerError> {
+        tokio::select! {
+            result = self.run(uri) => return result,
+            _ = signal => {}
+        }
+
+        info!(worker_id = self.id, "shutting down");
+
+        let mut in_flight = self.in_flight.subscribe();
+        if time::timeout(timeout, in_flight.wait_for(|tasks| *tasks == 0))
+            .await
+            .is_err()
+        {
+            warn!("cancelling running tasks");
+            self.cancelled.store(true, Ordering::Relaxed);
+
+            let _ = time::timeout(timeout, in_flight.wait_for(|tasks| *tasks == 0)).await;
+        }
+
+        // Never connected, so there's nothing to unregister from
+        if self.channel.borrow().is_none() {
+            return Ok(());
+        }
+
+        let message = WorkerMessage {
+            message: Some(worker_message::Message::Unregister(Unregister {})),
+        };
+        time::timeout(
+            timeout,
+            publish(
+                self.channel.subscribe(),
+                &self.id,
+                self.token.as_deref(),
+                message,
+            ),
+        )
+        .await??;
+
+        info!(worker_id = self.id, "unregistered from coordinator");
+
+        Ok(())
+    }
+
+    async fn subscribe(&self, uri: &Uri) -> Result<Streaming<CoordinatorMessage>, WorkerError> {
+        let mut endpoint = Channel::builder(uri.clone()).user_agent("candle-fl-worker/0.1.0")?;
+        if let Some(tls) = &self.tls {
+            endpoint = endpoint.tls_config(tls.clone())?;
+        }
+        let channel = endpoint.connect().await?;
+        let mut subscriber_client = SubscriberClient::new(channel.clone())
+            .max_decoding_message_size(self.max_message_size)
+            .max_encoding_message_size(self.max_message_size);
+
+        let stream = subscriber_client
+            .subscribe(request(&self.id, self.token.as_deref(), ())?)
+            .await?
+            .into_inner();
+
+        info!(
+            uri = uri.to_string(),
+            worker_id = self.id,
+            "connected to coordinator"
+        );
+
+        // Results that are waiting for a connection are published with the new one
+        self.channel.send_replace(Some(channel));
+
+        Ok(stream)
+    }
+
+    async fn handle_messages(
+        &self,
+        mut stream: Streaming<CoordinatorMessage>,
+    ) -> Result<(), WorkerError> {
+        while let Some(message) = stream.message().await? {
+            if let Some(message) = message.message {
+                match message {
+                    coordinator_message::Message::WeightsRequest(weights_request) => {
+                        debug!(job_id = weights_request.job_id, "received WeightsRequest");
+
+                        let registry = self.registry.clone();
+                        let dataset_config = self.dataset_config.clone();
+                        let datasets = self.datasets.clone();
+                        let signing_key = self.signing_key.clone();
+                        let job_id = weights_request.job_id.clone();
+                        let encoding = weights_request.weight_encoding();
+
+                        // This is a blocking operation, so we'll offload it
+                        let task = task::spawn_blocking(move || -> Result<_, Error> {
+                            let dev = Device::Cpu;
+                            let data = datasets.get(&dev, &dataset_config)?;
+                            let spec = model_spec(weights_request.model);
+                            let (varmap, _) = prepare_model(&dev, &registry, &spec, data.info())?;
+
+                            serialize(&varmap, encoding)
+                        });
+
+                        self.deliver(weights_request.job_id, 0, async move {
+                            let weights = task.await??;
+                            let signature = signing_key
+                                .map(|key| {
+                                    signing::sign(
+                                        &key,
+                                        &signing::Signed {
+                                            context: signing::WEIGHTS_RESPONSE,
+                                            job_id: &job_id,
+                                            round: 0,
+                                            encoding,
+                                            weights: &weights,
+                                            fields_digest: Vec::new(),
+                                        },
+                                    )
+                                })
+                                .unwrap_or_default();
+
+                            Ok(worker_message::Message::WeightsResponse(WeightsResponse {
+                                job_id,
+                                weights,
+                                signature,
+                                weights_blob_id: String::new(),
+                                weight_encoding: encoding.into(),
+                            }))
+                        });
+                    }
+                    coordinator_message::Message::FitRequest(mut fit_request) => {
+                        debug!(job_id = fit_request.job_id, "received FitRequest");
+
+                        let registry = self.registry.clone();
+                        let dataset_config = self.dataset_config.clone();
+                        let datasets = self.datasets.clone();
+                        let sessions = self.sessions.clone();
+                        let cancelled = self.cancelled.clone();
+                        let signing_key = self.signing_key.clone();
+                        let coordinator_key = self.coordinator_key;
+                        let channel = self.channel.subscribe();
+                        let worker_id = self.id.clone();
+                        let token = self.token.clone();
+                        let job_id = fit_request.job_id.clone();
+                        let round = fit_request.round;
+                        let encoding = fit_request.weight_encoding();
+
+                        self.deliver(job_id.clone(), round, async move {
+                            // Weights that are too large for a single message are downloaded
+                            if !fit_request.weights_blob_id.is_empty() {
+                                fit_request.weights = transfer::download(
+                                    channel,
+                                    &worker_id,
+                                    token.as_deref(),
+                                    &job_id,
+                                    &fit_request.weights_blob_id,
+                                )
+                                .await?;
+                            }
+
+                            // Don't train on weights that weren't sent by the coordinator
+                            verify(coordinator_key.as_ref(), &fit_request)?;
+
+                            // This is a blocking operation, so we'll offload it
+                            let session_id = job_id.clone();
+                            let task = task::spawn_blocking(move || -> Result<_, Error> {
+                                let dev = Device::Cpu;
+                                let config = TrainConfig::try_from(&fit_request.config)?;
+                                let data = datasets.get(&dev, &dataset_config)?;
+                                let spec = model_spec(fit_request.model);
+
+                                let mut session = if config.persist_optimizer_state {
+                                    sessions.take(
+                                        &session_id,
+                                        &dev,
+                                        &registry,
+                                        &spec,
+                                        data.info(),
+                                    )?
+                                } else {
+                                    Session::new(&dev, &registry, &spec, data.info())?
+                                };
+
+                                let metrics = train(
+                                    &mut session,
+                                    &encoding::decode(&fit_request.weights, encoding)?,
+                                    &data,
+                                    &config,
+                                    shuffle_seed(&session_id, fit_request.round),
+                                    &cancelled,
+                                    &dev,
+                                )?;
+                                let weights = serialize(&session.varmap, encoding)?;
+
+                                if config.persist_optimizer_state {
+                                    sessions.insert(session_id, session);
+                                }
+
+                                Ok((weights, metrics))
+                            });
+
+                            let (weights, metrics) = task.await??;
+                            let signature = signing_key
+                                .map(|key| {
+                                    signing::sign(
+                                        &key,
+                                        &signing::Signed {
+                                            context: signing::FIT_RESPONSE,
+                                            job_id: &job_id,
+                                            round,
+                                            encoding,
+                                            weights: &weights,
+                                            fields_digest: Vec::new(),
+                                        },
+                                    )
+                                })
+                                .unwrap_or_default();
+
+                            Ok(worker_message::Message::FitResponse(FitResponse {
+                                job_id,
+                                weights,
+                                metrics,
+                                round,
+                                signature,
+                                weights_blob_id: String::new(),
+                                weight_encoding: encoding.into(),
+                            }))
+                        });
+                    }
+                    coordinator_message::Message::Shutdown(_) => {
+                        info!("coordinator is shutting down");
+
+                        // Reconnect once the coordinator is back
+                        return Err(Status::unavailable("coordinator shut down").into());
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Publish the result of a task once it completes, or report its failure
+    /// to the coordinator together with the round of the task.
+    fn deliver(
+        &self,
+        job_id: String,
+        round: u64,
+        result: impl Future<Output = Result<worker_message::Message, WorkerError>> + Send + 'static,
+    ) {
+        let in_flight = InFlight::new(&self.in_flight);
+        let channel = self.channel.subscribe();
+        let worker_id = self.id.clone();
+        let token = self.token.clone();
+
+        task::spawn(async move {
+            let _in_flight = in_flight;
+
+            let result = match result.await {
+                // Weights that are too large for a single message are uploaded
+                Ok(mut message) => {
+                    transfer::offload(&mut message, channel.clone(), &worker_id, token.as_deref())
+                        .await
+                        .map(|()| message)
+                }
+                Err(e) => Err(e),
+            };
+
+            let message = match result {
+                Ok(message) => message,
+                Err(e) => {
+                    warn!(job_id, error = %e, "failed task");
+
+                    worker_message::Message::TaskError(TaskError {
+                        job_id: job_id.clone(),
+                        message: e.to_string(),
+                        round,
+                    })
+                }
+            };
+
+            let message = WorkerMessage {
+                message: Some(message),
+            };
+
+            match publish(channel, &worker_id, token.as_deref(), message).await {
+                Ok(()) => debug!(job_id, "sent result"),
+                Err(e) => warn!(job_id, error = %e, "failed to send result"),
+            }
+        });
+    }
+}
+
+/// TLS configuration to connect to a coordinator with.
+///
+/// The coordinator is verified with the PEM CA certificate `ca_cert`. With a
+/// PEM certificate and key as `identity`, the client authenticates itself for
+/// mutual TLS. `domain` overrides the name that the coordinator certificate is
+/// verified against, which defaults to the host of the coordinator URI.
+pub fn tls_config(
+    ca_cert: &Path,
+    identity: Option<(&Path, &Path)>,
+    domain: Option<&str>,
+) -> Result<ClientTlsConfig, io::Error> {
+    let mut config =
+        ClientTlsConfig::new().ca_certificate(Certificate::from_pem(fs::read(ca_cert)?));
+
+    if let Some((cert, key)) = identity {
+        config = config.identity(Identity::from_pem(fs::read(cert)?, fs::read(key)?));
+    }
+    if let Some(domain) = domain {
+        config = config.domain_name(domain);
+    }
+
+    Ok(config)
+}
+
+/// Read a bearer token to authenticate to a coordinator with from a file.
+pub fn read_token(path: &Path) -> Result<String, io::Error> {
+    Ok(fs::read_to_string(path)?.trim().to_string())
+}
+
+/// Model of a request, which defaults to the "mlp" architecture for MNIST like
+/// the training requests of the coordinator.
+fn model_spec(model: Option<ModelSpec>) -> ModelSpec {
+    model.unwrap_or_else(|| ModelSpec {
+        architecture: "mlp".to_string(),
+        config: [("input_dim", 784), ("num_classes", 10)]
+            .into_iter()
+            .map(|(name, value)| {
+                let value = ConfigValue {
+                    value: Some(config_value::Value::Int(value)),
+                };
+                (name.to_string(), value)
+            })
+            .collect(),
+    })
+}
+
+/// Verify the signature of a training request, if a coordinator key is configured.
+fn verify(key: Option<&VerifyingKey>, fit_request: &FitRequest) -> Result<(), WorkerError> {
+    let Some(key) = key else {
+        return Ok(());
+    };
+
+    let signed = signing::Signed {
+        context: signing::FIT_REQUEST,
+        job_id: &fit_request.job_id,
+        round: fit_request.round,
+        encoding: fit_request.weight_encoding(),
+        weights: &fit_request.weights,
+        fields_digest: signing::fit_request_digest(&fit_request.config, fit_request.model.as_ref()),
+    };
+    signing::verify(key, &signed, &fit_request.signature)?;
+
+    Ok(())
+}
+
+/// A task whose result is still to be delivered, counted until dropped.
+struct InFlight(Arc<watch::Sender<usize>>);
+
+impl InFlight {
+    fn new(counter: &Arc<watch::Sender<usize>>) -> Self {
+        counter.send_modify(|tasks| *tasks += 1);
+        Self(counter.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.send_modify(|tasks| *tasks -= 1);
+    }
+}
+
+/// Publish a message to the coordinator, retrying until it is delivered.
+///
+/// Failed attempts are retried once the worker has reconnected, or after
+/// backing off if the connection is still the same.
+async fn publish(
+    mut channel: watch::Receiver<Option<Channel>>,
+    worker_id: &str,
+    token: Option<&str>,
+    message: WorkerMessage,
+) -> Result<(), WorkerError> {
+    let mut backoff = Backoff::default();
+
+    loop {
+        let current = channel.borrow_and_update().clone();
+
+        if let Some(current) = current {
+            let mut publisher_client = PublisherClient::new(current);
+
+            match publisher_client
+                .publish(request(worker_id, token, message.clone())?)
+                .await
+            {
+                Ok(_) => return Ok(()),
+                // The coordinator rejected the message, sending it again won't help
+                Err(status) if !is_transient(&status) => return Err(status.into()),
+                Err(status) => warn!(error = %status, "failed to publish, retrying"),
+            }
+        }
+
+        tokio::select! {
+            changed = channel.changed() => changed?,
+            _ = time::sleep(backoff.next_delay()) => {}
+        }
+    }
+}
+
+/// Whether a request failed because of the connection rather than the coordinator.
+fn is_transient(status: &Status) -> bool {
+    matches!(
+        status.code(),
+        Code::Unavailable | Code::Unknown | Code::Cancelled
+    )
+}
+
+/// Request that identifies the worker to the coordinator, authenticated with
+/// `token` if provided.
+fn request<T>(worker_id: &str, token: Option<&str>, message: T) -> Result<Request<T>, WorkerError> {
+    let mut request = Request::new(message);
+    request
+        .metadata_mut()
+        .insert(WORKER_ID_KEY, worker_id.parse()?);
+
+    if let Some(token) = token {
+        request
+            .metadata_mut()
+            .insert(AUTHORIZATION_KEY, format!("Bearer {token}").parse()?);
+    }
+
+    Ok(request)
+}
+
+fn serialize(varmap: &VarMap, encoding: WeightEncoding) -> Result<Vec<u8>, Error> {
+    let tensor_data = varmap.data().lock().unwrap();
+
+    let weights: HashMap<_, _> = tensor_data
+        .iter()
+        .map(|(k, v)| (k.clone(), v.as_tensor().clone()))
+        .collect();
+
+    encoding::encode(&weights, encoding)
+}
@@ -1,27 +1,295 @@
-use candle_core::{Device, Error};
-use candle_nn::VarMap;
-use clap::Parser;
-use safetensors::{SafeTensorError, SafeTensors};
-use tokio::{sync::oneshot, task};
-use tonic::transport::{Channel, Uri};
-use tracing::{debug, info};
-
-use crate::candlefl::{
-    publisher_client::PublisherClient, subscriber_client::SubscriberClient, worker_message,
-    FitResponse, WeightsResponse, WorkerMessage,
+use std::{io, path::PathBuf, sync::Arc, time::Duration};
+
+use clap::{Parser, ValueEnum};
+use tokio::signal;
+use tonic::transport::{ClientTlsConfig, Uri};
+
+use worker::{
+    ml::{
+        DataSource, DatasetCache, DatasetConfig, FeatureStats, ImageFolderConfig, Normalization,
+        PartitionConfig, Partitioning, TabularConfig,
+    },
+    read_token,
+    signing::{read_signing_key, read_verifying_key},
+    tls_config, Worker, DEFAULT_MAX_MESSAGE_SIZE,
 };
-use crate::ml::{prepare_data, prepare_model, train};
-
-mod candlefl {
-    tonic::include_proto!("candlefl.v1");
-}
-mod ml;
 
 #[derive(Parser)]
 #[command(version)]
 struct Args {
     #[arg(long, default_value_t = String::from("[::1]:50051"))]
     addr: String,
+
+    /// ID to subscribe with, so that the coordinator recognizes the worker
+    /// after reconnecting. Defaults to a random ID
+    #[arg(long)]
+    worker_id: Option<String>,
+
+    #[command(flatten)]
+    tls: TlsArgs,
+
+    /// File with a bearer token to authenticate to the coordinator with. The
+    /// coordinator identifies the worker by the subject of the token
+    #[arg(long)]
+    token_file: Option<PathBuf>,
+
+    /// PKCS#8 PEM Ed25519 private key to sign results with, whose public key is
+    /// enrolled at the coordinator
+    #[arg(long)]
+    signing_key: Option<PathBuf>,
+
+    /// SPKI PEM Ed25519 public key of the coordinator, to only train on signed requests
+    #[arg(long)]
+    coordinator_public_key: Option<PathBuf>,
+
+    /// Directory with the MNIST IDX files, instead of downloading them
+    #[arg(long, conflicts_with_all = ["tabular_data", "image_folder"])]
+    data_dir: Option<PathBuf>,
+
+    #[command(flatten)]
+    tabular: TabularArgs,
+
+    #[command(flatten)]
+    images: ImageArgs,
+
+    #[command(flatten)]
+    partition: PartitionArgs,
+
+    /// Load the local dataset at startup instead of with the first request
+    #[arg(long)]
+    preload_data: bool,
+
+    /// Seconds to wait for running tasks on SIGINT or SIGTERM before cancelling them
+    #[arg(long, default_value_t = 30)]
+    shutdown_timeout: u64,
+
+    /// Limit of the size of gRPC messages in bytes. Weights larger than 1 MiB
+    /// are transferred in chunks
+    #[arg(long, default_value_t = DEFAULT_MAX_MESSAGE_SIZE)]
+    max_message_size: usize,
+}
+
+#[derive(clap::Args)]
+struct TlsArgs {
+    /// PEM CA certificate to verify the coordinator with, connects with TLS
+    #[arg(long)]
+    tls_ca_cert: Option<PathBuf>,
+
+    /// PEM certificate to authenticate with, if the coordinator requires mutual TLS
+    #[arg(long, requires_all = ["tls_key", "tls_ca_cert"])]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of the client certificate
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Name to verify the coordinator certificate against, defaults to the host of the address
+    #[arg(long, requires = "tls_ca_cert")]
+    tls_domain: Option<String>,
+}
+
+#[derive(clap::Args)]
+struct TabularArgs {
+    /// CSV or Parquet file to train on, instead of MNIST
+    #[arg(long, conflicts_with = "image_folder")]
+    tabular_data: Option<PathBuf>,
+
+    /// Feature columns, defaults to all columns except the label column
+    #[arg(long, value_delimiter = ',')]
+    feature_columns: Vec<String>,
+
+    #[arg(long, default_value_t = String::from("label"))]
+    label_column: String,
+
+    /// Feature columns that are one-hot encoded
+    #[arg(long, value_delimiter = ',')]
+    categorical_columns: Vec<String>,
+
+    /// Categories of a categorical or label column, e.g. "color=red,green,blue"
+    #[arg(long, value_parser = parse_categories)]
+    categories: Vec<(String, Vec<String>)>,
+
+    /// Number of classes of a label column with class indices
+    #[arg(long, requires = "tabular_data")]
+    num_classes: Option<usize>,
+
+    /// Size of the vocabulary if feature columns are token IDs, e.g. for the transformer
+    #[arg(long, requires = "tabular_data")]
+    vocab_size: Option<usize>,
+
+    /// Normalization of numeric feature columns, with statistics from `--feature-stats`
+    #[arg(long, value_enum, default_value_t = Normalization::None)]
+    normalization: Normalization,
+
+    /// Statistics of a numeric feature column, e.g. "age=mean,std" to normalize
+    /// it with "standard" and "age=min,max" with "min-max"
+    #[arg(long, value_parser = parse_feature_stats)]
+    feature_stats: Vec<(String, FeatureStats)>,
+}
+
+#[derive(clap::Args)]
+struct ImageArgs {
+    /// Directory with a subdirectory of PNG or JPEG images per class, instead of MNIST
+    #[arg(long)]
+    image_folder: Option<PathBuf>,
+
+    /// Height that images are resized to
+    #[arg(long, default_value_t = 32)]
+    image_height: usize,
+
+    /// Width that images are resized to
+    #[arg(long, default_value_t = 32)]
+    image_width: usize,
+
+    /// Convert images to grayscale
+    #[arg(long)]
+    grayscale: bool,
+
+    /// Scale pixels to [-1, 1] instead of [0, 1]
+    #[arg(long)]
+    normalize_images: bool,
+
+    /// Class subdirectories in the order of class indices, defaults to sorted subdirectories
+    #[arg(long, value_delimiter = ',')]
+    classes: Vec<String>,
+}
+
+#[derive(clap::Args)]
+struct PartitionArgs {
+    /// Train on a partition of the dataset, to simulate workers with different data
+    #[arg(long, requires_all = ["num_partitions", "partition_index"])]
+    partition: Option<PartitionMode>,
+
+    #[arg(long)]
+    num_partitions: Option<usize>,
+
+    /// Index of the partition of this worker, starting at 0
+    #[arg(long)]
+    partition_index: Option<usize>,
+
+    /// Concentration of the "dirichlet" and "quantity" partitionings, smaller is more skewed
+    #[arg(long, default_value_t = 0.5)]
+    partition_alpha: f64,
+
+    /// Number of classes per partition of the "pathological" partitioning
+    #[arg(long, default_value_t = 2)]
+    classes_per_partition: usize,
+
+    /// Seed of the partitioning, workers need to use the same seed
+    #[arg(long, default_value_t = 0)]
+    partition_seed: u64,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum PartitionMode {
+    Iid,
+    Dirichlet,
+    Pathological,
+    Quantity,
+}
+
+/// Parse "column=a,b,c" categories.
+fn parse_categories(s: &str) -> Result<(String, Vec<String>), String> {
+    let (column, categories) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected column=categories, got {s}"))?;
+
+    Ok((
+        column.to_string(),
+        categories.split(',').map(str::to_string).collect(),
+    ))
+}
+
+/// Parse "column=a,b" statistics of a feature column.
+fn parse_feature_stats(s: &str) -> Result<(String, FeatureStats), String> {
+    let (column, stats) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected column=statistics, got {s}"))?;
+    let (a, b) = stats
+        .split_once(',')
+        .ok_or_else(|| format!("expected two statistics, got {stats}"))?;
+    let parse = |value: &str| {
+        value
+            .trim()
+            .parse::<f64>()
+            .map_err(|e| format!("invalid statistic {value}: {e}"))
+    };
+
+    Ok((column.to_string(), FeatureStats(parse(a)?, parse(b)?)))
+}
+
+impl TlsArgs {
+    fn config(&self) -> Result<Option<ClientTlsConfig>, io::Error> {
+        let Some(ca_cert) = &self.tls_ca_cert else {
+            return Ok(None);
+        };
+
+        let identity = self.tls_cert.as_deref().zip(self.tls_key.as_deref());
+        tls_config(ca_cert, identity, self.tls_domain.as_deref()).map(Some)
+    }
+}
+
+impl Args {
+    fn dataset_config(&self) -> DatasetConfig {
+        DatasetConfig {
+            source: self.data_source(),
+            partition: self.partition(),
+        }
+    }
+
+    fn partition(&self) -> Option<PartitionConfig> {
+        let args = &self.partition;
+
+        let partitioning = match args.partition? {
+            PartitionMode::Iid => Partitioning::Iid,
+            PartitionMode::Dirichlet => Partitioning::Dirichlet {
+                alpha: args.partition_alpha,
+            },
+            PartitionMode::Pathological => Partitioning::Pathological {
+                classes_per_partition: args.classes_per_partition,
+            },
+            PartitionMode::Quantity => Partitioning::Quantity {
+                alpha: args.partition_alpha,
+            },
+        };
+
+        Some(PartitionConfig {
+            partitioning,
+            num_partitions: args.num_partitions?,
+            index: args.partition_index?,
+            seed: args.partition_seed,
+        })
+    }
+
+    fn data_source(&self) -> DataSource {
+        if let Some(path) = &self.images.image_folder {
+            return DataSource::ImageFolder(ImageFolderConfig {
+                path: path.clone(),
+                height: self.images.image_height,
+                width: self.images.image_width,
+                grayscale: self.images.grayscale,
+                normalize: self.images.normalize_images,
+                classes: self.images.classes.clone(),
+            });
+        }
+
+        match &self.tabular.tabular_data {
+            Some(path) => DataSource::Tabular(TabularConfig {
+                path: path.clone(),
+                feature_columns: self.tabular.feature_columns.clone(),
+                label_column: self.tabular.label_column.clone(),
+                categorical_columns: self.tabular.categorical_columns.clone(),
+                categories: self.tabular.categories.iter().cloned().collect(),
+                num_classes: self.tabular.num_classes,
+                vocab_size: self.tabular.vocab_size,
+                normalization: self.tabular.normalization,
+                feature_stats: self.tabular.feature_stats.iter().cloned().collect(),
+            }),
+            None => DataSource::Mnist {
+                data_dir: self.data_dir.clone(),
+            },
+        }
+    }
 }
 
 #[tokio::main]
@@ -30,114 +298,70 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let args = Args::parse();
 
-    let uri: Uri = format!("http://{}", args.addr).parse()?;
-
-    let channel = Channel::builder(uri.clone())
-        .user_agent("candle-fl-worker/0.1.0")?
-        .connect()
-        .await?;
-    let mut subscriber_client = SubscriberClient::new(channel.clone());
-
-    let mut stream = subscriber_client.subscribe(()).await?.into_inner();
-
-    info!(uri = uri.to_string(), "connected to coordinator");
-
-    // In production code we need to handle stream disconnections by retrying
-    // if a connection is dropped. This isn't done here.
-    while let Some(message) = stream.message().await? {
-        if let Some(message) = message.message {
-            match message {
-                candlefl::coordinator_message::Message::WeightsRequest(weights_request) => {
-                    debug!(job_id = weights_request.job_id, "received WeightsRequest");
-
-                    let channel = channel.clone();
-
-                    let (sender, receiver) = oneshot::channel();
-
-                    // This is a blocking operation, so we'll offload it
-                    task::spawn_blocking(move || {
-                        let result = || -> Result<_, Error> {
-                            let dev = Device::Cpu;
-                            let (varmap, _) = prepare_model(&dev)?;
-
-                            Ok(varmap)
-                        }();
-
-                        let _ = sender.send(result);
-                    });
-
-                    task::spawn(async move {
-                        let result = receiver.await.unwrap();
-
-                        let mut publisher_client = PublisherClient::new(channel);
-
-                        publisher_client
-                            .publish(WorkerMessage {
-                                message: Some(worker_message::Message::WeightsResponse(
-                                    WeightsResponse {
-                                        job_id: weights_request.job_id.clone(),
-                                        weights: serialize(&result.unwrap()).unwrap(),
-                                    },
-                                )),
-                            })
-                            .await
-                            .unwrap();
-
-                        debug!(job_id = weights_request.job_id, "sent WeightsResponse");
-                    });
-                }
-                candlefl::coordinator_message::Message::FitRequest(fit_request) => {
-                    debug!(job_id = fit_request.job_id, "received FitRequest");
-
-                    let channel = channel.clone();
-
-                    let (sender, receiver) = oneshot::channel();
-
-                    // This is a blocking operation, so we'll offload it
-                    task::spawn_blocking(move || {
-                        let result = || -> Result<_, Error> {
-                            let dev = Device::Cpu;
-                            let data = prepare_data(&dev)?;
-
-                            train(&deserialize(&fit_request.weights)?, &data, &dev)
-                        }();
-
-                        let _ = sender.send(result);
-                    });
-
-                    task::spawn(async move {
-                        let result = receiver.await.unwrap();
-
-                        let mut publisher_client = PublisherClient::new(channel);
-
-                        publisher_client
-                            .publish(WorkerMessage {
-                                message: Some(worker_message::Message::FitResponse(FitResponse {
-                                    job_id: fit_request.job_id.clone(),
-                                    weights: serialize(&result.unwrap()).unwrap(),
-                                })),
-                            })
-                            .await
-                            .unwrap();
-
-                        debug!(job_id = fit_request.job_id, "sent FitResponse");
-                    });
-                }
-            }
-        }
+    let worker_id = args
+        .worker_id
+        .clone()
+        .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+
+    let mut worker = Worker::new(
+        worker_id,
+        args.dataset_config(),
+        Arc::new(DatasetCache::default()),
+    )
+    .with_max_message_size(args.max_message_size);
+
+    let tls = args.tls.config()?;
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    if let Some(tls) = tls {
+        worker = worker.with_tls(tls);
+    }
+    if let Some(path) = &args.token_file {
+        worker = worker.with_token(read_token(path)?);
+    }
+    if let Some(path) = &args.signing_key {
+        worker = worker.with_signing_key(read_signing_key(path)?);
+    }
+    if let Some(path) = &args.coordinator_public_key {
+        worker = worker.with_coordinator_key(read_verifying_key(path)?);
     }
 
-    Ok(())
-}
+    // Load the dataset before connecting, so that the first round isn't delayed
+    if args.preload_data {
+        worker.preload().await?;
+    }
 
-fn serialize(varmap: &VarMap) -> Result<Vec<u8>, SafeTensorError> {
-    let tensor_data = varmap.data().lock().unwrap();
+    let uri: Uri = format!("{scheme}://{}", args.addr).parse()?;
 
-    let data = tensor_data.iter().map(|(k, v)| (k, v.as_tensor()));
+    worker
+        .run_with_shutdown(
+            uri,
+            shutdown_signal(),
+            Duration::from_secs(args.shutdown_timeout),
+        )
+        .await?;
 
-    safetensors::serialize(data, &None)
+    Ok(())
 }
 
-fn deserialize(data: &[u8]) -> Result<SafeTensors, SafeTensorError> {
-    safetensors::SafeTensors::deserialize(data)
+/// Completes on SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    let interrupt = async {
+        signal::ctrl_c().await.expect("failed to listen for SIGINT");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
 }
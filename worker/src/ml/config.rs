@@ -0,0 +1,225 @@
+use std::{collections::HashMap, str::FromStr};
+
+use candle_core::Error;
+
+use crate::candlefl::{config_value::Value, ConfigValue};
+
+/// Optimizer used for local training.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OptimizerKind {
+    Sgd,
+    AdamW,
+    RmsProp,
+}
+
+impl FromStr for OptimizerKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "sgd" => Ok(OptimizerKind::Sgd),
+            "adamw" => Ok(OptimizerKind::AdamW),
+            "rmsprop" => Ok(OptimizerKind::RmsProp),
+            _ => Err(Error::Msg(format!("unknown optimizer {s}"))),
+        }
+    }
+}
+
+/// Hyperparameters of local training.
+///
+/// They are sent by the coordinator with each `FitRequest`. Values that aren't
+/// provided fall back to their defaults.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrainConfig {
+    pub local_epochs: usize,
+    pub batch_size: usize,
+    pub learning_rate: f64,
+    pub optimizer: OptimizerKind,
+    pub weight_decay: f64,
+    /// Momentum of SGD and RMSprop.
+    pub momentum: f64,
+    /// Use Nesterov momentum with SGD.
+    pub nesterov: bool,
+    /// Decay rates of the moment estimates of AdamW.
+    pub beta1: f64,
+    pub beta2: f64,
+    /// Smoothing constant of RMSprop.
+    pub alpha: f64,
+    /// Term added to denominators of AdamW and RMSprop for numerical stability.
+    pub eps: f64,
+    /// Keep the optimizer state, e.g. momentum, across rounds of a job instead
+    /// of resetting it each round.
+    pub persist_optimizer_state: bool,
+    /// Stop training after this number of optimizer steps, even within an epoch.
+    pub max_steps: Option<usize>,
+    /// Shuffle examples each epoch.
+    pub shuffle: bool,
+    /// Skip the last batch of an epoch if it is incomplete.
+    pub drop_last: bool,
+}
+
+impl Default for TrainConfig {
+    fn default() -> Self {
+        Self {
+            local_epochs: 1,
+            batch_size: 32,
+            learning_rate: 0.1,
+            optimizer: OptimizerKind::Sgd,
+            weight_decay: 0.0,
+            momentum: 0.0,
+            nesterov: false,
+            beta1: 0.9,
+            beta2: 0.999,
+            alpha: 0.99,
+            eps: 1e-8,
+            persist_optimizer_state: false,
+            max_steps: None,
+            shuffle: true,
+            drop_last: false,
+        }
+    }
+}
+
+impl TryFrom<&HashMap<String, ConfigValue>> for TrainConfig {
+    type Error = Error;
+
+    fn try_from(config: &HashMap<String, ConfigValue>) -> Result<Self, Self::Error> {
+        let mut result = TrainConfig::default();
+
+        for (name, value) in config {
+            let value = value
+                .value
+                .as_ref()
+                .ok_or_else(|| Error::Msg(format!("missing value for {name}")))?;
+
+            match name.as_str() {
+                "local_epochs" => result.local_epochs = as_usize(name, value)?,
+                "batch_size" => result.batch_size = as_usize(name, value)?,
+                "learning_rate" => result.learning_rate = as_f64(name, value)?,
+                "optimizer" => result.optimizer = as_str(name, value)?.parse()?,
+                "weight_decay" => result.weight_decay = as_f64(name, value)?,
+                "momentum" => result.momentum = as_f64(name, value)?,
+                "nesterov" => result.nesterov = as_bool(name, value)?,
+                "beta1" => result.beta1 = as_f64(name, value)?,
+                "beta2" => result.beta2 = as_f64(name, value)?,
+                "alpha" => result.alpha = as_f64(name, value)?,
+                "eps" => result.eps = as_f64(name, value)?,
+                "persist_optimizer_state" => result.persist_optimizer_state = as_bool(name, value)?,
+                "max_steps" => result.max_steps = Some(as_usize(name, value)?),
+                "shuffle" => result.shuffle = as_bool(name, value)?,
+                "drop_last" => result.drop_last = as_bool(name, value)?,
+                // Configurations may contain values for other components
+                _ => {}
+            }
+        }
+
+        if result.batch_size == 0 {
+            return Err(Error::Msg("batch_size must be positive".to_string()));
+        }
+        if result.max_steps == Some(0) {
+            return Err(Error::Msg("max_steps must be positive".to_string()));
+        }
+        if result.nesterov && (result.optimizer != OptimizerKind::Sgd || result.momentum <= 0.0) {
+            return Err(Error::Msg(
+                "nesterov requires the sgd optimizer with positive momentum".to_string(),
+            ));
+        }
+
+        Ok(result)
+    }
+}
+
+/// Architecture specific configuration of a model, e.g. layer sizes.
+pub struct ModelConfig {
+    config: HashMap<String, ConfigValue>,
+}
+
+impl ModelConfig {
+    pub fn new(config: HashMap<String, ConfigValue>) -> Self {
+        Self { config }
+    }
+
+    /// Integer value of the configuration, or `default` if not provided.
+    pub fn usize(&self, name: &str, default: usize) -> Result<usize, Error> {
+        self.value(name)?
+            .map_or(Ok(default), |value| as_usize(name, value))
+    }
+
+    /// Integer value of the configuration that has no default, e.g. a shape
+    /// that all workers need to agree on.
+    pub fn required_usize(&self, name: &str) -> Result<usize, Error> {
+        self.value(name)?
+            .ok_or_else(|| Error::Msg(format!("model configuration requires {name}")))
+            .and_then(|value| as_usize(name, value))
+    }
+
+    fn value(&self, name: &str) -> Result<Option<&Value>, Error> {
+        self.config
+            .get(name)
+            .map(|value| {
+                value
+                    .value
+                    .as_ref()
+                    .ok_or_else(|| Error::Msg(format!("missing value for {name}")))
+            })
+            .transpose()
+    }
+}
+
+fn as_usize(name: &str, value: &Value) -> Result<usize, Error> {
+    match value {
+        Value::Int(v) => {
+            usize::try_from(*v).map_err(|_| Error::Msg(format!("{name} must not be negative")))
+        }
+        _ => Err(Error::Msg(format!("{name} must be an integer"))),
+    }
+}
+
+fn as_f64(name: &str, value: &Value) -> Result<f64, Error> {
+    match value {
+        Value::Double(v) => Ok(*v),
+        Value::Int(v) => Ok(*v as f64),
+        _ => Err(Error::Msg(format!("{name} must be a number"))),
+    }
+}
+
+fn as_bool(name: &str, value: &Value) -> Result<bool, Error> {
+    match value {
+        Value::Bool(v) => Ok(*v),
+        _ => Err(Error::Msg(format!("{name} must be a boolean"))),
+    }
+}
+
+fn as_str<'a>(name: &str, value: &'a Value) -> Result<&'a str, Error> {
+    match value {
+        Value::String(v) => Ok(v),
+        _ => Err(Error::Msg(format!("{name} must be a string"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(values: &[(&str, Value)]) -> HashMap<String, ConfigValue> {
+        values
+            .iter()
+            .map(|(name, value)| {
+                let value = ConfigValue {
+                    value: Some(value.clone()),
+                };
+                (name.to_string(), value)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_try_from() {
+        let result = TrainConfig::try_from(&config(&[("max_steps", Value::Int(10))])).unwrap();
+        assert_eq!(result.max_steps, Some(10));
+
+        // No steps would leave the metrics of the round undefined
+        assert!(TrainConfig::try_from(&config(&[("max_steps", Value::Int(0))])).is_err());
+        assert!(TrainConfig::try_from(&config(&[("batch_size", Value::Int(0))])).is_err());
+    }
+}
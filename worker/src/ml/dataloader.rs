@@ -1,53 +1,206 @@
-use candle_core::Tensor;
+use std::{
+    sync::{
+        mpsc::{self, Receiver},
+        Arc,
+    },
+    thread,
+};
 
+use candle_core::{Error, Tensor};
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+
+use crate::ml::dataset::Dataset;
+
+/// Number of batches that are collated ahead of training.
+const PREFETCH_BATCHES: usize = 4;
+
+/// Batches examples of a dataset, optionally shuffled per epoch.
+///
+/// Batches are collated on a background thread, so that reading datasets from
+/// disk overlaps with training.
 pub struct Dataloader {
-    inputs: Tensor,
-    targets: Tensor,
+    dataset: Arc<dyn Dataset>,
     batch_size: usize,
+    /// Seed of the shuffle, examples are batched in order if not provided.
+    seed: Option<u64>,
+    /// Skip the last batch of an epoch if it is smaller than `batch_size`.
+    drop_last: bool,
 }
 
 impl Dataloader {
-    pub fn new(inputs: Tensor, targets: Tensor, batch_size: usize) -> Self {
+    pub fn new(
+        dataset: Arc<dyn Dataset>,
+        batch_size: usize,
+        seed: Option<u64>,
+        drop_last: bool,
+    ) -> Self {
         Self {
-            inputs,
-            targets,
+            dataset,
             batch_size,
+            seed,
+            drop_last,
         }
     }
 
-    pub fn iter(&self) -> DataloaderIterator {
-        DataloaderIterator {
-            inputs: &self.inputs,
-            targets: &self.targets,
-            batch_size: self.batch_size,
-            index: 0,
-        }
+    /// Iterate over the batches of `num_epochs` consecutive epochs.
+    ///
+    /// Each epoch is shuffled differently, but deterministically for a seed.
+    pub fn epochs(&self, num_epochs: usize) -> Batches {
+        let (sender, receiver) = mpsc::sync_channel(PREFETCH_BATCHES);
+
+        let dataset = self.dataset.clone();
+        let (batch_size, seed, drop_last) = (self.batch_size, self.seed, self.drop_last);
+
+        thread::spawn(move || {
+            for epoch in 0..num_epochs {
+                let indices = indices(dataset.len(), batch_size, seed, drop_last, epoch);
+
+                for batch in indices.chunks(batch_size) {
+                    // Stop once the iterator was dropped, e.g. after 'max_steps'
+                    if sender.send(dataset.get_batch(batch)).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        Batches { receiver }
     }
 }
 
-pub struct DataloaderIterator<'a> {
-    inputs: &'a Tensor,
-    targets: &'a Tensor,
+/// Order of the examples of an epoch.
+fn indices(
+    len: usize,
     batch_size: usize,
-    index: usize,
+    seed: Option<u64>,
+    drop_last: bool,
+    epoch: usize,
+) -> Vec<u32> {
+    let mut indices = (0..len as u32).collect::<Vec<_>>();
+    if let Some(seed) = seed {
+        let mut rng = StdRng::seed_from_u64(seed.wrapping_add(epoch as u64));
+        indices.shuffle(&mut rng);
+    }
+
+    if drop_last {
+        indices.truncate(len - len % batch_size);
+    }
+
+    indices
+}
+
+/// Derive the shuffle seed of a round of a job.
+///
+/// The seed is stable across runs and platforms, so that rounds can be
+/// reproduced.
+pub fn shuffle_seed(job_id: &str, round: u64) -> u64 {
+    // FNV-1a
+    job_id
+        .bytes()
+        .chain(round.to_le_bytes())
+        .fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        })
+}
+
+/// Batches that are prefetched on a background thread.
+pub struct Batches {
+    receiver: Receiver<Result<(Tensor, Tensor), Error>>,
 }
 
-impl Iterator for DataloaderIterator<'_> {
-    type Item = (Tensor, Tensor);
+impl Iterator for Batches {
+    type Item = Result<(Tensor, Tensor), Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.index < self.inputs.dims()[0] {
-            let start = self.index;
-            let len = (self.batch_size).min(self.inputs.dims()[0] - start);
+        // The background thread closes the channel after the last batch
+        self.receiver.recv().ok()
+    }
+}
 
-            let inputs = self.inputs.narrow(0, start, len).unwrap();
-            let targets = self.targets.narrow(0, start, len).unwrap();
+#[cfg(test)]
+mod tests {
+    use candle_core::Device;
 
-            self.index = start + len;
+    use super::*;
+    use crate::ml::dataset::{DatasetInfo, TensorDataset};
 
-            Some((inputs, targets))
-        } else {
-            None
-        }
+    fn dataloader(seed: Option<u64>, drop_last: bool) -> Dataloader {
+        let inputs = Tensor::arange(0f32, 10., &Device::Cpu)
+            .unwrap()
+            .reshape((10, 1))
+            .unwrap();
+        let targets = Tensor::arange(0u32, 10, &Device::Cpu).unwrap();
+        let dataset = TensorDataset {
+            inputs,
+            targets,
+            info: DatasetInfo {
+                input_shape: vec![1],
+                num_classes: 10,
+                vocab_size: None,
+            },
+        };
+
+        Dataloader::new(Arc::new(dataset), 4, seed, drop_last)
+    }
+
+    fn targets(dataloader: &Dataloader, num_epochs: usize) -> Vec<Vec<u32>> {
+        dataloader
+            .epochs(num_epochs)
+            .map(|batch| {
+                let (inputs, targets) = batch.unwrap();
+                let targets = targets.to_vec1::<u32>().unwrap();
+
+                // Inputs and targets are batched together
+                let inputs = inputs.flatten_all().unwrap().to_vec1::<f32>().unwrap();
+                assert_eq!(
+                    inputs,
+                    targets.iter().map(|&t| t as f32).collect::<Vec<_>>()
+                );
+
+                targets
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_batches() {
+        assert_eq!(
+            targets(&dataloader(None, false), 1),
+            vec![vec![0, 1, 2, 3], vec![4, 5, 6, 7], vec![8, 9]]
+        );
+        assert_eq!(
+            targets(&dataloader(None, true), 1),
+            vec![vec![0, 1, 2, 3], vec![4, 5, 6, 7]]
+        );
+    }
+
+    #[test]
+    fn test_shuffle() {
+        let dataloader = dataloader(Some(shuffle_seed("job", 1)), false);
+
+        let batches = targets(&dataloader, 2);
+        let (epoch0, epoch1) = batches.split_at(3);
+
+        // Deterministic per epoch, different across epochs
+        assert_eq!(batches, targets(&dataloader, 2));
+        assert_ne!(epoch0, epoch1);
+
+        // Each example is seen once per epoch
+        let mut examples = epoch0.concat();
+        examples.sort();
+        assert_eq!(examples, (0..10).collect::<Vec<_>>());
+
+        // Different rounds are shuffled differently
+        assert_ne!(shuffle_seed("job", 1), shuffle_seed("job", 2));
+    }
+
+    #[test]
+    fn test_epochs() {
+        let dataloader = dataloader(Some(0), true);
+
+        assert_eq!(dataloader.epochs(3).count(), 6);
+
+        // Iteration can stop early
+        assert_eq!(dataloader.epochs(100).take(3).count(), 3);
     }
 }
@@ -0,0 +1,72 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use candle_core::{Device, Error};
+use tracing::info;
+
+use crate::ml::{
+    dataset::{Dataset, DatasetConfig, Subset},
+    prepare_data,
+};
+
+/// Datasets of the worker, loaded once and reused across rounds and jobs.
+///
+/// Datasets are kept until the worker exits. The worker trains on a single
+/// device, so datasets are keyed by their configuration only.
+#[derive(Default)]
+pub struct DatasetCache {
+    datasets: Mutex<HashMap<DatasetConfig, Arc<dyn Dataset>>>,
+}
+
+impl DatasetCache {
+    /// Get the dataset of a configuration, loading it if it isn't cached yet.
+    ///
+    /// The cache stays locked while loading, so that concurrent requests
+    /// don't load the same dataset twice.
+    pub fn get(&self, dev: &Device, config: &DatasetConfig) -> Result<Arc<dyn Dataset>, Error> {
+        let mut datasets = self.datasets.lock().unwrap();
+
+        load(&mut datasets, dev, config)
+    }
+}
+
+fn load(
+    datasets: &mut HashMap<DatasetConfig, Arc<dyn Dataset>>,
+    dev: &Device,
+    config: &DatasetConfig,
+) -> Result<Arc<dyn Dataset>, Error> {
+    if let Some(dataset) = datasets.get(config) {
+        return Ok(dataset.clone());
+    }
+
+    let dataset: Arc<dyn Dataset> = match &config.partition {
+        // Partitions share the dataset they are taken from
+        Some(partition) => {
+            let full_config = DatasetConfig {
+                source: config.source.clone(),
+                partition: None,
+            };
+            let dataset = load(datasets, dev, &full_config)?;
+            let indices = partition.indices(&dataset.labels()?)?;
+
+            Arc::new(Subset::new(dataset, indices))
+        }
+        None => {
+            info!(source = ?config.source, "loading dataset");
+
+            prepare_data(dev, &config.source)?
+        }
+    };
+
+    if dataset.is_empty() {
+        return Err(Error::Msg("local dataset is empty".to_string()));
+    }
+
+    info!(config = ?config, len = dataset.len(), "loaded dataset");
+
+    datasets.insert(config.clone(), dataset.clone());
+
+    Ok(dataset)
+}
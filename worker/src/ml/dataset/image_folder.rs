@@ -0,0 +1,199 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use candle_core::{DType, Device, Error, Tensor};
+use image::imageops::FilterType;
+
+use crate::ml::dataset::{Dataset, DatasetInfo};
+
+const EXTENSIONS: [&str; 3] = ["png", "jpg", "jpeg"];
+
+/// Configuration of an image dataset with one directory per class.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ImageFolderConfig {
+    pub path: PathBuf,
+    /// Images are resized to `height` x `width`.
+    pub height: usize,
+    pub width: usize,
+    /// Convert images to a single channel instead of RGB.
+    pub grayscale: bool,
+    /// Scale pixels to [-1, 1] instead of [0, 1].
+    pub normalize: bool,
+    /// Class directories, in the order of class indices.
+    ///
+    /// Workers need to agree on classes to train the same model. If they
+    /// aren't provided, the sorted subdirectories of `path` are used.
+    pub classes: Vec<String>,
+}
+
+/// Images that are decoded and preprocessed when they are batched.
+#[derive(Clone, Debug)]
+pub struct ImageFolder {
+    paths: Vec<PathBuf>,
+    labels: Vec<u32>,
+    config: ImageFolderConfig,
+    info: DatasetInfo,
+}
+
+impl ImageFolder {
+    /// Load the images at `indices`, flattened to one row per image.
+    fn load(&self, indices: &[u32]) -> Result<Tensor, Error> {
+        let [channels, height, width] = self.info.input_shape[..] else {
+            unreachable!()
+        };
+        let len = indices.len();
+
+        let mut pixels = Vec::with_capacity(len * channels * height * width);
+        for path in indices.iter().map(|&index| &self.paths[index as usize]) {
+            let image = image::open(path)
+                .map_err(|e| Error::Msg(format!("failed to decode {}: {e}", path.display())))?
+                .resize_exact(width as u32, height as u32, FilterType::Triangle);
+
+            // Pixels are stored as (height, width, channels)
+            if self.config.grayscale {
+                pixels.extend(image.to_luma8().into_raw());
+            } else {
+                pixels.extend(image.to_rgb8().into_raw());
+            }
+        }
+
+        let images = Tensor::from_vec(pixels, (len, height, width, channels), &Device::Cpu)?
+            .permute((0, 3, 1, 2))?
+            .to_dtype(DType::F32)?;
+        let images = if self.config.normalize {
+            ((images / 127.5)? - 1.)?
+        } else {
+            (images / 255.)?
+        };
+
+        images.flatten_from(1)
+    }
+}
+
+impl Dataset for ImageFolder {
+    fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    fn info(&self) -> &DatasetInfo {
+        &self.info
+    }
+
+    fn labels(&self) -> Result<Vec<u32>, Error> {
+        Ok(self.labels.clone())
+    }
+
+    fn get_batch(&self, indices: &[u32]) -> Result<(Tensor, Tensor), Error> {
+        let labels = indices
+            .iter()
+            .map(|&index| self.labels[index as usize])
+            .collect::<Vec<_>>();
+
+        Ok((self.load(indices)?, Tensor::new(labels, &Device::Cpu)?))
+    }
+}
+
+/// List the images of a directory per class, with their labels.
+pub fn load(config: &ImageFolderConfig) -> Result<ImageFolder, Error> {
+    let classes = if config.classes.is_empty() {
+        let mut classes = fs::read_dir(&config.path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .filter(|path| path.is_dir())
+            .filter_map(|path| path.file_name()?.to_str().map(str::to_string))
+            .collect::<Vec<_>>();
+        classes.sort();
+        classes
+    } else {
+        config.classes.clone()
+    };
+
+    let mut paths = Vec::new();
+    let mut labels = Vec::new();
+    for (label, class) in classes.iter().enumerate() {
+        let images = images(&config.path.join(class))?;
+        labels.resize(labels.len() + images.len(), label as u32);
+        paths.extend(images);
+    }
+
+    if paths.is_empty() {
+        return Err(Error::Msg(format!(
+            "no images in {}",
+            config.path.display()
+        )));
+    }
+
+    let channels = if config.grayscale { 1 } else { 3 };
+
+    Ok(ImageFolder {
+        paths,
+        labels,
+        config: config.clone(),
+        info: DatasetInfo {
+            input_shape: vec![channels, config.height, config.width],
+            num_classes: classes.len(),
+            vocab_size: None,
+        },
+    })
+}
+
+/// Sorted paths of the images in a directory.
+fn images(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut paths = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .filter(|path| {
+            path.extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+        })
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    #[test]
+    fn test_image_folder() {
+        let dir = std::env::temp_dir().join(format!("image-folder-{}", std::process::id()));
+        for (class, color) in [("black", [0, 0, 0]), ("white", [255, 255, 255])] {
+            fs::create_dir_all(dir.join(class)).unwrap();
+            RgbImage::from_pixel(8, 6, Rgb(color))
+                .save(dir.join(class).join("0.png"))
+                .unwrap();
+        }
+
+        let config = ImageFolderConfig {
+            path: dir.clone(),
+            height: 4,
+            width: 4,
+            grayscale: true,
+            ..Default::default()
+        };
+        let dataset = load(&config).unwrap();
+
+        assert_eq!(dataset.len(), 2);
+        assert_eq!(dataset.info().input_shape, vec![1, 4, 4]);
+        assert_eq!(dataset.info().num_classes, 2);
+
+        // Images are only decoded when they are batched
+        let (images, labels) = dataset.get_batch(&[1, 0]).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            images.to_vec2::<f32>().unwrap(),
+            vec![vec![1.0; 16], vec![0.0; 16]]
+        );
+        assert_eq!(labels.to_vec1::<u32>().unwrap(), vec![1, 0]);
+    }
+}
@@ -0,0 +1,31 @@
+use std::path::Path;
+
+use candle_core::Error;
+use protocol::mnist::{self, Mnist};
+
+use crate::ml::dataset::{DatasetInfo, TensorDataset};
+
+const NUM_CLASSES: usize = 10;
+
+/// Load the MNIST training split from the IDX files in `dir`.
+///
+/// Files may be uncompressed, e.g. `train-images-idx3-ubyte`, or compressed
+/// with gzip, e.g. `train-images-idx3-ubyte.gz`.
+pub fn load_dir(dir: &Path) -> Result<TensorDataset, Error> {
+    let Mnist {
+        images,
+        labels,
+        height,
+        width,
+    } = mnist::load_dir(dir, "train")?;
+
+    Ok(TensorDataset {
+        inputs: images,
+        targets: labels,
+        info: DatasetInfo {
+            input_shape: vec![1, height, width],
+            num_classes: NUM_CLASSES,
+            vocab_size: None,
+        },
+    })
+}
@@ -0,0 +1,106 @@
+use std::path::PathBuf;
+
+use candle_core::{DType, Device, Error, Tensor};
+
+pub use cache::DatasetCache;
+pub use image_folder::ImageFolderConfig;
+pub use partition::{PartitionConfig, Partitioning, Subset};
+pub use tabular::{FeatureStats, Normalization, TabularConfig};
+
+mod cache;
+pub mod image_folder;
+pub mod mnist;
+mod partition;
+pub mod tabular;
+
+/// Local training data of a worker.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct DatasetConfig {
+    pub source: DataSource,
+    /// Train on a partition of the dataset only, to simulate workers with
+    /// different data.
+    pub partition: Option<PartitionConfig>,
+}
+
+/// Where a worker reads its local training data from.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum DataSource {
+    /// MNIST, from IDX files in a directory or downloaded from the Hugging Face hub.
+    Mnist { data_dir: Option<PathBuf> },
+    /// CSV or Parquet file.
+    Tabular(TabularConfig),
+    /// Directory of images with a subdirectory per class.
+    ImageFolder(ImageFolderConfig),
+}
+
+/// Metadata of a dataset that determines the shapes of models trained on it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DatasetInfo {
+    /// Shape of a single example, e.g. `[1, 28, 28]` for grayscale images.
+    pub input_shape: Vec<usize>,
+    pub num_classes: usize,
+    /// Size of the vocabulary, if examples are sequences of token IDs.
+    pub vocab_size: Option<usize>,
+}
+
+/// Local training data that is batched by the `Dataloader`.
+///
+/// Datasets may be held in memory or read from disk when they are batched.
+pub trait Dataset: Send + Sync {
+    /// Number of examples.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn info(&self) -> &DatasetInfo;
+
+    /// Class labels of all examples.
+    fn labels(&self) -> Result<Vec<u32>, Error>;
+
+    /// Collate the examples at `indices` into inputs, flattened to one row per
+    /// example, and targets.
+    fn get_batch(&self, indices: &[u32]) -> Result<(Tensor, Tensor), Error>;
+}
+
+/// Dataset held in memory.
+pub struct TensorDataset {
+    /// Examples, flattened to one row per example.
+    pub inputs: Tensor,
+    pub targets: Tensor,
+    pub info: DatasetInfo,
+}
+
+impl TensorDataset {
+    pub fn to_device(&self, dev: &Device) -> Result<Self, Error> {
+        Ok(Self {
+            inputs: self.inputs.to_device(dev)?,
+            targets: self.targets.to_device(dev)?,
+            info: self.info.clone(),
+        })
+    }
+}
+
+impl Dataset for TensorDataset {
+    fn len(&self) -> usize {
+        self.targets.dims()[0]
+    }
+
+    fn info(&self) -> &DatasetInfo {
+        &self.info
+    }
+
+    fn labels(&self) -> Result<Vec<u32>, Error> {
+        self.targets.to_dtype(DType::U32)?.to_vec1()
+    }
+
+    fn get_batch(&self, indices: &[u32]) -> Result<(Tensor, Tensor), Error> {
+        let indices = Tensor::new(indices, self.targets.device())?;
+
+        Ok((
+            self.inputs.index_select(&indices, 0)?,
+            self.targets.index_select(&indices, 0)?,
+        ))
+    }
+}
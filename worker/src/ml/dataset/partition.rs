@@ -0,0 +1,296 @@
+use std::{
+    hash::{Hash, Hasher},
+    sync::Arc,
+};
+
+use candle_core::{Error, Tensor};
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use rand_distr::{Distribution, Gamma};
+
+use crate::ml::dataset::{Dataset, DatasetInfo};
+
+/// How a dataset is split across simulated workers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Partitioning {
+    /// Equally sized partitions of shuffled examples.
+    Iid,
+    /// Label skew: class proportions of each partition are drawn from a
+    /// Dirichlet distribution. Smaller `alpha` gives more skewed partitions.
+    Dirichlet { alpha: f64 },
+    /// Examples are sorted by label and split into shards, each partition gets
+    /// `classes_per_partition` shards.
+    Pathological { classes_per_partition: usize },
+    /// Quantity skew: partition sizes are drawn from a Dirichlet distribution,
+    /// labels are distributed evenly.
+    Quantity { alpha: f64 },
+}
+
+// Alphas are positive and finite, see `PartitionConfig::validate`
+impl Eq for Partitioning {}
+
+impl Hash for Partitioning {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Partitioning::Iid => {}
+            Partitioning::Dirichlet { alpha } | Partitioning::Quantity { alpha } => {
+                alpha.to_bits().hash(state)
+            }
+            Partitioning::Pathological {
+                classes_per_partition,
+            } => classes_per_partition.hash(state),
+        }
+    }
+}
+
+/// Partition of a dataset that a worker trains on.
+///
+/// Workers with the same dataset, partitioning and seed compute the same
+/// partitions and train on the one at their `index`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PartitionConfig {
+    pub partitioning: Partitioning,
+    pub num_partitions: usize,
+    pub index: usize,
+    pub seed: u64,
+}
+
+impl PartitionConfig {
+    fn validate(&self) -> Result<(), Error> {
+        if self.index >= self.num_partitions {
+            return Err(Error::Msg(format!(
+                "partition index {} must be less than the number of partitions {}",
+                self.index, self.num_partitions
+            )));
+        }
+
+        match self.partitioning {
+            Partitioning::Dirichlet { alpha } | Partitioning::Quantity { alpha }
+                if !(alpha.is_finite() && alpha > 0.0) =>
+            {
+                Err(Error::Msg(format!("alpha {alpha} must be positive")))
+            }
+            Partitioning::Pathological {
+                classes_per_partition: 0,
+            } => Err(Error::Msg(
+                "classes per partition must be positive".to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Indices of the examples in the partition of this worker.
+    pub fn indices(&self, labels: &[u32]) -> Result<Vec<u32>, Error> {
+        self.validate()?;
+
+        let mut partitions = partitions(labels, self.num_partitions, self.partitioning, self.seed);
+
+        Ok(partitions.swap_remove(self.index))
+    }
+}
+
+/// Split the examples with `labels` into `num_partitions` partitions.
+fn partitions(
+    labels: &[u32],
+    num_partitions: usize,
+    partitioning: Partitioning,
+    seed: u64,
+) -> Vec<Vec<u32>> {
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    let mut indices = (0..labels.len() as u32).collect::<Vec<_>>();
+    indices.shuffle(&mut rng);
+
+    match partitioning {
+        Partitioning::Iid => split(&indices, &vec![1.0; num_partitions]),
+        Partitioning::Dirichlet { alpha } => {
+            let num_classes = labels.iter().max().map_or(0, |&max| max as usize + 1);
+
+            let mut partitions = vec![Vec::new(); num_partitions];
+            for class in 0..num_classes as u32 {
+                let class_indices = indices
+                    .iter()
+                    .copied()
+                    .filter(|&index| labels[index as usize] == class)
+                    .collect::<Vec<_>>();
+
+                let proportions = dirichlet(alpha, num_partitions, &mut rng);
+                for (partition, split) in partitions
+                    .iter_mut()
+                    .zip(split(&class_indices, &proportions))
+                {
+                    partition.extend(split);
+                }
+            }
+
+            partitions
+        }
+        Partitioning::Pathological {
+            classes_per_partition,
+        } => {
+            // Sort by label, shuffled within each label
+            indices.sort_by_key(|&index| labels[index as usize]);
+
+            let mut shards = split(&indices, &vec![1.0; num_partitions * classes_per_partition]);
+            shards.shuffle(&mut rng);
+
+            shards
+                .chunks(classes_per_partition)
+                .map(|shards| shards.concat())
+                .collect()
+        }
+        Partitioning::Quantity { alpha } => {
+            split(&indices, &dirichlet(alpha, num_partitions, &mut rng))
+        }
+    }
+}
+
+/// Split `indices` into consecutive parts of the given relative sizes.
+fn split(indices: &[u32], proportions: &[f64]) -> Vec<Vec<u32>> {
+    let total = proportions.iter().sum::<f64>();
+
+    let mut parts = Vec::with_capacity(proportions.len());
+    let mut start = 0;
+    let mut cumulative = 0.0;
+    for proportion in proportions {
+        cumulative += proportion;
+        let end = ((cumulative / total) * indices.len() as f64).round() as usize;
+        let end = end.clamp(start, indices.len());
+
+        parts.push(indices[start..end].to_vec());
+        start = end;
+    }
+
+    parts
+}
+
+/// Sample from a symmetric Dirichlet distribution.
+fn dirichlet(alpha: f64, size: usize, rng: &mut StdRng) -> Vec<f64> {
+    let gamma = Gamma::new(alpha, 1.0).unwrap();
+
+    let samples = (0..size).map(|_| gamma.sample(rng)).collect::<Vec<_>>();
+    let total = samples.iter().sum::<f64>();
+
+    // Very small alphas may underflow all samples
+    if total > 0.0 {
+        samples.into_iter().map(|sample| sample / total).collect()
+    } else {
+        let mut samples = vec![0.0; size];
+        samples[0] = 1.0;
+        samples.shuffle(rng);
+        samples
+    }
+}
+
+/// Examples of a dataset at the given indices.
+pub struct Subset {
+    dataset: Arc<dyn Dataset>,
+    indices: Vec<u32>,
+}
+
+impl Subset {
+    pub fn new(dataset: Arc<dyn Dataset>, indices: Vec<u32>) -> Self {
+        Self { dataset, indices }
+    }
+}
+
+impl Dataset for Subset {
+    fn len(&self) -> usize {
+        self.indices.len()
+    }
+
+    fn info(&self) -> &DatasetInfo {
+        self.dataset.info()
+    }
+
+    fn labels(&self) -> Result<Vec<u32>, Error> {
+        let labels = self.dataset.labels()?;
+
+        Ok(self
+            .indices
+            .iter()
+            .map(|&index| labels[index as usize])
+            .collect())
+    }
+
+    fn get_batch(&self, indices: &[u32]) -> Result<(Tensor, Tensor), Error> {
+        let indices = indices
+            .iter()
+            .map(|&index| self.indices[index as usize])
+            .collect::<Vec<_>>();
+
+        self.dataset.get_batch(&indices)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn labels() -> Vec<u32> {
+        (0..1000).map(|i| i % 10).collect()
+    }
+
+    fn assert_disjoint_cover(partitions: &[Vec<u32>], len: usize) {
+        let mut indices = partitions.concat();
+        indices.sort();
+        assert_eq!(indices, (0..len as u32).collect::<Vec<_>>());
+    }
+
+    fn classes(labels: &[u32], partition: &[u32]) -> HashSet<u32> {
+        partition
+            .iter()
+            .map(|&index| labels[index as usize])
+            .collect()
+    }
+
+    #[test]
+    fn test_partitions() {
+        let labels = labels();
+
+        let iid = partitions(&labels, 4, Partitioning::Iid, 0);
+        assert_disjoint_cover(&iid, labels.len());
+        assert!(iid.iter().all(|partition| partition.len() == 250));
+
+        let dirichlet = partitions(&labels, 4, Partitioning::Dirichlet { alpha: 0.1 }, 0);
+        assert_disjoint_cover(&dirichlet, labels.len());
+
+        let pathological = partitions(
+            &labels,
+            5,
+            Partitioning::Pathological {
+                classes_per_partition: 2,
+            },
+            0,
+        );
+        assert_disjoint_cover(&pathological, labels.len());
+        assert!(pathological
+            .iter()
+            .all(|partition| classes(&labels, partition).len() <= 2));
+
+        let quantity = partitions(&labels, 4, Partitioning::Quantity { alpha: 1.0 }, 0);
+        assert_disjoint_cover(&quantity, labels.len());
+    }
+
+    #[test]
+    fn test_partition_config() {
+        let labels = labels();
+        let config = PartitionConfig {
+            partitioning: Partitioning::Dirichlet { alpha: 0.5 },
+            num_partitions: 3,
+            index: 1,
+            seed: 42,
+        };
+
+        // Workers compute the same partitions
+        assert_eq!(
+            config.indices(&labels).unwrap(),
+            config.indices(&labels).unwrap()
+        );
+
+        let config = PartitionConfig { index: 3, ..config };
+        assert!(config.indices(&labels).is_err());
+    }
+}
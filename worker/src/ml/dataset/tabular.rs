@@ -0,0 +1,461 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    hash::{Hash, Hasher},
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use candle_core::{Device, Error, Tensor};
+use parquet::{
+    file::reader::{FileReader, SerializedFileReader},
+    record::Field,
+};
+
+use crate::ml::dataset::{DatasetInfo, TensorDataset};
+
+/// Normalization of numeric feature columns, using configured statistics.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, clap::ValueEnum)]
+pub enum Normalization {
+    #[default]
+    None,
+    /// Zero mean and unit variance.
+    Standard,
+    /// Scale to the range [0, 1].
+    MinMax,
+}
+
+/// Statistics of a numeric feature column that it is normalized with, the
+/// mean and standard deviation for `Standard` and the minimum and maximum for
+/// `MinMax`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FeatureStats(pub f64, pub f64);
+
+// Statistics are part of the configuration that identifies a cached dataset
+impl Eq for FeatureStats {}
+
+impl Hash for FeatureStats {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.to_bits().hash(state);
+        self.1.to_bits().hash(state);
+    }
+}
+
+/// Configuration of a tabular dataset in a CSV or Parquet file.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct TabularConfig {
+    pub path: PathBuf,
+    /// Feature columns, all columns except the label column if empty.
+    pub feature_columns: Vec<String>,
+    pub label_column: String,
+    /// Feature columns that are one-hot encoded.
+    pub categorical_columns: Vec<String>,
+    /// Categories of categorical columns and of the label column.
+    ///
+    /// Workers need to agree on categories to train the same model. If they
+    /// aren't provided, the sorted distinct values of the local data are used.
+    pub categories: BTreeMap<String, Vec<String>>,
+    /// Number of classes of a label column with class indices.
+    ///
+    /// Workers need to agree on it, as local data may not contain all classes.
+    pub num_classes: Option<usize>,
+    /// Size of the vocabulary if feature columns are token IDs, e.g. of
+    /// tokenized text for the "transformer" model.
+    pub vocab_size: Option<usize>,
+    pub normalization: Normalization,
+    /// Statistics of numeric feature columns, which normalization requires.
+    ///
+    /// Workers need to agree on them, so that the same value is normalized
+    /// the same way on all workers, which statistics of local data aren't.
+    pub feature_stats: BTreeMap<String, FeatureStats>,
+}
+
+/// Load a CSV or Parquet file, depending on its extension.
+pub fn load(config: &TabularConfig) -> Result<TensorDataset, Error> {
+    let table = match config.path.extension().and_then(|ext| ext.to_str()) {
+        Some("csv") => read_csv(File::open(&config.path)?)?,
+        Some("parquet") => read_parquet(&config.path)?,
+        _ => {
+            return Err(Error::Msg(format!(
+                "unsupported tabular file {}, expected .csv or .parquet",
+                config.path.display()
+            )))
+        }
+    };
+
+    encode(&table, config)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Cell {
+    Number(f64),
+    Text(String),
+    Missing,
+}
+
+impl Cell {
+    fn category(&self) -> Option<String> {
+        match self {
+            Cell::Number(value) => Some(value.to_string()),
+            Cell::Text(value) => Some(value.clone()),
+            Cell::Missing => None,
+        }
+    }
+}
+
+struct Table {
+    columns: Vec<String>,
+    rows: Vec<Vec<Cell>>,
+}
+
+impl Table {
+    fn column(&self, name: &str) -> Result<impl Iterator<Item = &Cell>, Error> {
+        let index = self
+            .columns
+            .iter()
+            .position(|column| column == name)
+            .ok_or_else(|| Error::Msg(format!("missing column {name}")))?;
+
+        Ok(self.rows.iter().map(move |row| &row[index]))
+    }
+}
+
+fn read_csv(reader: impl Read) -> Result<Table, Error> {
+    let mut reader = csv::Reader::from_reader(reader);
+
+    let columns = reader
+        .headers()
+        .map_err(Error::wrap)?
+        .iter()
+        .map(str::to_string)
+        .collect();
+    let rows = reader
+        .records()
+        .map(|record| {
+            Ok(record
+                .map_err(Error::wrap)?
+                .iter()
+                .map(|value| match value.trim() {
+                    "" => Cell::Missing,
+                    value => value
+                        .parse()
+                        .map_or_else(|_| Cell::Text(value.to_string()), Cell::Number),
+                })
+                .collect())
+        })
+        .collect::<Result<_, Error>>()?;
+
+    Ok(Table { columns, rows })
+}
+
+fn read_parquet(path: &Path) -> Result<Table, Error> {
+    let reader = SerializedFileReader::new(File::open(path)?).map_err(Error::wrap)?;
+
+    let columns = reader
+        .metadata()
+        .file_metadata()
+        .schema_descr()
+        .root_schema()
+        .get_fields()
+        .iter()
+        .map(|field| field.name().to_string())
+        .collect();
+    let rows = reader
+        .get_row_iter(None)
+        .map_err(Error::wrap)?
+        .map(|row| {
+            row.map_err(Error::wrap)?
+                .get_column_iter()
+                .map(|(name, field)| parquet_cell(name, field))
+                .collect()
+        })
+        .collect::<Result<_, Error>>()?;
+
+    Ok(Table { columns, rows })
+}
+
+fn parquet_cell(name: &str, field: &Field) -> Result<Cell, Error> {
+    let cell = match field {
+        Field::Null => Cell::Missing,
+        Field::Bool(value) => Cell::Number(*value as u8 as f64),
+        Field::Byte(value) => Cell::Number(*value as f64),
+        Field::Short(value) => Cell::Number(*value as f64),
+        Field::Int(value) => Cell::Number(*value as f64),
+        Field::Long(value) => Cell::Number(*value as f64),
+        Field::UByte(value) => Cell::Number(*value as f64),
+        Field::UShort(value) => Cell::Number(*value as f64),
+        Field::UInt(value) => Cell::Number(*value as f64),
+        Field::ULong(value) => Cell::Number(*value as f64),
+        Field::Float(value) => Cell::Number(*value as f64),
+        Field::Double(value) => Cell::Number(*value),
+        Field::Str(value) => Cell::Text(value.clone()),
+        _ => {
+            return Err(Error::Msg(format!(
+                "unsupported value {field} in column {name}"
+            )))
+        }
+    };
+
+    Ok(cell)
+}
+
+/// Encode a table into inputs and targets.
+///
+/// Numeric features are normalized, categorical features are one-hot encoded
+/// and labels are encoded as class indices. With a vocabulary size, features
+/// are token IDs instead, which are kept as they are.
+fn encode(table: &Table, config: &TabularConfig) -> Result<TensorDataset, Error> {
+    if config.vocab_size.is_some()
+        && (!config.categorical_columns.is_empty() || config.normalization != Normalization::None)
+    {
+        return Err(Error::Msg(
+            "token ID columns can't be categorical or normalized".to_string(),
+        ));
+    }
+
+    let feature_columns = if config.feature_columns.is_empty() {
+        table
+            .columns
+            .iter()
+            .filter(|column| **column != config.label_column)
+            .cloned()
+            .collect()
+    } else {
+        config.feature_columns.clone()
+    };
+
+    let num_rows = table.rows.len();
+    let mut features = Vec::with_capacity(feature_columns.len());
+    for column in feature_columns.iter() {
+        if config.categorical_columns.contains(column) {
+            let categories = categories(table, column, config)?;
+            for category in categories.iter() {
+                features.push(
+                    table
+                        .column(column)?
+                        .map(|cell| (cell.category().as_ref() == Some(category)) as u8 as f32)
+                        .collect::<Vec<_>>(),
+                );
+            }
+        } else if let Some(vocab_size) = config.vocab_size {
+            features.push(token_ids(table, column, vocab_size)?);
+        } else {
+            let values = numeric(table, column)?;
+            let stats = config.feature_stats.get(column).copied();
+            features.push(normalize(&values, config.normalization, stats, column)?);
+        }
+    }
+
+    // Labels are categorical if they aren't class indices already
+    let labels = table.column(&config.label_column)?.collect::<Vec<_>>();
+    let (targets, num_classes) = if !config.categories.contains_key(&config.label_column)
+        && labels
+            .iter()
+            .all(|cell| matches!(cell, Cell::Number(value) if value.fract() == 0. && *value >= 0.))
+    {
+        let num_classes = config.num_classes.ok_or_else(|| {
+            Error::Msg(format!(
+                "label column {} contains class indices, which requires the number of classes",
+                config.label_column
+            ))
+        })?;
+        let targets = labels
+            .iter()
+            .map(|cell| match cell {
+                Cell::Number(value) if (*value as usize) < num_classes => Ok(*value as u32),
+                Cell::Number(value) => Err(Error::Msg(format!(
+                    "label {value} out of range for {num_classes} classes"
+                ))),
+                _ => unreachable!(),
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        (targets, num_classes)
+    } else {
+        let categories = categories(table, &config.label_column, config)?;
+        let targets = labels
+            .iter()
+            .map(|cell| {
+                let category = cell.category().ok_or_else(|| {
+                    Error::Msg(format!("missing label in column {}", config.label_column))
+                })?;
+                categories
+                    .iter()
+                    .position(|c| *c == category)
+                    .map(|index| index as u32)
+                    .ok_or_else(|| Error::Msg(format!("unknown label {category}")))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        (targets, categories.len())
+    };
+
+    // Features are stored by column, examples are stored by row
+    let num_features = features.len();
+    let inputs = (0..num_rows)
+        .flat_map(|row| features.iter().map(move |column| column[row]))
+        .collect::<Vec<_>>();
+
+    Ok(TensorDataset {
+        inputs: Tensor::from_vec(inputs, (num_rows, num_features), &Device::Cpu)?,
+        targets: Tensor::from_vec(targets, num_rows, &Device::Cpu)?,
+        info: DatasetInfo {
+            input_shape: vec![num_features],
+            num_classes,
+            vocab_size: config.vocab_size,
+        },
+    })
+}
+
+/// Categories of a column, either configured or the sorted distinct local values.
+fn categories(table: &Table, column: &str, config: &TabularConfig) -> Result<Vec<String>, Error> {
+    if let Some(categories) = config.categories.get(column) {
+        // Values outside of the configured categories are a configuration error
+        for category in table.column(column)?.filter_map(Cell::category) {
+            if !categories.contains(&category) {
+                return Err(Error::Msg(format!(
+                    "unknown category {category} in column {column}"
+                )));
+            }
+        }
+
+        return Ok(categories.clone());
+    }
+
+    Ok(table
+        .column(column)?
+        .filter_map(Cell::category)
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect())
+}
+
+fn numeric(table: &Table, column: &str) -> Result<Vec<f64>, Error> {
+    table
+        .column(column)?
+        .enumerate()
+        .map(|(row, cell)| match cell {
+            Cell::Number(value) => Ok(*value),
+            Cell::Text(value) => Err(Error::Msg(format!(
+                "non-numeric value {value} in row {row} of column {column}, is it categorical?"
+            ))),
+            Cell::Missing => Err(Error::Msg(format!(
+                "missing value in row {row} of column {column}"
+            ))),
+        })
+        .collect()
+}
+
+/// Token IDs of a column, which need to be within the vocabulary.
+fn token_ids(table: &Table, column: &str, vocab_size: usize) -> Result<Vec<f32>, Error> {
+    numeric(table, column)?
+        .into_iter()
+        .enumerate()
+        .map(|(row, value)| {
+            if value.fract() == 0. && value >= 0. && (value as usize) < vocab_size {
+                Ok(value as f32)
+            } else {
+                Err(Error::Msg(format!(
+                    "token ID {value} in row {row} of column {column} out of range for vocabulary size {vocab_size}"
+                )))
+            }
+        })
+        .collect()
+}
+
+fn normalize(
+    values: &[f64],
+    normalization: Normalization,
+    stats: Option<FeatureStats>,
+    column: &str,
+) -> Result<Vec<f32>, Error> {
+    let (offset, scale) = match (normalization, stats) {
+        (Normalization::None, _) => (0., 1.),
+        (Normalization::Standard, Some(FeatureStats(mean, std))) => (mean, std),
+        (Normalization::MinMax, Some(FeatureStats(min, max))) => (min, max - min),
+        (_, None) => {
+            return Err(Error::Msg(format!(
+                "normalization of column {column} requires its statistics"
+            )))
+        }
+    };
+
+    // Constant columns are only shifted
+    let scale = if scale > 0. { scale } else { 1. };
+
+    Ok(values
+        .iter()
+        .map(|value| ((value - offset) / scale) as f32)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_csv() {
+        let data = "age,color,species\n20,red,cat\n30,blue,dog\n40,red,cat\n";
+        let table = read_csv(data.as_bytes()).unwrap();
+
+        // Statistics aren't derived from the local data
+        let config = TabularConfig {
+            label_column: "species".to_string(),
+            categorical_columns: vec!["color".to_string()],
+            normalization: Normalization::MinMax,
+            ..Default::default()
+        };
+        assert!(encode(&table, &config).is_err());
+
+        let config = TabularConfig {
+            feature_stats: BTreeMap::from([("age".to_string(), FeatureStats(20., 40.))]),
+            ..config
+        };
+        let dataset = encode(&table, &config).unwrap();
+
+        // Age is scaled, colors are one-hot encoded in sorted order
+        assert_eq!(
+            dataset.inputs.to_vec2::<f32>().unwrap(),
+            vec![
+                vec![0.0, 0.0, 1.0],
+                vec![0.5, 1.0, 0.0],
+                vec![1.0, 0.0, 1.0]
+            ]
+        );
+        assert_eq!(dataset.targets.to_vec1::<u32>().unwrap(), vec![0, 1, 0]);
+        assert_eq!(dataset.info.input_shape, vec![3]);
+        assert_eq!(dataset.info.num_classes, 2);
+
+        // Non-numeric features need to be declared categorical
+        let config = TabularConfig {
+            label_column: "species".to_string(),
+            ..Default::default()
+        };
+        assert!(encode(&table, &config).is_err());
+    }
+
+    #[test]
+    fn test_encode_class_indices() {
+        let data = "age,label\n20,0\n30,2\n";
+        let table = read_csv(data.as_bytes()).unwrap();
+
+        // The number of classes isn't derived from the local labels
+        let config = TabularConfig {
+            label_column: "label".to_string(),
+            ..Default::default()
+        };
+        assert!(encode(&table, &config).is_err());
+
+        let config = TabularConfig {
+            num_classes: Some(5),
+            ..config
+        };
+        let dataset = encode(&table, &config).unwrap();
+        assert_eq!(dataset.targets.to_vec1::<u32>().unwrap(), vec![0, 2]);
+        assert_eq!(dataset.info.num_classes, 5);
+
+        let config = TabularConfig {
+            num_classes: Some(2),
+            ..config
+        };
+        assert!(encode(&table, &config).is_err());
+    }
+}
@@ -1,64 +1,187 @@
-use candle_core::{safetensors::Load, DType, Device, Error, D};
-use candle_nn::{loss, ops, Optimizer, VarBuilder, VarMap, SGD};
-use safetensors::SafeTensors;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use candle_core::{DType, Device, Error, Tensor};
+use candle_nn::{VarBuilder, VarMap};
 use tracing::info;
 
+use crate::candlefl::ModelSpec;
+use crate::ml::config::ModelConfig;
 use crate::ml::dataloader::Dataloader;
-use crate::ml::model::Model;
-
+use crate::ml::dataset::{Dataset, DatasetInfo, TensorDataset};
+use crate::ml::model::FederatedModel;
+use crate::ml::optimizer::LocalOptimizer;
+
+pub use config::TrainConfig;
+pub use dataloader::shuffle_seed;
+pub use dataset::{
+    DataSource, DatasetCache, DatasetConfig, ImageFolderConfig, Normalization, PartitionConfig,
+    Partitioning, TabularConfig,
+};
+pub use model::ModelRegistry;
+pub use session::{Session, Sessions};
+
+mod config;
 mod dataloader;
+mod dataset;
 mod model;
+mod optimizer;
+mod session;
+
+/// Load the local training data.
+///
+/// Datasets held in memory are moved to `dev`, batches of datasets read from
+/// disk are moved to `dev` during training.
+pub fn prepare_data(dev: &Device, source: &DataSource) -> Result<Arc<dyn Dataset>, Error> {
+    let dataset: Arc<dyn Dataset> = match source {
+        DataSource::Mnist { data_dir: None } => Arc::new(download_mnist()?.to_device(dev)?),
+        DataSource::Mnist {
+            data_dir: Some(data_dir),
+        } => Arc::new(dataset::mnist::load_dir(data_dir)?.to_device(dev)?),
+        DataSource::Tabular(config) => Arc::new(dataset::tabular::load(config)?.to_device(dev)?),
+        DataSource::ImageFolder(config) => Arc::new(dataset::image_folder::load(config)?),
+    };
+
+    Ok(dataset)
+}
 
-pub fn prepare_data(dev: &Device) -> Result<Dataloader, Error> {
+fn download_mnist() -> Result<TensorDataset, Error> {
     let dataset = candle_datasets::vision::mnist::load()?;
 
-    let inputs = dataset.train_images.to_device(dev)?;
-    let targets = dataset.train_labels.to_device(dev)?;
-
-    Ok(Dataloader::new(inputs, targets, 32))
+    Ok(TensorDataset {
+        inputs: dataset.train_images,
+        targets: dataset.train_labels,
+        info: DatasetInfo {
+            input_shape: vec![1, 28, 28],
+            num_classes: dataset.labels,
+            vocab_size: None,
+        },
+    })
 }
 
-pub fn prepare_model(dev: &Device) -> Result<(VarMap, Model), Error> {
+/// Build the model announced by the coordinator, checking that the local
+/// dataset fits its input and output shapes.
+pub fn prepare_model(
+    dev: &Device,
+    registry: &ModelRegistry,
+    spec: &ModelSpec,
+    info: &DatasetInfo,
+) -> Result<(VarMap, Box<dyn FederatedModel>), Error> {
     let varmap = VarMap::new();
     let vs = VarBuilder::from_varmap(&varmap, DType::F32, dev);
 
     // Creating the model builds 'varmap' parameters
-    let model = Model::new(&vs)?;
+    let model = registry.build(
+        &spec.architecture,
+        vs,
+        &ModelConfig::new(spec.config.clone()),
+        info,
+    )?;
 
     Ok((varmap, model))
 }
 
-pub fn train(weights: &SafeTensors, data: &Dataloader, dev: &Device) -> Result<VarMap, Error> {
-    info!("starting training");
-
-    let (varmap, model) = prepare_model(dev)?;
+/// Train the model of a session on local data, starting with the provided weights.
+///
+/// The optimizer of the session is reused if its kind and hyperparameters match
+/// the configuration. Only its learning rate is updated then, to follow the
+/// schedule of the job.
+/// Examples are shuffled with `seed`, unless shuffling is disabled.
+/// Training stops with an error once `cancelled` is set.
+/// Returns training metrics, the updated weights are kept in the session.
+pub fn train(
+    session: &mut Session,
+    weights: &HashMap<String, Tensor>,
+    data: &Arc<dyn Dataset>,
+    config: &TrainConfig,
+    seed: u64,
+    cancelled: &AtomicBool,
+    dev: &Device,
+) -> Result<HashMap<String, f64>, Error> {
+    info!(config = ?config, "starting training");
+
+    let start = Instant::now();
 
     // Load weights
     {
-        let mut tensor_data = varmap.data().lock().unwrap();
+        let mut tensor_data = session.varmap.data().lock().unwrap();
         for (name, var) in tensor_data.iter_mut() {
-            let data = weights.tensor(name)?;
-            var.set(&data.load(dev)?)?;
+            let data = weights
+                .get(name)
+                .ok_or_else(|| Error::CannotFindTensor { path: name.clone() })?;
+            var.set(&data.to_device(dev)?)?;
         }
     }
 
-    let mut optimizer = SGD::new(varmap.all_vars(), 0.1)?;
+    let mut optimizer = match session.optimizer.take() {
+        Some(mut optimizer) if optimizer.matches(config) => {
+            optimizer.set_learning_rate(config.learning_rate);
+            optimizer
+        }
+        _ => LocalOptimizer::new(session.varmap.all_vars(), config)?,
+    };
+
+    let data = Dataloader::new(
+        data.clone(),
+        config.batch_size,
+        config.shuffle.then_some(seed),
+        config.drop_last,
+    );
 
     let mut sum_loss = 0f32;
+    let mut sum_metrics = HashMap::<String, f64>::new();
     let mut total = 0;
+    let mut steps = 0;
 
-    for (inputs, targets) in data.iter() {
-        let logits = model.forward(&inputs)?;
-        let logits_softmax = ops::log_softmax(&logits, D::Minus1)?;
-        let loss = loss::nll(&logits_softmax, &targets)?;
+    for batch in data.epochs(config.local_epochs) {
+        if config.max_steps.is_some_and(|max_steps| steps >= max_steps) {
+            break;
+        }
+        if cancelled.load(Ordering::Relaxed) {
+            return Err(Error::Msg("training was cancelled".to_string()));
+        }
+
+        let (inputs, targets) = batch?;
+        let (inputs, targets) = (inputs.to_device(dev)?, targets.to_device(dev)?);
+
+        let logits = session.model.forward(&inputs)?;
+        let loss = session.model.loss(&logits, &targets)?;
 
         optimizer.backward_step(&loss)?;
-        sum_loss += loss.to_vec0::<f32>()?;
-        total += inputs.dims()[0];
+
+        let batch_size = inputs.dims()[0];
+        sum_loss += loss.to_vec0::<f32>()? * batch_size as f32;
+        for (name, value) in session.model.metrics(&logits, &targets)? {
+            *sum_metrics.entry(name).or_default() += value;
+        }
+        total += batch_size;
+        steps += 1;
     }
-    let avg_loss = sum_loss / total as f32;
+    session.optimizer = Some(optimizer);
+
+    // Metrics are averaged over the examples, so they'd be undefined
+    if total == 0 {
+        return Err(Error::Msg("no examples to train on".to_string()));
+    }
+
+    let mut metrics = sum_metrics
+        .into_iter()
+        .map(|(name, value)| (name, value / total as f64))
+        .collect::<HashMap<_, _>>();
+    metrics.extend([
+        ("loss".to_string(), (sum_loss / total as f32) as f64),
+        ("num_steps".to_string(), steps as f64),
+        ("num_examples".to_string(), total as f64),
+        ("wall_time".to_string(), start.elapsed().as_secs_f64()),
+    ]);
 
-    info!(loss = avg_loss, "completed training");
+    info!(metrics = ?metrics, "completed training");
 
-    Ok(varmap)
+    Ok(metrics)
 }
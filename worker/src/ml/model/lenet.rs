@@ -0,0 +1,79 @@
+use candle_core::{Error, Tensor};
+use candle_nn::{conv2d, linear, Conv2d, Conv2dConfig, Linear, Module, VarBuilder};
+
+use crate::ml::{
+    config::ModelConfig,
+    dataset::DatasetInfo,
+    model::{check_classes, FederatedModel},
+};
+
+/// LeNet-5 style convolutional network for image classification.
+///
+/// Examples are reshaped to the `[channels, height, width]` input shape of the
+/// configuration. Configuration: "channels", "height", "width" and
+/// "num_classes".
+pub struct LeNet {
+    conv1: Conv2d,
+    conv2: Conv2d,
+    fc1: Linear,
+    fc2: Linear,
+    fc3: Linear,
+    input_shape: (usize, usize, usize),
+}
+
+impl FederatedModel for LeNet {
+    fn build(vs: VarBuilder, config: &ModelConfig, info: &DatasetInfo) -> Result<Self, Error> {
+        let channels = config.required_usize("channels")?;
+        let height = config.required_usize("height")?;
+        let width = config.required_usize("width")?;
+        let num_classes = config.required_usize("num_classes")?;
+        if height < 12 || width < 12 {
+            return Err(Error::Msg(format!(
+                "lenet requires inputs of at least 12x12, got {height}x{width}"
+            )));
+        }
+        if info.input_shape != [channels, height, width] {
+            return Err(Error::Msg(format!(
+                "dataset examples have shape {:?}, model has [{channels}, {height}, {width}]",
+                info.input_shape
+            )));
+        }
+        check_classes(num_classes, info)?;
+
+        // The first convolution keeps the size, the second one shrinks it
+        // by 4, and each is followed by pooling that halves it.
+        let conv1_config = Conv2dConfig {
+            padding: 2,
+            ..Default::default()
+        };
+        let conv1 = conv2d(channels, 6, 5, conv1_config, vs.push_prefix("conv1"))?;
+        let conv2 = conv2d(6, 16, 5, Default::default(), vs.push_prefix("conv2"))?;
+        let features = 16 * ((height / 2 - 4) / 2) * ((width / 2 - 4) / 2);
+
+        let fc1 = linear(features, 120, vs.push_prefix("fc1"))?;
+        let fc2 = linear(120, 84, vs.push_prefix("fc2"))?;
+        let fc3 = linear(84, num_classes, vs.push_prefix("fc3"))?;
+
+        Ok(Self {
+            conv1,
+            conv2,
+            fc1,
+            fc2,
+            fc3,
+            input_shape: (channels, height, width),
+        })
+    }
+
+    fn forward(&self, xs: &Tensor) -> Result<Tensor, Error> {
+        let (channels, height, width) = self.input_shape;
+        let batch_size = xs.dims()[0];
+
+        let xs = xs.reshape((batch_size, channels, height, width))?;
+        let xs = self.conv1.forward(&xs)?.relu()?.max_pool2d(2)?;
+        let xs = self.conv2.forward(&xs)?.relu()?.max_pool2d(2)?;
+        let xs = xs.flatten_from(1)?;
+        let xs = self.fc1.forward(&xs)?.relu()?;
+        let xs = self.fc2.forward(&xs)?.relu()?;
+        self.fc3.forward(&xs)
+    }
+}
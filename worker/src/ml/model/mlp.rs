@@ -0,0 +1,44 @@
+use candle_core::{Error, Tensor};
+use candle_nn::{linear, Linear, Module, VarBuilder};
+
+use crate::ml::{
+    config::ModelConfig,
+    dataset::DatasetInfo,
+    model::{check_classes, FederatedModel},
+};
+
+/// Multilayer perceptron with a single hidden layer.
+///
+/// Configuration: "input_dim" (size of an example), "num_classes" and
+/// "hidden_dim" (100).
+pub struct Mlp {
+    ln1: Linear,
+    ln2: Linear,
+}
+
+impl FederatedModel for Mlp {
+    fn build(vs: VarBuilder, config: &ModelConfig, info: &DatasetInfo) -> Result<Self, Error> {
+        let input_dim = config.required_usize("input_dim")?;
+        let num_classes = config.required_usize("num_classes")?;
+        let hidden_dim = config.usize("hidden_dim", 100)?;
+
+        let example_size = info.input_shape.iter().product::<usize>();
+        if example_size != input_dim {
+            return Err(Error::Msg(format!(
+                "dataset examples have size {example_size}, model has input_dim {input_dim}"
+            )));
+        }
+        check_classes(num_classes, info)?;
+
+        let ln1 = linear(input_dim, hidden_dim, vs.push_prefix("ln1"))?;
+        let ln2 = linear(hidden_dim, num_classes, vs.push_prefix("ln2"))?;
+
+        Ok(Self { ln1, ln2 })
+    }
+
+    fn forward(&self, xs: &Tensor) -> Result<Tensor, Error> {
+        let xs = self.ln1.forward(xs)?;
+        let xs = xs.relu()?;
+        self.ln2.forward(&xs)
+    }
+}
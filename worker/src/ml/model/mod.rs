@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Error, Tensor, D};
+use candle_nn::{loss, ops, VarBuilder};
+
+use crate::ml::{config::ModelConfig, dataset::DatasetInfo};
+
+pub use lenet::LeNet;
+pub use mlp::Mlp;
+pub use transformer::TransformerClassifier;
+
+mod lenet;
+mod mlp;
+mod transformer;
+
+/// A model that workers train on their local data.
+pub trait FederatedModel: Send {
+    /// Build the model, creating its parameters with `vs`.
+    ///
+    /// Input and output shapes are taken from the configuration, so that all
+    /// workers build the same parameters. The metadata of the local dataset is
+    /// only checked against them.
+    fn build(vs: VarBuilder, config: &ModelConfig, info: &DatasetInfo) -> Result<Self, Error>
+    where
+        Self: Sized;
+
+    fn forward(&self, xs: &Tensor) -> Result<Tensor, Error>;
+
+    /// Loss of a batch that is minimized during training.
+    ///
+    /// Defaults to the cross-entropy of the logits.
+    fn loss(&self, logits: &Tensor, targets: &Tensor) -> Result<Tensor, Error> {
+        loss::nll(&ops::log_softmax(logits, D::Minus1)?, targets)
+    }
+
+    /// Metrics of a batch, summed over its examples.
+    ///
+    /// They are averaged over all examples of a round. Defaults to accuracy.
+    fn metrics(&self, logits: &Tensor, targets: &Tensor) -> Result<HashMap<String, f64>, Error> {
+        let correct = logits
+            .argmax(D::Minus1)?
+            .eq(&targets.to_dtype(DType::U32)?)?
+            .to_dtype(DType::F32)?
+            .sum_all()?
+            .to_vec0::<f32>()?;
+
+        Ok(HashMap::from([("accuracy".to_string(), correct as f64)]))
+    }
+}
+
+/// Check that the labels of the local dataset fit the classes of a model.
+fn check_classes(num_classes: usize, info: &DatasetInfo) -> Result<(), Error> {
+    if info.num_classes > num_classes {
+        return Err(Error::Msg(format!(
+            "dataset has {} classes, model has num_classes {num_classes}",
+            info.num_classes
+        )));
+    }
+
+    Ok(())
+}
+
+type Builder = fn(VarBuilder, &ModelConfig, &DatasetInfo) -> Result<Box<dyn FederatedModel>, Error>;
+
+/// Model architectures that the worker can train, keyed by name.
+///
+/// The coordinator announces the architecture of a job by name.
+pub struct ModelRegistry {
+    builders: HashMap<String, Builder>,
+}
+
+impl ModelRegistry {
+    pub fn new() -> Self {
+        Self {
+            builders: HashMap::new(),
+        }
+    }
+
+    pub fn register<M: FederatedModel + 'static>(&mut self, architecture: &str) {
+        self.builders
+            .insert(architecture.to_string(), |vs, config, info| {
+                Ok(Box::new(M::build(vs, config, info)?))
+            });
+    }
+
+    pub fn build(
+        &self,
+        architecture: &str,
+        vs: VarBuilder,
+        config: &ModelConfig,
+        info: &DatasetInfo,
+    ) -> Result<Box<dyn FederatedModel>, Error> {
+        let builder = self
+            .builders
+            .get(architecture)
+            .ok_or_else(|| Error::Msg(format!("unknown model architecture {architecture}")))?;
+
+        builder(vs, config, info)
+    }
+}
+
+impl Default for ModelRegistry {
+    /// Registry of the models provided by the worker.
+    fn default() -> Self {
+        let mut registry = Self::new();
+        registry.register::<Mlp>("mlp");
+        registry.register::<LeNet>("lenet");
+        registry.register::<TransformerClassifier>("transformer");
+        registry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::Device;
+    use candle_nn::VarMap;
+
+    use crate::{
+        candlefl::{config_value::Value, ConfigValue},
+        ml::dataset::{tabular, Dataset, TabularConfig},
+    };
+
+    use super::*;
+
+    fn config(values: &[(&str, i64)]) -> ModelConfig {
+        ModelConfig::new(
+            values
+                .iter()
+                .map(|&(name, value)| {
+                    let value = ConfigValue {
+                        value: Some(Value::Int(value)),
+                    };
+                    (name.to_string(), value)
+                })
+                .collect(),
+        )
+    }
+
+    fn build(
+        architecture: &str,
+        config: &ModelConfig,
+        info: &DatasetInfo,
+    ) -> Result<Box<dyn FederatedModel>, Error> {
+        let varmap = VarMap::new();
+        let vs = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+
+        ModelRegistry::default().build(architecture, vs, config, info)
+    }
+
+    #[test]
+    fn test_lenet() -> Result<(), Error> {
+        let config = config(&[
+            ("channels", 1),
+            ("height", 28),
+            ("width", 28),
+            ("num_classes", 10),
+        ]);
+        let info = DatasetInfo {
+            input_shape: vec![1, 28, 28],
+            num_classes: 10,
+            vocab_size: None,
+        };
+
+        let model = build("lenet", &config, &info)?;
+        let xs = Tensor::zeros((2, 784), DType::F32, &Device::Cpu)?;
+        assert_eq!(model.forward(&xs)?.dims(), [2, 10]);
+
+        // Datasets that don't fit the configured shapes are rejected
+        let info = DatasetInfo {
+            input_shape: vec![3, 28, 28],
+            ..info
+        };
+        assert!(build("lenet", &config, &info).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_transformer() -> Result<(), Error> {
+        let config = config(&[
+            ("sequence_length", 8),
+            ("vocab_size", 100),
+            ("num_classes", 3),
+            ("hidden_dim", 16),
+            ("num_heads", 2),
+        ]);
+        let info = DatasetInfo {
+            input_shape: vec![8],
+            num_classes: 3,
+            vocab_size: Some(50),
+        };
+
+        let model = build("transformer", &config, &info)?;
+        let xs = Tensor::ones((2, 8), DType::F32, &Device::Cpu)?;
+        assert_eq!(model.forward(&xs)?.dims(), [2, 3]);
+
+        // Datasets with more classes than the model are rejected
+        let info = DatasetInfo {
+            num_classes: 4,
+            ..info
+        };
+        assert!(build("transformer", &config, &info).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_transformer_tabular() -> Result<(), Error> {
+        let path = std::env::temp_dir().join(format!("tokens-{}.csv", std::process::id()));
+        std::fs::write(&path, "t0,t1,t2,t3,label\n1,5,7,0,0\n2,49,3,3,2\n")?;
+
+        let config = TabularConfig {
+            path: path.clone(),
+            label_column: "label".to_string(),
+            num_classes: Some(3),
+            vocab_size: Some(50),
+            ..Default::default()
+        };
+        let dataset = tabular::load(&config);
+        std::fs::remove_file(&path)?;
+        let dataset = dataset?;
+
+        let config = self::config(&[
+            ("sequence_length", 4),
+            ("vocab_size", 50),
+            ("num_classes", 3),
+            ("hidden_dim", 16),
+            ("num_heads", 2),
+        ]);
+        let model = build("transformer", &config, dataset.info())?;
+        let (xs, _) = dataset.get_batch(&[0, 1])?;
+        assert_eq!(model.forward(&xs)?.dims(), [2, 3]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_shape() {
+        let info = DatasetInfo {
+            input_shape: vec![4],
+            num_classes: 2,
+            vocab_size: None,
+        };
+
+        // Shapes aren't derived from the local dataset
+        assert!(build("mlp", &config(&[("num_classes", 2)]), &info).is_err());
+
+        let config = config(&[("input_dim", 4), ("num_classes", 2)]);
+        assert!(build("mlp", &config, &info).is_ok());
+    }
+}
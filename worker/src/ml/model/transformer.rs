@@ -0,0 +1,185 @@
+use candle_core::{DType, Error, Tensor, D};
+use candle_nn::{
+    embedding, layer_norm, linear, ops, Embedding, LayerNorm, Linear, Module, VarBuilder,
+};
+
+use crate::ml::{
+    config::ModelConfig,
+    dataset::DatasetInfo,
+    model::{check_classes, FederatedModel},
+};
+
+/// Small transformer encoder for text classification.
+///
+/// Examples are sequences of token IDs of the configured length. Token
+/// representations of the last layer are averaged and classified.
+///
+/// Configuration: "sequence_length", "vocab_size", "num_classes",
+/// "hidden_dim" (64), "num_heads" (4), "num_layers" (2) and
+/// "feedforward_dim" (128).
+pub struct TransformerClassifier {
+    token_embedding: Embedding,
+    position_embedding: Embedding,
+    layers: Vec<EncoderLayer>,
+    ln: LayerNorm,
+    classifier: Linear,
+}
+
+impl FederatedModel for TransformerClassifier {
+    fn build(vs: VarBuilder, config: &ModelConfig, info: &DatasetInfo) -> Result<Self, Error> {
+        let sequence_length = config.required_usize("sequence_length")?;
+        let vocab_size = config.required_usize("vocab_size")?;
+        let num_classes = config.required_usize("num_classes")?;
+        if info.input_shape != [sequence_length] {
+            return Err(Error::Msg(format!(
+                "dataset examples have shape {:?}, model has [{sequence_length}]",
+                info.input_shape
+            )));
+        }
+        match info.vocab_size {
+            Some(size) if size <= vocab_size => {}
+            Some(size) => {
+                return Err(Error::Msg(format!(
+                    "dataset has vocabulary size {size}, model has vocab_size {vocab_size}"
+                )))
+            }
+            None => {
+                return Err(Error::Msg(
+                    "transformer requires a dataset of token IDs, e.g. tabular data with a vocabulary size"
+                        .to_string(),
+                ))
+            }
+        }
+        check_classes(num_classes, info)?;
+        let hidden_dim = config.usize("hidden_dim", 64)?;
+        let num_heads = config.usize("num_heads", 4)?;
+        let num_layers = config.usize("num_layers", 2)?;
+        let feedforward_dim = config.usize("feedforward_dim", 128)?;
+        if num_heads == 0 || hidden_dim % num_heads != 0 {
+            return Err(Error::Msg(format!(
+                "hidden_dim {hidden_dim} must be divisible by num_heads {num_heads}"
+            )));
+        }
+
+        let token_embedding = embedding(vocab_size, hidden_dim, vs.push_prefix("token_embedding"))?;
+        let position_embedding = embedding(
+            sequence_length,
+            hidden_dim,
+            vs.push_prefix("position_embedding"),
+        )?;
+        let layers = (0..num_layers)
+            .map(|i| {
+                EncoderLayer::new(
+                    hidden_dim,
+                    num_heads,
+                    feedforward_dim,
+                    vs.push_prefix(format!("layers.{i}")),
+                )
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        let ln = layer_norm(hidden_dim, 1e-5, vs.push_prefix("ln"))?;
+        let classifier = linear(hidden_dim, num_classes, vs.push_prefix("classifier"))?;
+
+        Ok(Self {
+            token_embedding,
+            position_embedding,
+            layers,
+            ln,
+            classifier,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor) -> Result<Tensor, Error> {
+        let sequence_length = xs.dims()[1];
+
+        // Token IDs may be provided as floats by the dataloader
+        let xs = xs.to_dtype(DType::U32)?;
+        let positions = Tensor::arange(0u32, sequence_length as u32, xs.device())?;
+
+        let mut xs = self
+            .token_embedding
+            .forward(&xs)?
+            .broadcast_add(&self.position_embedding.forward(&positions)?)?;
+        for layer in self.layers.iter() {
+            xs = layer.forward(&xs)?;
+        }
+
+        let xs = self.ln.forward(&xs)?.mean(1)?;
+        self.classifier.forward(&xs)
+    }
+}
+
+/// Pre-norm transformer encoder layer.
+struct EncoderLayer {
+    attention: SelfAttention,
+    ln1: LayerNorm,
+    ln2: LayerNorm,
+    ff1: Linear,
+    ff2: Linear,
+}
+
+impl EncoderLayer {
+    fn new(
+        hidden_dim: usize,
+        num_heads: usize,
+        feedforward_dim: usize,
+        vs: VarBuilder,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            attention: SelfAttention::new(hidden_dim, num_heads, vs.push_prefix("attention"))?,
+            ln1: layer_norm(hidden_dim, 1e-5, vs.push_prefix("ln1"))?,
+            ln2: layer_norm(hidden_dim, 1e-5, vs.push_prefix("ln2"))?,
+            ff1: linear(hidden_dim, feedforward_dim, vs.push_prefix("ff1"))?,
+            ff2: linear(feedforward_dim, hidden_dim, vs.push_prefix("ff2"))?,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor) -> Result<Tensor, Error> {
+        let xs = (xs + self.attention.forward(&self.ln1.forward(xs)?)?)?;
+        let ys = self.ff1.forward(&self.ln2.forward(&xs)?)?.gelu()?;
+        xs + self.ff2.forward(&ys)?
+    }
+}
+
+/// Multi-head self-attention without masking.
+struct SelfAttention {
+    qkv: Linear,
+    out: Linear,
+    num_heads: usize,
+}
+
+impl SelfAttention {
+    fn new(hidden_dim: usize, num_heads: usize, vs: VarBuilder) -> Result<Self, Error> {
+        Ok(Self {
+            qkv: linear(hidden_dim, 3 * hidden_dim, vs.push_prefix("qkv"))?,
+            out: linear(hidden_dim, hidden_dim, vs.push_prefix("out"))?,
+            num_heads,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor) -> Result<Tensor, Error> {
+        let (batch_size, sequence_length, hidden_dim) = xs.dims3()?;
+        let head_dim = hidden_dim / self.num_heads;
+
+        // Split into heads: (batch, heads, sequence, head_dim)
+        let heads = |xs: Tensor| {
+            xs.reshape((batch_size, sequence_length, self.num_heads, head_dim))?
+                .transpose(1, 2)?
+                .contiguous()
+        };
+        let qkv = self.qkv.forward(xs)?.chunk(3, D::Minus1)?;
+        let q = heads(qkv[0].clone())?;
+        let k = heads(qkv[1].clone())?;
+        let v = heads(qkv[2].clone())?;
+
+        let attention = (q.matmul(&k.t()?.contiguous()?)? / (head_dim as f64).sqrt())?;
+        let attention = ops::softmax_last_dim(&attention)?;
+
+        let ys = attention.matmul(&v)?.transpose(1, 2)?.reshape((
+            batch_size,
+            sequence_length,
+            hidden_dim,
+        ))?;
+        self.out.forward(&ys)
+    }
+}
@@ -0,0 +1,385 @@
+use candle_core::{backprop::GradStore, Error, Tensor, Var};
+use candle_nn::{AdamW, Optimizer, ParamsAdamW};
+
+use crate::ml::config::{OptimizerKind, TrainConfig};
+
+/// Optimizer used for local training, selected by the training configuration.
+pub enum LocalOptimizer {
+    Sgd(Sgd),
+    AdamW(AdamW),
+    RmsProp(RmsProp),
+}
+
+impl LocalOptimizer {
+    pub fn new(vars: Vec<Var>, config: &TrainConfig) -> Result<Self, Error> {
+        match config.optimizer {
+            OptimizerKind::Sgd => Ok(LocalOptimizer::Sgd(Sgd::new(vars, params_sgd(config))?)),
+            OptimizerKind::AdamW => Ok(LocalOptimizer::AdamW(AdamW::new(
+                vars,
+                params_adamw(config),
+            )?)),
+            OptimizerKind::RmsProp => Ok(LocalOptimizer::RmsProp(RmsProp::new(
+                vars,
+                params_rmsprop(config),
+            )?)),
+        }
+    }
+
+    /// Whether the optimizer has the kind and hyperparameters of a
+    /// configuration, apart from the learning rate, which follows the
+    /// schedule of the job.
+    pub fn matches(&self, config: &TrainConfig) -> bool {
+        match (self, config.optimizer) {
+            (LocalOptimizer::Sgd(optimizer), OptimizerKind::Sgd) => {
+                let params = ParamsSgd {
+                    learning_rate: optimizer.params.learning_rate,
+                    ..params_sgd(config)
+                };
+                optimizer.params == params
+            }
+            (LocalOptimizer::AdamW(optimizer), OptimizerKind::AdamW) => {
+                let (current, params) = (optimizer.params(), params_adamw(config));
+                current.beta1 == params.beta1
+                    && current.beta2 == params.beta2
+                    && current.eps == params.eps
+                    && current.weight_decay == params.weight_decay
+            }
+            (LocalOptimizer::RmsProp(optimizer), OptimizerKind::RmsProp) => {
+                let params = ParamsRmsProp {
+                    learning_rate: optimizer.params.learning_rate,
+                    ..params_rmsprop(config)
+                };
+                optimizer.params == params
+            }
+            _ => false,
+        }
+    }
+
+    pub fn backward_step(&mut self, loss: &Tensor) -> Result<(), Error> {
+        match self {
+            LocalOptimizer::Sgd(optimizer) => optimizer.backward_step(loss),
+            LocalOptimizer::AdamW(optimizer) => optimizer.backward_step(loss),
+            LocalOptimizer::RmsProp(optimizer) => optimizer.backward_step(loss),
+        }
+    }
+
+    pub fn set_learning_rate(&mut self, learning_rate: f64) {
+        match self {
+            LocalOptimizer::Sgd(optimizer) => optimizer.set_learning_rate(learning_rate),
+            LocalOptimizer::AdamW(optimizer) => optimizer.set_learning_rate(learning_rate),
+            LocalOptimizer::RmsProp(optimizer) => optimizer.set_learning_rate(learning_rate),
+        }
+    }
+}
+
+fn params_sgd(config: &TrainConfig) -> ParamsSgd {
+    ParamsSgd {
+        learning_rate: config.learning_rate,
+        momentum: config.momentum,
+        nesterov: config.nesterov,
+        weight_decay: config.weight_decay,
+    }
+}
+
+fn params_adamw(config: &TrainConfig) -> ParamsAdamW {
+    ParamsAdamW {
+        lr: config.learning_rate,
+        beta1: config.beta1,
+        beta2: config.beta2,
+        eps: config.eps,
+        weight_decay: config.weight_decay,
+    }
+}
+
+fn params_rmsprop(config: &TrainConfig) -> ParamsRmsProp {
+    ParamsRmsProp {
+        learning_rate: config.learning_rate,
+        alpha: config.alpha,
+        eps: config.eps,
+        momentum: config.momentum,
+        weight_decay: config.weight_decay,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParamsSgd {
+    pub learning_rate: f64,
+    pub momentum: f64,
+    pub nesterov: bool,
+    pub weight_decay: f64,
+}
+
+/// Stochastic Gradient Descent with optional momentum, Nesterov momentum and
+/// weight decay, following the PyTorch implementation.
+pub struct Sgd {
+    vars: Vec<(Var, Option<Var>)>,
+    params: ParamsSgd,
+}
+
+impl Optimizer for Sgd {
+    type Config = ParamsSgd;
+
+    fn new(vars: Vec<Var>, params: ParamsSgd) -> Result<Self, Error> {
+        let vars = vars
+            .into_iter()
+            .filter(|var| var.dtype().is_float())
+            .map(|var| (var, None))
+            .collect();
+
+        Ok(Self { vars, params })
+    }
+
+    fn step(&mut self, grads: &GradStore) -> Result<(), Error> {
+        let ParamsSgd {
+            learning_rate,
+            momentum,
+            nesterov,
+            weight_decay,
+        } = self.params;
+
+        for (var, momentum_buffer) in self.vars.iter_mut() {
+            if let Some(grad) = grads.get(var) {
+                let mut grad = grad.clone();
+                if weight_decay != 0.0 {
+                    grad = (grad + (var.as_tensor() * weight_decay)?)?;
+                }
+
+                if momentum != 0.0 {
+                    // The buffer is initialized with the first gradient
+                    let buffer = match momentum_buffer {
+                        Some(buffer) => {
+                            buffer.set(&((buffer.as_tensor() * momentum)? + &grad)?)?;
+                            buffer.as_tensor().clone()
+                        }
+                        None => {
+                            let buffer = Var::from_tensor(&grad)?;
+                            let tensor = buffer.as_tensor().clone();
+                            *momentum_buffer = Some(buffer);
+                            tensor
+                        }
+                    };
+
+                    grad = if nesterov {
+                        (grad + (buffer * momentum)?)?
+                    } else {
+                        buffer
+                    };
+                }
+
+                var.set(&var.sub(&(grad * learning_rate)?)?)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.params.learning_rate
+    }
+
+    fn set_learning_rate(&mut self, learning_rate: f64) {
+        self.params.learning_rate = learning_rate;
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParamsRmsProp {
+    pub learning_rate: f64,
+    pub alpha: f64,
+    pub eps: f64,
+    pub momentum: f64,
+    pub weight_decay: f64,
+}
+
+/// RMSprop with optional momentum and weight decay, following the PyTorch
+/// implementation.
+pub struct RmsProp {
+    vars: Vec<VarRmsProp>,
+    params: ParamsRmsProp,
+}
+
+struct VarRmsProp {
+    var: Var,
+    square_avg: Var,
+    momentum_buffer: Var,
+}
+
+impl Optimizer for RmsProp {
+    type Config = ParamsRmsProp;
+
+    fn new(vars: Vec<Var>, params: ParamsRmsProp) -> Result<Self, Error> {
+        let vars = vars
+            .into_iter()
+            .filter(|var| var.dtype().is_float())
+            .map(|var| {
+                let square_avg = Var::zeros(var.shape(), var.dtype(), var.device())?;
+                let momentum_buffer = Var::zeros(var.shape(), var.dtype(), var.device())?;
+                Ok(VarRmsProp {
+                    var,
+                    square_avg,
+                    momentum_buffer,
+                })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        Ok(Self { vars, params })
+    }
+
+    fn step(&mut self, grads: &GradStore) -> Result<(), Error> {
+        let ParamsRmsProp {
+            learning_rate,
+            alpha,
+            eps,
+            momentum,
+            weight_decay,
+        } = self.params;
+
+        for var in self.vars.iter() {
+            let theta = &var.var;
+            if let Some(grad) = grads.get(theta) {
+                let mut grad = grad.clone();
+                if weight_decay != 0.0 {
+                    grad = (grad + (theta.as_tensor() * weight_decay)?)?;
+                }
+
+                let square_avg =
+                    ((var.square_avg.as_tensor() * alpha)? + (grad.sqr()? * (1.0 - alpha))?)?;
+                let update = (grad / (square_avg.sqrt()? + eps)?)?;
+                var.square_avg.set(&square_avg)?;
+
+                let update = if momentum != 0.0 {
+                    let buffer = ((var.momentum_buffer.as_tensor() * momentum)? + update)?;
+                    var.momentum_buffer.set(&buffer)?;
+                    buffer
+                } else {
+                    update
+                };
+
+                theta.set(&theta.sub(&(update * learning_rate)?)?)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.params.learning_rate
+    }
+
+    fn set_learning_rate(&mut self, learning_rate: f64) {
+        self.params.learning_rate = learning_rate;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::Device;
+
+    use super::*;
+
+    #[test]
+    fn test_sgd_momentum() -> Result<(), Error> {
+        let var = Var::new(&[1f32], &Device::Cpu)?;
+
+        let mut optimizer = Sgd::new(
+            vec![var.clone()],
+            ParamsSgd {
+                learning_rate: 0.1,
+                momentum: 0.5,
+                nesterov: false,
+                weight_decay: 0.0,
+            },
+        )?;
+
+        // The gradient of 'x' is always 1
+        optimizer.backward_step(&var.as_tensor().sum_all()?)?;
+        assert!((var.to_vec1::<f32>()?[0] - 0.9).abs() < 1e-6);
+
+        // The momentum buffer is now 0.5 * 1 + 1
+        optimizer.backward_step(&var.as_tensor().sum_all()?)?;
+        assert!((var.to_vec1::<f32>()?[0] - 0.75).abs() < 1e-6);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sgd_nesterov() -> Result<(), Error> {
+        let var = Var::new(&[1f32], &Device::Cpu)?;
+
+        let mut optimizer = Sgd::new(
+            vec![var.clone()],
+            ParamsSgd {
+                learning_rate: 0.1,
+                momentum: 0.5,
+                nesterov: true,
+                weight_decay: 0.0,
+            },
+        )?;
+
+        // The step is the gradient plus the momentum buffer times the momentum
+        optimizer.backward_step(&var.as_tensor().sum_all()?)?;
+        assert!((var.to_vec1::<f32>()?[0] - 0.85).abs() < 1e-6);
+
+        optimizer.backward_step(&var.as_tensor().sum_all()?)?;
+        assert!((var.to_vec1::<f32>()?[0] - 0.675).abs() < 1e-6);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rmsprop() -> Result<(), Error> {
+        let var = Var::new(&[1f32], &Device::Cpu)?;
+
+        let mut optimizer = RmsProp::new(
+            vec![var.clone()],
+            ParamsRmsProp {
+                learning_rate: 0.01,
+                alpha: 0.99,
+                eps: 1e-8,
+                momentum: 0.0,
+                weight_decay: 0.0,
+            },
+        )?;
+
+        // The square average is 0.01, so the gradient is scaled to 10
+        optimizer.backward_step(&var.as_tensor().sum_all()?)?;
+        assert!((var.to_vec1::<f32>()?[0] - 0.9).abs() < 1e-5);
+
+        // The square average is 0.99 * 0.01 + 0.01
+        optimizer.backward_step(&var.as_tensor().sum_all()?)?;
+        assert!((var.to_vec1::<f32>()?[0] - (0.9 - 0.01 / 0.0199f32.sqrt())).abs() < 1e-5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_matches() -> Result<(), Error> {
+        let var = Var::new(&[1f32], &Device::Cpu)?;
+        let config = TrainConfig {
+            optimizer: OptimizerKind::Sgd,
+            momentum: 0.9,
+            ..TrainConfig::default()
+        };
+        let optimizer = LocalOptimizer::new(vec![var], &config)?;
+
+        // The learning rate follows the schedule, other changes need a new optimizer
+        assert!(optimizer.matches(&TrainConfig {
+            learning_rate: 0.5,
+            ..config.clone()
+        }));
+        assert!(!optimizer.matches(&TrainConfig {
+            momentum: 0.5,
+            ..config.clone()
+        }));
+        assert!(!optimizer.matches(&TrainConfig {
+            nesterov: true,
+            ..config.clone()
+        }));
+        assert!(!optimizer.matches(&TrainConfig {
+            optimizer: OptimizerKind::RmsProp,
+            ..config
+        }));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use candle_core::{Device, Error};
+use candle_nn::VarMap;
+
+use crate::candlefl::ModelSpec;
+use crate::ml::{
+    dataset::DatasetInfo,
+    model::{FederatedModel, ModelRegistry},
+    optimizer::LocalOptimizer,
+    prepare_model,
+};
+
+/// Local training state of a job.
+///
+/// Keeping a session across the rounds of a job persists the state of the
+/// local optimizer, e.g. momentum or moment estimates.
+pub struct Session {
+    pub varmap: VarMap,
+    pub model: Box<dyn FederatedModel>,
+    pub optimizer: Option<LocalOptimizer>,
+}
+
+impl Session {
+    pub fn new(
+        dev: &Device,
+        registry: &ModelRegistry,
+        spec: &ModelSpec,
+        info: &DatasetInfo,
+    ) -> Result<Self, Error> {
+        let (varmap, model) = prepare_model(dev, registry, spec, info)?;
+
+        Ok(Self {
+            varmap,
+            model,
+            optimizer: None,
+        })
+    }
+}
+
+/// Default number of sessions that are kept, see [`Sessions::new`].
+const DEFAULT_CAPACITY: usize = 4;
+
+/// Default time after which unused sessions are evicted, see [`Sessions::new`].
+const DEFAULT_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Sessions of jobs that persist their optimizer state across rounds.
+///
+/// Workers aren't told when a job finishes, so sessions that haven't been used
+/// for a while are evicted, as are the least recently used sessions beyond the
+/// capacity of the store.
+pub struct Sessions {
+    // Sessions by job ID, with the time they were last used.
+    sessions: Mutex<HashMap<String, (Session, Instant)>>,
+    capacity: usize,
+    ttl: Duration,
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY, DEFAULT_TTL)
+    }
+}
+
+impl Sessions {
+    /// Keep up to `capacity` sessions, each for at most `ttl` after its last round.
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            sessions: Mutex::new(HashMap::new()),
+            capacity,
+            ttl,
+        }
+    }
+
+    /// Take the session of a job out of the store, or create a new one.
+    ///
+    /// The session is removed while training, so that the store isn't locked
+    /// for the duration of a round.
+    pub fn take(
+        &self,
+        job_id: &str,
+        dev: &Device,
+        registry: &ModelRegistry,
+        spec: &ModelSpec,
+        info: &DatasetInfo,
+    ) -> Result<Session, Error> {
+        match self.sessions.lock().unwrap().remove(job_id) {
+            Some((session, _)) => Ok(session),
+            None => Session::new(dev, registry, spec, info),
+        }
+    }
+
+    /// Store the session of a job after a round, evicting expired and least
+    /// recently used sessions.
+    pub fn insert(&self, job_id: String, session: Session) {
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+
+        sessions.retain(|_, (_, last_used)| now.duration_since(*last_used) < self.ttl);
+        while sessions.len() >= self.capacity {
+            let Some(oldest) = sessions
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(job_id, _)| job_id.clone())
+            else {
+                break;
+            };
+            sessions.remove(&oldest);
+        }
+
+        if self.capacity > 0 {
+            sessions.insert(job_id, (session, now));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::candlefl::{config_value::Value, ConfigValue};
+
+    use super::*;
+
+    fn session() -> Session {
+        let spec = ModelSpec {
+            architecture: "mlp".to_string(),
+            config: [("input_dim", 4), ("num_classes", 2)]
+                .into_iter()
+                .map(|(name, value)| {
+                    let value = ConfigValue {
+                        value: Some(Value::Int(value)),
+                    };
+                    (name.to_string(), value)
+                })
+                .collect(),
+        };
+        let info = DatasetInfo {
+            input_shape: vec![4],
+            num_classes: 2,
+            vocab_size: None,
+        };
+
+        Session::new(&Device::Cpu, &ModelRegistry::default(), &spec, &info).unwrap()
+    }
+
+    #[test]
+    fn test_evict_least_recently_used() {
+        let sessions = Sessions::new(2, DEFAULT_TTL);
+        sessions.insert("a".to_string(), session());
+        sessions.insert("b".to_string(), session());
+        sessions.insert("c".to_string(), session());
+
+        let sessions = sessions.sessions.lock().unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert!(!sessions.contains_key("a"));
+    }
+
+    #[test]
+    fn test_evict_expired() {
+        let sessions = Sessions::new(2, Duration::ZERO);
+        sessions.insert("a".to_string(), session());
+        sessions.insert("b".to_string(), session());
+
+        assert_eq!(sessions.sessions.lock().unwrap().len(), 1);
+    }
+}
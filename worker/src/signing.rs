@@ -0,0 +1,116 @@
+//! Ed25519 signatures of the weights exchanged with the coordinator.
+//!
+//! Workers sign their results with a key that is enrolled at the coordinator,
+//! which signs its training requests and final models in turn.
+
+use std::{fs, io, path::Path};
+
+use ed25519_dalek::{
+    pkcs8::{DecodePrivateKey, DecodePublicKey},
+    Signature, SignatureError, Signer, SigningKey, Verifier, VerifyingKey,
+};
+
+pub use protocol::signing::{
+    fit_request_digest, Signed, FIT_REQUEST, FIT_RESPONSE, TRAIN_RESPONSE, WEIGHTS_RESPONSE,
+};
+
+/// Read a PKCS#8 PEM Ed25519 private key.
+pub fn read_signing_key(path: &Path) -> Result<SigningKey, io::Error> {
+    let pem = fs::read_to_string(path)?;
+
+    SigningKey::from_pkcs8_pem(&pem).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: invalid signing key: {e}", path.display()),
+        )
+    })
+}
+
+/// Read an SPKI PEM Ed25519 public key.
+pub fn read_verifying_key(path: &Path) -> Result<VerifyingKey, io::Error> {
+    let pem = fs::read_to_string(path)?;
+
+    VerifyingKey::from_public_key_pem(&pem).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: invalid public key: {e}", path.display()),
+        )
+    })
+}
+
+/// Sign the weights of a job.
+pub fn sign(key: &SigningKey, signed: &Signed) -> Vec<u8> {
+    key.sign(&signed.payload()).to_bytes().to_vec()
+}
+
+/// Verify the signature of the weights of a job.
+pub fn verify(key: &VerifyingKey, signed: &Signed, signature: &[u8]) -> Result<(), SignatureError> {
+    let signature = Signature::from_slice(signature)?;
+
+    key.verify(&signed.payload(), &signature)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::candlefl::WeightEncoding;
+
+    use super::*;
+
+    #[test]
+    fn test_sign_verify() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let signed = |context, job_id, encoding, weights| Signed {
+            context,
+            job_id,
+            round: 1,
+            encoding,
+            weights,
+            fields_digest: b"fields".to_vec(),
+        };
+        let signature = sign(
+            &key,
+            &signed(FIT_REQUEST, "job", WeightEncoding::None, b"weights"),
+        );
+
+        let is_valid = |signed: Signed, signature: &[u8]| {
+            verify(&key.verifying_key(), &signed, signature).is_ok()
+        };
+
+        assert!(is_valid(
+            signed(FIT_REQUEST, "job", WeightEncoding::None, b"weights"),
+            &signature
+        ));
+        assert!(!is_valid(
+            signed(FIT_REQUEST, "job", WeightEncoding::None, b"tampered"),
+            &signature
+        ));
+        assert!(!is_valid(
+            signed(
+                FIT_REQUEST,
+                "job",
+                WeightEncoding::Int8PerTensor,
+                b"weights"
+            ),
+            &signature
+        ));
+        assert!(!is_valid(
+            Signed {
+                fields_digest: b"tampered".to_vec(),
+                ..signed(FIT_REQUEST, "job", WeightEncoding::None, b"weights")
+            },
+            &signature
+        ));
+        assert!(!is_valid(
+            signed(FIT_REQUEST, "other", WeightEncoding::None, b"weights"),
+            &signature
+        ));
+        assert!(!is_valid(
+            signed(TRAIN_RESPONSE, "job", WeightEncoding::None, b"weights"),
+            &signature
+        ));
+        assert!(!is_valid(
+            signed(FIT_REQUEST, "job", WeightEncoding::None, b"weights"),
+            &[]
+        ));
+    }
+}
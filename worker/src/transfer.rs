@@ -0,0 +1,187 @@
+use std::{mem, sync::Arc};
+
+use protocol::blob::{blob_id, chunks, CHUNK_SIZE};
+use tokio::{sync::watch, time};
+use tonic::{transport::Channel, Status};
+use tracing::{debug, warn};
+
+use crate::{
+    backoff::Backoff,
+    candlefl::{
+        transfer_client::TransferClient, worker_message, DownloadRequest, UploadStatusRequest,
+    },
+    is_transient, request, WorkerError,
+};
+
+/// Download a blob of weights from the coordinator.
+///
+/// Interrupted downloads are resumed at the offset received so far, once the
+/// worker has reconnected or after backing off. The content is checked against
+/// the ID of the blob, the hex-encoded SHA-256 digest.
+pub async fn download(
+    mut channel: watch::Receiver<Option<Channel>>,
+    worker_id: &str,
+    token: Option<&str>,
+    job_id: &str,
+    blob_id: &str,
+) -> Result<Vec<u8>, WorkerError> {
+    let mut backoff = Backoff::default();
+    let mut data = Vec::new();
+
+    loop {
+        let current = channel.borrow_and_update().clone();
+
+        if let Some(current) = current {
+            let download_request = DownloadRequest {
+                blob_id: blob_id.to_string(),
+                offset: data.len() as u64,
+                job_id: job_id.to_string(),
+            };
+            let request = request(worker_id, token, download_request)?;
+
+            match receive(TransferClient::new(current), request, &mut data).await {
+                Ok(()) => break,
+                Err(status) if !is_transient(&status) => return Err(status.into()),
+                Err(status) => {
+                    warn!(blob_id, offset = data.len(), error = %status, "download interrupted, resuming");
+                }
+            }
+        }
+
+        tokio::select! {
+            changed = channel.changed() => changed?,
+            _ = time::sleep(backoff.next_delay()) => {}
+        }
+    }
+
+    if self::blob_id(&data) != blob_id {
+        return Err(WorkerError::ChecksumMismatch(blob_id.to_string()));
+    }
+
+    debug!(blob_id, size = data.len(), "downloaded blob");
+
+    Ok(data)
+}
+
+/// Receive the chunks of a download, appending them to `data`.
+async fn receive(
+    mut client: TransferClient<Channel>,
+    request: tonic::Request<DownloadRequest>,
+    data: &mut Vec<u8>,
+) -> Result<(), Status> {
+    let mut chunks = client.download(request).await?.into_inner();
+
+    while let Some(chunk) = chunks.message().await? {
+        if chunk.offset != data.len() as u64 {
+            return Err(Status::data_loss(format!(
+                "chunk at offset {}, expected {}",
+                chunk.offset,
+                data.len()
+            )));
+        }
+        data.extend_from_slice(&chunk.data);
+
+        if data.len() as u64 >= chunk.size {
+            return Ok(());
+        }
+    }
+
+    Err(Status::unavailable("download ended early"))
+}
+
+/// Upload a blob of weights of a job to the coordinator and return its ID.
+///
+/// Interrupted uploads are resumed at the offset that the coordinator has
+/// received so far, once the worker has reconnected or after backing off.
+pub async fn upload(
+    mut channel: watch::Receiver<Option<Channel>>,
+    worker_id: &str,
+    token: Option<&str>,
+    job_id: &str,
+    data: Arc<[u8]>,
+) -> Result<String, WorkerError> {
+    let mut backoff = Backoff::default();
+    let blob_id = blob_id(&data);
+
+    loop {
+        let current = channel.borrow_and_update().clone();
+
+        if let Some(current) = current {
+            let mut client = TransferClient::new(current);
+            let status_request = request(
+                worker_id,
+                token,
+                UploadStatusRequest {
+                    blob_id: blob_id.clone(),
+                    job_id: job_id.to_string(),
+                },
+            )?;
+
+            let result = match client.get_upload_status(status_request).await {
+                Ok(status) if status.get_ref().complete => Ok(()),
+                Ok(status) => {
+                    let offset = status.into_inner().offset as usize;
+                    let chunks = tokio_stream::iter(chunks(
+                        job_id.to_string(),
+                        blob_id.clone(),
+                        data.clone(),
+                        offset,
+                    ));
+
+                    client
+                        .upload(request(worker_id, token, chunks)?)
+                        .await
+                        .map(|_| ())
+                }
+                Err(status) => Err(status),
+            };
+
+            match result {
+                Ok(()) => break,
+                Err(status) if !is_transient(&status) => return Err(status.into()),
+                Err(status) => warn!(blob_id, error = %status, "upload interrupted, resuming"),
+            }
+        }
+
+        tokio::select! {
+            changed = channel.changed() => changed?,
+            _ = time::sleep(backoff.next_delay()) => {}
+        }
+    }
+
+    debug!(blob_id, size = data.len(), "uploaded blob");
+
+    Ok(blob_id)
+}
+
+/// Upload the weights of a result as a blob, if they are too large for a
+/// single message, and refer to the blob instead.
+pub async fn offload(
+    message: &mut worker_message::Message,
+    channel: watch::Receiver<Option<Channel>>,
+    worker_id: &str,
+    token: Option<&str>,
+) -> Result<(), WorkerError> {
+    let (job_id, weights, weights_blob_id) = match message {
+        worker_message::Message::WeightsResponse(response) => (
+            &response.job_id,
+            &mut response.weights,
+            &mut response.weights_blob_id,
+        ),
+        worker_message::Message::FitResponse(response) => (
+            &response.job_id,
+            &mut response.weights,
+            &mut response.weights_blob_id,
+        ),
+        _ => return Ok(()),
+    };
+
+    if weights.len() <= CHUNK_SIZE {
+        return Ok(());
+    }
+
+    let data = Arc::from(mem::take(weights));
+    *weights_blob_id = upload(channel, worker_id, token, job_id, data).await?;
+
+    Ok(())
+}